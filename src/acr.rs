@@ -0,0 +1,816 @@
+//! Writer for acrylic source, turning a [`Document`] back into `.acr` text.
+//!
+//! Parsing the output gives back an equal document. What the document doesn't keep (comments,
+//! which characters were escaped, `$:` vs `${...}` math...) is written in a canonical way.
+
+use crate::parser::{
+    Indent, IndentOrigin, Node3, Span, Spanned, StandardOptions, TaskFormat, Term3,
+    stage1::{IndentStats, is},
+    stage3::{
+        BulletType, Document, HeadingLine, Line, TableItem, TableLine, TaskPrefix, TaskState,
+    },
+    visit::{Visit, walk_term},
+};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// The content of each footnote, which is written where its marker is.
+type Footnotes<'a> = [Vec<Spanned<Term3<'a>>>];
+
+/// Write `doc` as acrylic source.
+///
+/// Fails with [`io::ErrorKind::InvalidInput`] if the document has lines that failed to parse (see
+/// [`crate::parse_recovering`]), as there's no source to write for them, and with
+/// [`io::ErrorKind::InvalidData`] if a [`Term3::Footnote`] has no footnote in the document.
+pub fn write_acr<W: Write>(w: &mut W, doc: &Document) -> io::Result<()> {
+    let mut check = FootnoteCheck {
+        count: doc.footnotes.len(),
+        missing: None,
+    };
+    check.visit_document(doc);
+    if let Some((n, Span { line, column, .. })) = check.missing {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{line}:{column}: footnote {n} isn't in the document"),
+        ));
+    }
+
+    let opts = &doc.options;
+    let unit = match opts.indent {
+        Indent::Tab => "\t".to_owned(),
+        Indent::Space(n) => " ".repeat(n),
+    };
+
+    let footnotes = &doc.footnotes[..];
+    let mut body = String::new();
+    for node in &doc.nodes {
+        write_node(&mut body, footnotes, node, &unit, 0)?;
+    }
+
+    // without an `%:indent` entry, the indent is inferred from the body (which might not give the
+    // same one, e.g. if it was inferred from comments that aren't written back)
+    let inferred = match IndentStats::of(&body, 0, 1, body.len()).indent() {
+        Some(indent) => (indent, IndentOrigin::Inferred),
+        None => (StandardOptions::default().indent, IndentOrigin::Default),
+    };
+    let write_indent =
+        opts.indent_origin == IndentOrigin::Header || inferred != (opts.indent, opts.indent_origin);
+
+    let header = header_source(&doc.header, opts, write_indent);
+    write!(w, "{header}")?;
+
+    // the first line of the body would be read as a part of the header if it looked like an entry
+    if !header.is_empty() || body.starts_with("%:") {
+        writeln!(w)?;
+    }
+    write!(w, "{body}")?;
+
+    Ok(())
+}
+
+fn header_source(
+    header: &HashMap<String, String>,
+    opts: &StandardOptions,
+    write_indent: bool,
+) -> String {
+    let mut ret = String::new();
+    let mut entry = |key: &str, value: &str| {
+        ret.push_str("%:");
+        ret.push_str(key);
+        // continuation lines are indented, and the first line of the value can be empty
+        for (i, line) in value.split('\n').enumerate() {
+            ret.push_str(match i {
+                0 if line.is_empty() => "",
+                0 => " ",
+                _ => "\n  ",
+            });
+            ret.push_str(line);
+        }
+        ret.push('\n');
+    };
+
+    if !opts.title.is_empty() {
+        entry("title", &opts.title);
+    }
+    if let Some(author) = &opts.author {
+        entry("author", author);
+    }
+    if let Some(date) = &opts.date {
+        entry("date", &date.to_string());
+    }
+    if let Some(lang) = &opts.lang {
+        entry("lang", lang);
+    }
+    if let Some(description) = &opts.description {
+        entry("description", description);
+    }
+    if !opts.tags.is_empty() {
+        entry("tags", &opts.tags.join(" "));
+    }
+
+    if write_indent {
+        match opts.indent {
+            Indent::Tab => entry("indent", "tab"),
+            Indent::Space(n) => entry("indent", &n.to_string()),
+        }
+    }
+
+    if !opts.katex_macros.is_empty() {
+        let macros: Vec<String> = opts
+            .katex_macros
+            .iter()
+            .map(|(name, expansion)| format!("{name} {expansion}").trim_end().to_owned())
+            .collect();
+        entry("katex-macros", &format!("\n{}", macros.join("\n")));
+    }
+
+    let mut keys: Vec<&String> = header.keys().collect();
+    keys.sort();
+    for key in keys {
+        entry(key, &header[key]);
+    }
+
+    ret
+}
+
+/// Finds the first footnote marker whose footnote isn't in the document.
+struct FootnoteCheck {
+    count: usize,
+    missing: Option<(usize, Span)>,
+}
+
+impl<'a> Visit<'a> for FootnoteCheck {
+    fn visit_term(&mut self, term: &'a Spanned<Term3<'_>>) {
+        match term.value {
+            Term3::Footnote(n) if !(1..=self.count).contains(&n) => {
+                self.missing.get_or_insert((n, term.span));
+            }
+            _ => {}
+        }
+        walk_term(self, term);
+    }
+}
+
+fn write_node(
+    out: &mut String,
+    footnotes: &Footnotes,
+    node: &Node3,
+    unit: &str,
+    depth: usize,
+) -> io::Result<()> {
+    out.push_str(&unit.repeat(depth));
+    out.push_str(&line_source(footnotes, &node.line, node.span, unit, depth)?);
+    out.push('\n');
+
+    // a blank line right after the node's own line (and not after its children) is what sets it
+    if node.bottom_spacing {
+        out.push('\n');
+    }
+
+    for child in &node.children {
+        write_node(out, footnotes, child, unit, depth + 1)?;
+    }
+
+    Ok(())
+}
+
+/// Get the source of a line, without its indent.
+///
+/// Multi-line terms (e.g. a table) have their inner lines indented according to `depth`.
+fn line_source(
+    footnotes: &Footnotes,
+    line: &Line,
+    span: Span,
+    unit: &str,
+    depth: usize,
+) -> io::Result<String> {
+    let mut out = String::new();
+
+    match line {
+        Line::Text(l) => {
+            match l.bullet {
+                Some(BulletType::Dash) => out.push('-'),
+                Some(BulletType::Star) => out.push('*'),
+                None => {}
+            }
+
+            if let Some(TaskPrefix { state, format }) = &l.task {
+                let state = match state {
+                    TaskState::Todo => ' ',
+                    TaskState::Done => 'x',
+                    TaskState::Cancelled => '-',
+                };
+                match format {
+                    TaskFormat::Square => out.extend(['[', state, ']']),
+                    TaskFormat::Paren => out.extend(['(', state, ')']),
+                }
+            }
+
+            let mut content = &l.content[..];
+            if l.bullet.is_none() && l.task.is_none() && content.first().is_some_and(is_space) {
+                // whitespace at the start of a line is its indent, unless it's of the other kind
+                out.push(if unit == "\t" { ' ' } else { '\t' });
+                content = &content[1..];
+            }
+
+            write_terms(&mut out, footnotes, content, false, "");
+
+            // nor is a task prefix, unless it's a single space between the brackets
+            let task_like = out.starts_with("( )") || out.starts_with("[ ]");
+            if l.bullet.is_none() && l.task.is_none() && task_like {
+                out.insert(1, ' ');
+            }
+        }
+        Line::Heading(l) => {
+            out.push_str("@h");
+            let level = l.level.to_string();
+            let level = Some(&*level).filter(|_| l.level != HeadingLine::level_at(depth));
+            let id = Some(&*l.id).filter(|_| l.explicit_id);
+            write_kwargs(&mut out, &[("level", level), ("id", id)]);
+            write_terms_arg(&mut out, footnotes, &l.content, false);
+        }
+        Line::Quote(l) => {
+            out.push_str("@quote");
+            write_kwargs(&mut out, &[("by", l.attribution.as_deref())]);
+            write_terms_arg(&mut out, footnotes, &l.content, false);
+        }
+        Line::Table(l) => write_table(&mut out, footnotes, l, unit, depth),
+        Line::Image(l) => {
+            out.push_str("@image");
+            let kwargs = [
+                ("caption", l.caption.as_deref()),
+                ("width", l.width.as_deref()),
+            ];
+            write_kwargs(&mut out, &kwargs);
+            write_string_arg(&mut out, &l.url, false);
+        }
+        Line::DotGraph(l) => {
+            out.push_str("@dot");
+            let engine = Some(&*l.engine).filter(|&e| e != "dot");
+            write_kwargs(&mut out, &[("engine", engine)]);
+            write_string_arg(&mut out, &l.code, false);
+        }
+        Line::CodeBlock(l) => {
+            out.push_str("@code");
+            write_kwargs(&mut out, &[("lang", l.lang.as_deref())]);
+
+            write_raw_arg(&mut out, &code_body(&l.code, &unit.repeat(depth)));
+        }
+        Line::DisplayMath(x) => {
+            out.push('$');
+            write_math(&mut out, x);
+        }
+        Line::Rule => out.push_str("---"),
+        Line::Error(e) => {
+            let Span { line, column, .. } = span;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{line}:{column}: can't write a line that failed to parse ({e})"),
+            ));
+        }
+    }
+
+    Ok(out)
+}
+
+/// Get the source of a code block's body, with its lines indented by `indent`.
+///
+/// The code is dedented and has its first and last blank lines trimmed when parsed, so it's
+/// indented along with the call and wrapped in blank lines.
+pub(crate) fn code_body(code: &str, indent: &str) -> String {
+    let mut ret = String::from("\n");
+    for line in code.split('\n') {
+        if !line.trim().is_empty() {
+            ret.push_str(indent);
+            ret.push_str(line);
+        }
+        ret.push('\n');
+    }
+    ret.push_str(indent);
+    ret
+}
+
+fn is_space(term: &Spanned<Term3>) -> bool {
+    matches!(term.value, Term3::Space)
+}
+
+fn write_table(out: &mut String, footnotes: &Footnotes, l: &TableLine, unit: &str, depth: usize) {
+    out.push_str("@table");
+    let header = (!l.header).then_some("no");
+    write_kwargs(
+        out,
+        &[("header", header), ("caption", l.caption.as_deref())],
+    );
+
+    if l.items.is_empty() {
+        out.push_str("{}");
+        return;
+    }
+
+    // one item per line, which is fine since whitespace between them is skipped
+    out.push_str("{\n");
+    for item in &l.items {
+        out.push_str(&unit.repeat(depth + 1));
+        match item {
+            TableItem::Row(cells) => {
+                out.push('@');
+                for cell in cells {
+                    write_terms_arg(out, footnotes, cell, false);
+                }
+            }
+            TableItem::Separator => out.push_str("---"),
+        }
+        out.push('\n');
+    }
+    out.push_str(&unit.repeat(depth));
+    out.push('}');
+}
+
+/// Write terms that are followed by `end` (empty at the end of the line).
+///
+/// `italics` is whether they're inside italics, where every `_` ends a word.
+fn write_terms(
+    out: &mut String,
+    footnotes: &Footnotes,
+    terms: &[Spanned<Term3>],
+    italics: bool,
+    end: &str,
+) {
+    // how a term is written can depend on what comes after it, so the terms are written back to
+    // front
+    let mut pieces: Vec<String> = Vec::with_capacity(terms.len());
+    for t in terms.iter().rev() {
+        let next = pieces.last().map_or(end, |s| s.as_str());
+        let mut s = String::new();
+        write_term(&mut s, footnotes, t, italics, next);
+        pieces.push(s);
+    }
+
+    for s in pieces.iter().rev() {
+        out.push_str(s);
+    }
+}
+
+/// Write a term that is followed by `next`.
+///
+/// Some terms can only be followed by certain others in an argument, where they can be split by a
+/// line break (which is skipped there), so that's what is written between them.
+fn write_term(out: &mut String, footnotes: &Footnotes, term: &Term3, italics: bool, next: &str) {
+    let next_char = next.chars().next();
+
+    match term {
+        Term3::Space => {
+            out.push(' ');
+            if next_char.is_some_and(is::inline_whitespace) {
+                out.push('\n');
+            }
+        }
+        Term3::Word(x) | Term3::Url(x) => {
+            let part_goes_on = write_word(out, x, italics, next_char);
+            if part_goes_on && !italics && next_char == Some('_') {
+                out.push('\n');
+            }
+        }
+        Term3::Tag(x) => {
+            out.push('%');
+            out.push_str(x);
+
+            // a tag takes everything up to the next whitespace (or the rest of the line, if it
+            // looks like a comment)
+            let comment = x.starts_with('%') && !next.is_empty();
+            if comment || next_char.is_some_and(|c| !c.is_whitespace()) {
+                out.push('\n');
+            }
+        }
+        Term3::Math(x) => {
+            let to_line_end = write_math(out, x);
+            if to_line_end && !next.is_empty() {
+                out.push('\n');
+            }
+        }
+        Term3::Ref { content, target } | Term3::Link { content, target } => {
+            out.push_str(match term {
+                Term3::Ref { .. } => "@ref",
+                _ => "@link",
+            });
+            write_string_arg(out, target, italics);
+
+            // with a single argument, there's no content of its own
+            if !content.is_empty() {
+                write_terms_arg(out, footnotes, content, italics);
+            }
+        }
+        Term3::Anchor(x) => {
+            out.push_str("@anchor");
+            write_string_arg(out, x, italics);
+        }
+        Term3::Code(x) => {
+            if x.contains('\n') || x.starts_with(' ') {
+                out.push_str("@c");
+                write_string_arg(out, x, italics);
+            } else {
+                out.push('`');
+                let mut it = x.chars().peekable();
+                while let Some(c) = it.next() {
+                    // a backslash is only kept as-is if it doesn't escape anything
+                    let escape = match c {
+                        '`' => true,
+                        '\\' => matches!(it.peek(), None | Some('`' | '\\')),
+                        _ => false,
+                    };
+                    if escape {
+                        out.push('\\');
+                    }
+                    out.push(c);
+                }
+                out.push('`');
+            }
+        }
+        Term3::Bold(x) => write_emphasis(out, footnotes, '*', x, italics),
+        Term3::Italics(x) => write_emphasis(out, footnotes, '_', x, true),
+        Term3::Strikethrough(x) => write_call(out, footnotes, "s", x, italics),
+        Term3::Underline(x) => write_call(out, footnotes, "u", x, italics),
+        Term3::Highlight(x) => write_call(out, footnotes, "mark", x, italics),
+        Term3::Superscript(x) => write_call(out, footnotes, "sup", x, italics),
+        Term3::Subscript(x) => write_call(out, footnotes, "sub", x, italics),
+        Term3::Kbd(x) => {
+            out.push_str("@kbd");
+            write_string_arg(out, x, italics);
+        }
+        Term3::Footnote(n) => {
+            out.push_str("@fn");
+            // (`write_acr` checked that it's there)
+            write_terms_arg(out, footnotes, &footnotes[n - 1], italics);
+        }
+    }
+
+    // a function call takes any arguments right after it
+    let is_word = matches!(term, Term3::Word(_) | Term3::Url(_));
+    let is_call = !is_word && out.starts_with('@');
+    let raw_arg = next.starts_with('#') && next.trim_start_matches('#').starts_with('{');
+    if is_call && (next.starts_with(['{', '(']) || raw_arg) {
+        out.push('\n');
+    }
+}
+
+fn write_emphasis(
+    out: &mut String,
+    footnotes: &Footnotes,
+    delim: char,
+    terms: &[Spanned<Term3>],
+    italics: bool,
+) {
+    out.push(delim);
+
+    // a space right after the delimiter would make it a plain character, but a tab doesn't
+    let mut terms = terms;
+    if terms.first().is_some_and(is_space) {
+        out.push('\t');
+        terms = &terms[1..];
+    }
+
+    write_terms(out, footnotes, terms, italics, &delim.to_string());
+    out.push(delim);
+}
+
+/// Write a word, escaping the characters that would otherwise start another term.
+///
+/// Returns whether a word part that follows it would still be a part of it.
+fn write_word(out: &mut String, word: &str, italics: bool, next: Option<char>) -> bool {
+    let chars: Vec<char> = word.chars().collect();
+    let after = |i: usize| chars.get(i).copied().or(next);
+
+    let mut part_goes_on = false;
+    for (i, &c) in chars.iter().enumerate() {
+        let escape = match c {
+            '\\' | '`' | '*' => true,
+            // a tag or a comment, unless nothing follows it
+            '%' => !matches!(after(i + 1), None | Some(' ')),
+            '$' => matches!(after(i + 1), Some('{' | ':' | '$')),
+            '_' => {
+                // a word part only ends in italics or before a character that isn't part of words,
+                // and italics can start wherever a term does
+                let term_start = i == 0 || !is::word_char(chars[i - 1]);
+                italics || (term_start && !matches!(after(i + 1), None | Some(' ')))
+            }
+            '@' => {
+                // a function call (or list) only if it's followed by an argument
+                let mut j = i + 1;
+                if chars.get(j).is_some_and(|c| c.is_ascii_alphabetic()) {
+                    while chars.get(j).is_some_and(|c| c.is_ascii_alphanumeric()) {
+                        j += 1;
+                    }
+                }
+                matches!(after(j), None | Some('{' | '(' | '[' | '#'))
+            }
+            _ => false,
+        };
+
+        if escape {
+            debug_assert!(is::escapable_char(c));
+            out.push('\\');
+        }
+        out.push(c);
+        part_goes_on = escape || is::word_char(c);
+    }
+
+    part_goes_on
+}
+
+/// Write math (after a `$`, which also starts display math), using `${...}` whenever it fits.
+///
+/// Returns whether it was written as `$:...`, which goes on up to the end of the line.
+fn write_math(out: &mut String, math: &str) -> bool {
+    let mut depth: usize = 0;
+    let mut balanced = true;
+    let mut it = math.chars();
+    while let Some(c) = it.next() {
+        match c {
+            '\\' if it.next().is_none() => balanced = false,
+            '{' => depth += 1,
+            '}' if depth == 0 => balanced = false,
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    if balanced && depth == 0 {
+        out.push_str("${");
+        out.push_str(math);
+        out.push('}');
+        false
+    } else {
+        out.push_str("$:");
+        out.push_str(math);
+        true
+    }
+}
+
+fn write_call(
+    out: &mut String,
+    footnotes: &Footnotes,
+    name: &str,
+    terms: &[Spanned<Term3>],
+    italics: bool,
+) {
+    out.push('@');
+    out.push_str(name);
+    write_terms_arg(out, footnotes, terms, italics);
+}
+
+/// Write an argument with terms, between `{...}` or, if that would end it early, `(...)`.
+fn write_terms_arg(
+    out: &mut String,
+    footnotes: &Footnotes,
+    terms: &[Spanned<Term3>],
+    italics: bool,
+) {
+    let has_bracket = |close: char| {
+        terms.iter().any(|t| match &t.value {
+            // (a tag takes any brackets it has along with it)
+            Term3::Word(x) | Term3::Url(x) => x.contains(close),
+            _ => false,
+        })
+    };
+
+    // a raw argument is read as a single word with anything in it
+    if let [
+        Spanned {
+            value: Term3::Word(x),
+            ..
+        },
+    ] = terms
+        && (x.contains(char::is_whitespace) || has_bracket('}') && has_bracket(')'))
+    {
+        write_raw_arg(out, x);
+        return;
+    }
+
+    let close = if has_bracket('}') { ')' } else { '}' };
+    out.push(if close == ')' { '(' } else { '{' });
+    write_terms(out, footnotes, terms, italics, &close.to_string());
+    out.push(close);
+}
+
+/// Write an argument that is read as a string, as plain words if they'd read back the same.
+fn write_string_arg(out: &mut String, s: &str, italics: bool) {
+    // whitespace between words is read back as a single space
+    let plain = !s.contains(['\n', '\r', '\t', '}']) && !s.contains("  ");
+    if !plain {
+        write_raw_arg(out, s);
+        return;
+    }
+
+    out.push('{');
+    let words: Vec<&str> = s.split(' ').collect();
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        let next = if i + 1 < words.len() { ' ' } else { '}' };
+        write_word(out, word, italics, Some(next));
+    }
+    out.push('}');
+}
+
+/// Write a raw argument (`#{...}#`), with as many `#` as needed for `s` not to end it.
+fn write_raw_arg(out: &mut String, s: &str) {
+    let mut hashes = String::from("#");
+    while s.contains(&format!("}}{hashes}")) {
+        hashes.push('#');
+    }
+
+    out.push_str(&hashes);
+    out.push('{');
+    out.push_str(s);
+    out.push('}');
+    out.push_str(&hashes);
+}
+
+/// Write keyword arguments (`[key=value ...]`), skipping the ones that weren't given.
+fn write_kwargs(out: &mut String, kwargs: &[(&str, Option<&str>)]) {
+    let given: Vec<(&str, &str)> = kwargs
+        .iter()
+        .filter_map(|&(name, value)| Some((name, value?)))
+        .collect();
+    if given.is_empty() {
+        return;
+    }
+
+    out.push('[');
+    for (i, (name, value)) in given.into_iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        out.push_str(name);
+        out.push('=');
+
+        // bare values are taken as-is, up to whitespace or a bracket
+        let bare = !value.is_empty()
+            && !value.contains(|c: char| c.is_whitespace() || matches!(c, '[' | ']' | '{' | '}'));
+        if bare {
+            out.push_str(value);
+        } else {
+            write_string_arg(out, value, false);
+        }
+    }
+    out.push(']');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, parse_recovering};
+
+    fn to_acr(doc: &Document) -> String {
+        let mut out = Vec::new();
+        write_acr(&mut out, doc).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Check that writing the parsed input and parsing it again gives back the same document.
+    fn assert_roundtrip(input: &str) -> String {
+        let doc = parse(input).unwrap_or_else(|e| panic!("{input:?}: {e}"));
+        let output = to_acr(&doc);
+        let reparsed = parse(&output).unwrap_or_else(|e| panic!("{output:?}: {e}"));
+        assert_eq!(doc, reparsed, "{input:?} was written as {output:?}");
+
+        // and that the output is stable
+        assert_eq!(to_acr(&reparsed), output);
+        output
+    }
+
+    #[test]
+    fn roundtrip_example() {
+        assert_roundtrip(include_str!("../examples/all.acr"));
+    }
+
+    #[test]
+    fn roundtrip_terms() {
+        let inputs = [
+            "a \\\\ b \\* c \\_d\\_ \\` e",
+            "snake_case x_ _ a * b",
+            "\\%%not a comment 50% off \\%tag %tag",
+            "$ ${x} \\${x} a\\$: $5 $$",
+            "user@example.com \\@s{x} @ x@ref{y} a\\@b @anchor{y}",
+            "*bold \\* _and italics \\_ inside_* _\\_x_",
+            "_@kbd{a\\_b} @ref{x\\_y}{z} @c{ a\\_b}_@anchor{x\\_y}",
+            "(_x_) {*y*} \\_(a)",
+            "`code` `a\\`b` `\\\\` `x\\ny` `` @c{ leading space} @c#{a  b}#",
+            "@link{../a.acr}{*a* note} @link{http://x.org} @link{a b}{@link{c}{d}} @link{}",
+            "@ref{target} @ref{some target}{*content*} @ref{a}{a} @ref{x}{http://x.org}\n\
+             @anchor{target}*@anchor{some target}* @anchor{a}@anchor{x}",
+            "@s{a) b} @u(a} b) @mark{@sup{x}} @sub{%tag\n} @kbd{Ctrl+\\%} @kbd#{a\tb}#",
+            "http://example.com/a_b %%comment",
+            "$: x^{2\n",
+            "- [ ] bullet",
+            "* star",
+            "[x] done\n[-] cancelled\n( ) paren",
+            "\\* not a star",
+        ];
+
+        for input in inputs {
+            assert_roundtrip(input);
+        }
+    }
+
+    #[test]
+    fn roundtrip_lines() {
+        let inputs = [
+            "$${ x \\} }\n$$: y {\n$${ multi\n  line }",
+            "@code{}\n@code#{\n\n  a\n\t  b\n\n}#\n@code[lang=rust]#{ x }# \n@code{ }",
+            "@code##{\n  ends with }#\n}##",
+            "@dot#{ a -> b }#\n@dot[engine=fdp]#{\n  x\n}#\n@dot{ a -> b }",
+            "@image{cat.png}\n@image[caption={A cat} width=30%]{a b.png}",
+            "@table{}\n@table[header=no caption=#{x  y}#]{ @{a}{b} --- @{c}{d} }",
+            "x\n  @table{\n    @{*a*}{b c}(d})\n  }\n  @code#{\n    x\n  }#",
+            "@h{A}\n  @h{a}\n@h{!}\n@h[id=a-3]{b}\n@h[id=x]{x}",
+            "@h{Intro}\n  @h{*Sub* section}\n    @h[level=1 id=x]{a}\n      @h[id={}]{b}",
+            "@quote{a _b_}\n  c\n@quote[by={Someone, 1900}]\n@quote[by=x]{}",
+            "---\n  ---\n- ---\n--- x",
+            "a@fn{*b* @fn{c}} d@fn{}\n  e@fn{f}",
+            "@h{A}\n  @ref{a} @ref{b}{c} x @anchor{b}@fn{@ref{b} @anchor{d}}\n@ref{d}",
+        ];
+
+        for input in inputs {
+            assert_roundtrip(input);
+        }
+    }
+
+    #[test]
+    fn roundtrip_in_arguments() {
+        // line breaks are skipped in arguments, so some terms can only be next to each other there
+        let inputs = [
+            "@s{ \r   a   b}",
+            "@s{d\n_b_} @s{d\\\\\n_b_}",
+            "@u(  }%%\n  })",
+            "@s{$: x {\n y}",
+            "@s{@u{a}\n(b)} @s{@kbd{a}\n#{b}}",
+            "x@sup#{2}, H@sub{2}O}#",
+            "@u#{( }\n _ }#",
+        ];
+
+        for input in inputs {
+            assert_roundtrip(input);
+        }
+    }
+
+    #[test]
+    fn roundtrip_structure() {
+        let output = assert_roundtrip("%:indent 4\n\na\n\n    b\n        c\n\n    d\n\ne\n");
+        assert_eq!(
+            output,
+            "%:indent 4\n\na\n\n    b\n        c\n\n    d\n\ne\n"
+        );
+
+        assert_roundtrip("a\n\tb\n\t\tc\n\t\t d");
+        assert_roundtrip("%:indent tab\n\nno children");
+        assert_roundtrip("\n\n%:not a header entry\n");
+    }
+
+    #[test]
+    fn roundtrip_header() {
+        let output = assert_roundtrip(concat!(
+            "%:title A title\n",
+            "  that goes on\n",
+            "%:tags a b\n",
+            "%:custom foo\n",
+            "  bar\n",
+            "%:empty-first\n",
+            "  x\n",
+            "%:katex-macros\n",
+            "  \\RR \\mathbb{R}\n",
+            "  \\empty\n",
+            "%:date 2024-02-29\n",
+            "%:author Someone\n",
+            "%:lang pt-BR\n",
+            "%:description Text\n",
+            "%:indent 2\n",
+            "%:trailing x  \n",
+            "%:body-looks-like-header\n",
+            "%:x y",
+        ));
+
+        assert!(output.starts_with("%:title A title that goes on\n%:author Someone\n"));
+    }
+
+    #[test]
+    fn error_lines_fail() {
+        let (doc, _) = parse_recovering("fine\n`unterminated\n");
+        let err = write_acr(&mut Vec::new(), &doc).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn missing_footnotes_fail() {
+        let mut doc = parse("a@fn{b}\n").unwrap();
+        for n in [0, 2] {
+            let Line::Text(ref mut l) = doc.nodes[0].line else {
+                panic!()
+            };
+            l.content[1].value = Term3::Footnote(n);
+            let err = write_acr(&mut Vec::new(), &doc).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{n}");
+        }
+    }
+}
@@ -64,10 +64,6 @@ impl<'a> CliParser<'a> {
         self.args.iter().find(|a| query == a.name)
     }
 
-    pub fn get_arg_mut(&mut self, query: &str) -> Option<&mut CliArg> {
-        self.args.iter_mut().find(|a| query == a.name)
-    }
-
     pub fn parse_args<I>(&mut self, args: I) -> Result<(), String>
     where
         I: IntoIterator,
@@ -127,7 +123,7 @@ impl<'a> CliParser<'a> {
                 } else {
                     let cur_arg = match self.args.get_mut(arg_i) {
                         Some(x) => x,
-                        None => return Err(self.error_help(format!("too many arguments"))),
+                        None => return Err(self.error_help("too many arguments".to_string())),
                     };
                     cur_arg.value = Some(arg.to_owned());
                     arg_i += 1;
@@ -141,7 +137,7 @@ impl<'a> CliParser<'a> {
         }
 
         if arg_i != self.args.len() {
-            return Err(self.error_help(format!("too few arguments")));
+            return Err(self.error_help("too few arguments".to_string()));
         }
 
         Ok(())
@@ -149,7 +145,7 @@ impl<'a> CliParser<'a> {
 
     pub fn error_help(&self, error: String) -> String {
         self.help();
-        return error;
+        error
     }
 
     pub fn help(&self) {
@@ -172,8 +168,7 @@ impl<'a> CliParser<'a> {
 }
 
 fn get_progname(arg0: &str) -> Option<&str> {
-    Some(arg0)
-        .map(Path::new)
+    Some(Path::new(arg0))
         .and_then(Path::file_name)
         .and_then(|x| x.try_into().ok())
 }
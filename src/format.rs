@@ -0,0 +1,526 @@
+//! Formatter for acrylic source, rewriting a document into a canonical layout.
+//!
+//! Unlike [`crate::write_acr`], this works on the source itself, so comments and the way each term
+//! was written (escapes, math delimiters...) are kept as they are. Only the layout changes:
+//!
+//! - lines are indented with [`FormatStyle::indent`], and so are the inner lines of multi-line
+//!   arguments (e.g. the rows of a table);
+//! - bullets and task prefixes are written with [`FormatStyle::bullet`] and
+//!   [`FormatStyle::task_format`], and the whitespace after them is a single space;
+//! - runs of blank lines become a single one, and blank lines at the start and end of the body are
+//!   removed;
+//! - trailing whitespace is removed, as well as whitespace at the start of a line's content (e.g.
+//!   spaces after the tabs of its indent);
+//! - the bodies of `@code` and `@dot` raw arguments are dedented and indented along with the call.
+
+use crate::acr::code_body;
+use crate::parser::{
+    BulletType, Diagnostics, ErrorKind, Indent, Line, ParseError, Span, Spanned, StandardOptions,
+    TaskFormat, TaskPrefix, TaskState, Term,
+    stage1::{self, IndentStats},
+    stage3::process_code_block_arg,
+};
+
+/// The layout [`format_acr`] rewrites documents into.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatStyle {
+    pub indent: Indent,
+    pub bullet: BulletType,
+    pub task_format: TaskFormat,
+}
+
+impl Default for FormatStyle {
+    fn default() -> Self {
+        Self {
+            indent: Indent::Space(2),
+            bullet: BulletType::Dash,
+            task_format: TaskFormat::Paren,
+        }
+    }
+}
+
+/// Rewrite `source` in the layout described by `style`.
+///
+/// The `%:indent` header entry is changed to match the new indent, or added if it wouldn't be
+/// inferred from the body. Fails if any line fails to parse, or if it's a tab-indented line with
+/// whitespace before what would be read as a prefix, and `style` indents with spaces.
+pub fn format_acr(source: &str, style: &FormatStyle) -> Result<String, ParseError> {
+    let mut diag = Diagnostics::strict();
+    let (_, options, mut lexer) = stage1::parse_header(source, &mut diag)?;
+    let header_end = lexer.offset();
+
+    let f = Formatter {
+        source,
+        old_indent: options.indent,
+        unit: match style.indent {
+            Indent::Tab => "\t".to_owned(),
+            Indent::Space(n) => " ".repeat(n),
+        },
+        style,
+    };
+
+    let mut body = String::new();
+    let mut blank = false;
+    while let Some(line) = lexer.next_line(&options, &mut diag)? {
+        let content = f.line(&line)?;
+        if content.is_empty() {
+            blank = !body.is_empty();
+            continue;
+        }
+
+        if blank {
+            body.push('\n');
+            blank = false;
+        }
+        body.push_str(&f.unit.repeat(line.indent));
+        body.push_str(&content);
+        body.push('\n');
+    }
+
+    let mut header = String::new();
+    let mut has_indent = false;
+    let mut lines = source[..header_end]
+        .lines()
+        .map(str::trim_end)
+        .filter(|l| !l.is_empty())
+        .peekable();
+    while let Some(l) = lines.next() {
+        let is_indent = l
+            .strip_prefix("%:indent")
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace));
+        if !is_indent {
+            header.push_str(l);
+            header.push('\n');
+            continue;
+        }
+
+        // replace the entry along with its continuation lines
+        while lines
+            .next_if(|l| l.starts_with(char::is_whitespace))
+            .is_some()
+        {}
+        header.push_str(&indent_entry(style.indent));
+        has_indent = true;
+    }
+
+    // without an entry, the indent is inferred from the body, which only matters if anything is
+    // indented at all (even if just inside an argument)
+    let inferred = IndentStats::of(&body, 0, 1, body.len())
+        .indent()
+        .unwrap_or(StandardOptions::default().indent);
+    let indented = body.lines().any(|l| l.starts_with(char::is_whitespace));
+    if !has_indent && indented && inferred != style.indent {
+        header.push_str(&indent_entry(style.indent));
+    }
+
+    // the first line of the body would be read as a part of the header if it looked like an entry
+    if !header.is_empty() && !body.is_empty() || body.starts_with("%:") {
+        header.push('\n');
+    }
+    header.push_str(&body);
+
+    Ok(header)
+}
+
+fn indent_entry(indent: Indent) -> String {
+    match indent {
+        Indent::Tab => "%:indent tab\n".to_owned(),
+        Indent::Space(n) => format!("%:indent {n}\n"),
+    }
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    old_indent: Indent,
+    unit: String,
+    style: &'a FormatStyle,
+}
+
+impl Formatter<'_> {
+    /// Get the formatted source of a line, without its indent.
+    ///
+    /// Empty for blank lines (including the ones with nothing but whitespace). Fails if the line
+    /// starts with whitespace that can't be kept.
+    fn line(&self, line: &Line) -> Result<String, ParseError> {
+        let Some(first) = line.terms.iter().position(|t| !is_space(t)) else {
+            // either blank or just a comment (and an empty one is the same as a blank line)
+            let comment = self.slice(line.span).trim();
+            return Ok(if comment == "%%" { "" } else { comment }.to_owned());
+        };
+
+        let mut leaves = Vec::new();
+        let mut spaces = Vec::new();
+        collect_spans(&line.terms, &mut leaves, &mut spaces);
+
+        let start = line.terms[first].span.start;
+        let last_start = self
+            .slice(line.span)
+            .rfind('\n')
+            .map_or(start, |i| line.span.start + i + 1);
+        let mut end = self.trailing_junk(last_start.max(start), line.span.end, &leaves);
+
+        // a bullet has to be followed by whitespace, even if there's nothing after it
+        let last = line.terms.iter().rfind(|t| !is_space(t));
+        if let Some(t) = last.filter(|t| matches!(t.value, Term::BulletPrefix(_))) {
+            end = end.max(t.span.end + 1);
+        }
+
+        // replacements for ranges of the source, in order
+        let mut edits: Vec<(usize, usize, String)> = Vec::new();
+
+        for (i, t) in line.terms.iter().enumerate() {
+            let prefix = match &t.value {
+                Term::BulletPrefix(_) => match self.style.bullet {
+                    BulletType::Dash => "-".to_owned(),
+                    BulletType::Star => "*".to_owned(),
+                },
+                Term::TaskPrefix(TaskPrefix { state, .. }) => {
+                    let state = match state {
+                        TaskState::Todo => ' ',
+                        TaskState::Done => 'x',
+                        TaskState::Cancelled => '-',
+                    };
+                    match self.style.task_format {
+                        TaskFormat::Paren => format!("({state})"),
+                        TaskFormat::Square => format!("[{state}]"),
+                    }
+                }
+                _ => continue,
+            };
+            edits.push((t.span.start, t.span.end, prefix));
+
+            if let Some(next) = line.terms.get(i + 1).filter(|t| is_space(t)) {
+                edits.push((next.span.start, next.span.end, " ".to_owned()));
+            }
+        }
+
+        let depth = self.unit.repeat(line.indent);
+        for t in &line.terms {
+            if let Some((span, code)) = raw_code_arg(self.source, t) {
+                let code: Vec<&str> = code.split('\n').map(str::trim_end).collect();
+                edits.push((span.start, span.end, code_body(&code.join("\n"), &depth)));
+            }
+        }
+
+        // line breaks between the terms of an argument, each along with the whitespace around it
+        let breaks: Vec<usize> = self.source[start..end]
+            .match_indices('\n')
+            .map(|(i, _)| start + i)
+            .filter(|&i| !in_any(&leaves, i))
+            .collect();
+        let mut k = 0;
+        while k < breaks.len() {
+            let line_start = self.source[..breaks[k]].rfind('\n').map_or(0, |i| i + 1);
+            let junk = self.trailing_junk(line_start.max(start), breaks[k], &leaves);
+
+            // blank lines (or ones with just an empty comment) go along with it
+            let mut last = k;
+            while let Some(&i) = breaks.get(last + 1)
+                && self.trailing_junk(breaks[last] + 1, i, &leaves) == breaks[last] + 1
+            {
+                last += 1;
+            }
+
+            let next = breaks[last] + 1;
+            let after = &self.source[next..];
+            let leading = after.len() - after.trim_start_matches([' ', '\t']).len();
+
+            // whitespace is kept if it's all that separates two terms (the line break doesn't)
+            let separates = spaces.iter().any(|&i| junk <= i && i < next) && leading == 0;
+            let mut new = if separates { " " } else { "" }.to_owned();
+            new.push_str(if last > k { "\n\n" } else { "\n" });
+            new.push_str(&self.reindent(&after[..leading]));
+            edits.push((junk, next + leading, new));
+
+            k = last + 1;
+        }
+
+        edits.sort_by_key(|&(s, ..)| s);
+
+        let mut ret = String::new();
+
+        // the whitespace before the content is left out, unless without it the content would be
+        // read as a prefix (which can only be told apart from the indent with tabs)
+        if first > 0 && reads_as_prefix(&self.source[start..]) {
+            if self.style.indent != Indent::Tab {
+                return Err(ParseError::new(
+                    ErrorKind::MixedIndent,
+                    line.terms[0].span.to(line.terms[first - 1].span),
+                    "whitespace before something that looks like a bullet or task prefix, which \
+                     can't be kept when indenting with spaces",
+                ));
+            }
+            ret.push(' ');
+        }
+
+        let mut pos = start;
+        for (s, e, new) in edits {
+            if s >= end {
+                break;
+            }
+            ret.push_str(&self.source[pos..s]);
+            ret.push_str(&new);
+            pos = e;
+        }
+        if pos < end {
+            ret.push_str(&self.source[pos..end]);
+        }
+
+        Ok(ret)
+    }
+
+    /// Get where the whitespace at the end of `source[line_start..end]` starts, along with an empty
+    /// comment before it.
+    fn trailing_junk(&self, line_start: usize, end: usize, leaves: &[Span]) -> usize {
+        let text = &self.source[line_start..end];
+        let trimmed = text.trim_end_matches([' ', '\t', '\r']);
+        let Some(rest) = trimmed.strip_suffix("%%") else {
+            return line_start + trimmed.len();
+        };
+
+        // without the whitespace after it, an empty comment would be read as a tag
+        if in_any(leaves, line_start + rest.len()) {
+            line_start + trimmed.len()
+        } else if rest.ends_with(['%', '\\']) {
+            // (but it might not even be a comment)
+            end
+        } else {
+            line_start + rest.trim_end_matches([' ', '\t']).len()
+        }
+    }
+
+    /// Change the indent unit of the whitespace at the start of a line.
+    fn reindent(&self, ws: &str) -> String {
+        let (levels, rest) = match self.old_indent {
+            Indent::Tab => {
+                let n = ws.len() - ws.trim_start_matches('\t').len();
+                (n, &ws[n..])
+            }
+            Indent::Space(size) => {
+                let n = ws.len() - ws.trim_start_matches(' ').len();
+                (n / size, &ws[n - n % size..])
+            }
+        };
+        self.unit.repeat(levels) + rest
+    }
+
+    fn slice(&self, span: Span) -> &str {
+        &self.source[span.start..span.end]
+    }
+}
+
+fn is_space(term: &Spanned<Term>) -> bool {
+    matches!(term.value, Term::Space)
+}
+
+fn in_any(spans: &[Span], offset: usize) -> bool {
+    spans.iter().any(|s| s.start <= offset && offset < s.end)
+}
+
+/// Whether a line starting with `s` would start with a bullet or a task prefix.
+fn reads_as_prefix(s: &str) -> bool {
+    let start: Vec<char> = s.chars().take(3).collect();
+    matches!(
+        start[..],
+        ['-' | '*', ' ' | '\t', ..]
+            | ['[', ' ' | 'x' | 'X' | '-', ']']
+            | ['(', ' ' | 'x' | 'X' | '-', ')']
+    )
+}
+
+/// Get the raw argument of a `@code` or `@dot` call with more than one line, along with its span.
+fn raw_code_arg(source: &str, term: &Spanned<Term>) -> Option<(Span, String)> {
+    let Term::FuncCall(fc) = &term.value else {
+        return None;
+    };
+    if !matches!(&*fc.name, "code" | "dot") {
+        return None;
+    }
+
+    let [
+        Spanned {
+            value: Term::Word(code),
+            span,
+        },
+    ] = &fc.args.last()?[..]
+    else {
+        return None;
+    };
+    let is_raw = source[..span.start].ends_with("#{");
+    let multiline = source[span.start..span.end].contains('\n');
+
+    (is_raw && multiline).then(|| (*span, process_code_block_arg(code)))
+}
+
+/// Collect the spans of the terms that don't have other terms inside, and the start of every space
+/// term.
+fn collect_spans(terms: &[Spanned<Term>], leaves: &mut Vec<Span>, spaces: &mut Vec<usize>) {
+    for t in terms {
+        match &t.value {
+            Term::Space => spaces.push(t.span.start),
+            Term::InlineBold(x) | Term::InlineItalics(x) => collect_spans(x, leaves, spaces),
+            Term::List(args) => {
+                for arg in args {
+                    collect_spans(arg, leaves, spaces);
+                }
+            }
+            Term::FuncCall(fc) => {
+                for arg in &fc.args {
+                    collect_spans(arg, leaves, spaces);
+                }
+                for kw in &fc.kwargs {
+                    collect_spans(&kw.value.arg, leaves, spaces);
+                }
+            }
+            _ => leaves.push(t.span),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use crate::parser::{Node3, stage3::Line as Line3};
+
+    fn style(indent: Indent) -> FormatStyle {
+        FormatStyle {
+            indent,
+            ..FormatStyle::default()
+        }
+    }
+
+    /// Format `source` and check that it's already formatted after that.
+    fn format(source: &str, style: &FormatStyle) -> String {
+        let formatted = format_acr(source, style).unwrap();
+        assert_eq!(format_acr(&formatted, style).unwrap(), formatted);
+        formatted
+    }
+
+    #[test]
+    fn same_document() {
+        let source = include_str!("../examples/all.acr");
+        assert_eq!(format(source, &FormatStyle::default()), source);
+
+        // DOT code isn't dedented when parsed (but whitespace doesn't matter there)
+        fn nodes(source: &str) -> Vec<Node3<'_>> {
+            let mut nodes = parse(source).unwrap().nodes;
+            for node in nodes.iter_mut().flat_map(|n| &mut n.children) {
+                if let Line3::DotGraph(l) = &mut node.line {
+                    l.code = process_code_block_arg(&l.code).into();
+                }
+            }
+            nodes
+        }
+
+        for indent in [Indent::Tab, Indent::Space(4), Indent::Space(2)] {
+            let formatted = format(source, &style(indent));
+            assert_eq!(parse(&formatted).unwrap().options.indent, indent);
+            assert_eq!(nodes(&formatted), nodes(source), "{formatted}");
+        }
+    }
+
+    #[test]
+    fn canonical_layout() {
+        let source = concat!(
+            "%:title  Messy   \n",
+            "%:indent tab\n",
+            "\n\n\n",
+            "Top   \n",
+            "\t* item  %% a note   \n",
+            "\t[X] done\n",
+            "\t[ ]\ttodo\n",
+            "\n\n\n",
+            "\t%% a comment\n",
+            "\t@table{\n",
+            "\t\t@{a}{b}   \n",
+            "\n",
+            "\t\t@{c}{d} %% another note \n",
+            "\t}\n",
+            "\t@code[lang=py]#{\n",
+            "\t\t\tdef f():   \n",
+            "\t\t\t    pass\n",
+            "\t}#\n",
+            "\t@s{foo \n",
+            "bar}\n",
+            "\n\n",
+        );
+        let expected = concat!(
+            "%:title  Messy\n",
+            "%:indent 2\n",
+            "\n",
+            "Top\n",
+            "  - item  %% a note\n",
+            "  (x) done\n",
+            "  ( ) todo\n",
+            "\n",
+            "  %% a comment\n",
+            "  @table{\n",
+            "    @{a}{b}\n",
+            "\n",
+            "    @{c}{d} %% another note\n",
+            "  }\n",
+            "  @code[lang=py]#{\n",
+            "  def f():\n",
+            "      pass\n",
+            "  }#\n",
+            // the space is all that separates the words
+            "  @s{foo \n",
+            "bar}\n",
+        );
+        assert_eq!(format(source, &FormatStyle::default()), expected);
+
+        let square_stars = FormatStyle {
+            bullet: BulletType::Star,
+            task_format: TaskFormat::Square,
+            ..FormatStyle::default()
+        };
+        assert_eq!(
+            format("- a\n( ) b\n(-) c\n-\n", &square_stars),
+            "* a\n[ ] b\n[-] c\n-\n"
+        );
+        assert_eq!(format("-  \n", &square_stars), "* \n");
+    }
+
+    #[test]
+    fn indent_entry() {
+        // only added when the body is indented, and wouldn't be inferred as the right indent
+        assert_eq!(format("a\n    b\n", &style(Indent::Tab)), "a\n\tb\n");
+        assert_eq!(format("a\n\tb\n", &style(Indent::Space(4))), "a\n    b\n");
+        assert_eq!(
+            format("a\n\tb\n\t\tc\n", &style(Indent::Space(2))),
+            "a\n  b\n    c\n"
+        );
+        assert_eq!(
+            format("a\n\t\tb\n", &style(Indent::Space(2))),
+            "%:indent 2\n\na\n    b\n"
+        );
+        assert_eq!(
+            format("%:indent 4\nnot indented\n", &style(Indent::Tab)),
+            "%:indent tab\n\nnot indented\n"
+        );
+        assert_eq!(
+            format("\n\n%:not a header entry\n", &FormatStyle::default()),
+            "\n%:not a header entry\n"
+        );
+    }
+
+    #[test]
+    fn leading_whitespace() {
+        // with tabs, whitespace at the start of the content is the only thing keeping it from
+        // being read as a prefix
+        let source = "a\n\t (x) b\n\t\t - c\n\t  d\n";
+        let expected = "a\n\t (x) b\n\t\t - c\n\td\n";
+        assert_eq!(format(source, &style(Indent::Tab)), expected);
+
+        let e = format_acr(source, &FormatStyle::default()).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::MixedIndent);
+        assert_eq!(e.span.line, 2);
+    }
+
+    #[test]
+    fn errors_fail() {
+        assert!(format_acr("%:indent 2\na\n   b\n", &FormatStyle::default()).is_err());
+        assert!(format_acr("@s{a\n", &FormatStyle::default()).is_err());
+    }
+}
@@ -3,15 +3,20 @@
 //! Supports display math via KaTeX, and includes built-in CSS and JS.
 
 use crate::parser::{
-    stage3::{BulletType, Document, Line, TableItem, TaskPrefix, TaskState, TextLine},
-    Node3, Term3,
+    Node3, Span, Spanned, StandardOptions, Term3,
+    refs::RefIndex,
+    stage3::{
+        BulletType, Document, Line, TableItem, TableLine, TaskPrefix, TaskState, TextLine,
+        is_link_target, is_url,
+    },
 };
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::io::{self, BufWriter, Write};
 use std::process::{Command, Stdio};
 
 mod primitives;
-use primitives::{elem, text};
+use primitives::{close_tag, elem, open_tag, text};
 
 #[derive(Debug, Clone)]
 pub struct HtmlOptions<'a> {
@@ -19,16 +24,16 @@ pub struct HtmlOptions<'a> {
     pub katex_path: &'a str,
 }
 
-type AttrsMap<'a> = HashMap<&'a str, String>;
+type AttrsMap<'a> = BTreeMap<&'a str, String>;
 
-const HEADER_METATAGS: &'static str = concat!(
+const HEADER_METATAGS: &str = concat!(
     r#"<meta name="viewport" content="width=device-width,initial-scale=1,maximum-scale=1,user-scalable=no"/>"#,
     r#"<meta http-equiv="X-UA-Compatible" content="IE=edge,chrome=1"/>"#,
     r#"<meta name="HandheldFriendly" content="true"/>"#,
     r#"<meta charset="UTF-8"/>"#,
 );
-const DEFAULT_STYLE: &'static str = include_str!("style.css");
-const INIT_JS: &'static str = include_str!("script.js");
+const DEFAULT_STYLE: &str = include_str!("style.css");
+const INIT_JS: &str = include_str!("script.js");
 const SPACE_PER_INDENT_EM: f32 = 1.25;
 
 /// Write a HTML representation of `doc` into `w`.
@@ -37,31 +42,143 @@ pub fn write_html<W: Write>(
     doc: &Document,
     options: &HtmlOptions<'_>,
 ) -> io::Result<()> {
-    let write_head = |w: &mut W| {
-        write!(w, "{}", HEADER_METATAGS)?;
-        elem(w, "title", [], |w| text(w, &doc.options.title))?;
-        write_katex_header(w, options.katex_path)?;
-        write!(w, "<style>{}</style>", DEFAULT_STYLE)?;
-        Ok(())
-    };
+    let mut html = HtmlWriter::start_document(w, doc, options)?;
+    for node in &doc.nodes {
+        html.write_node(node)?;
+    }
+    html.write_footnotes(&doc.footnotes)?;
+    html.finish()?;
+
+    Ok(())
+}
 
-    let write_article = |w: &mut W| {
-        if !doc.options.title.is_empty() {
-            elem(w, "h1", [], |w| text(w, &doc.options.title))?;
+/// Writes a HTML document a piece at a time: the start, each top-level node, and the end.
+///
+/// This is for writing nodes as soon as they're parsed (see [`crate::parser::stream`]). A ref
+/// written before the anchor it points to shows its target, since the text of the anchor isn't
+/// known yet.
+pub struct HtmlWriter<W: Write> {
+    w: W,
+    /// The anchors written so far.
+    refs: RefIndex,
+    /// Whether `refs` already has the whole document (so what's written isn't added again).
+    indexed: bool,
+}
+
+impl<W: Write> HtmlWriter<W> {
+    /// Write everything that comes before the nodes.
+    pub fn start(mut w: W, opts: &StandardOptions, options: &HtmlOptions<'_>) -> io::Result<Self> {
+        let html_attrs = opts.lang.as_deref().map(|lang| ("lang", lang));
+
+        writeln!(w, "<!DOCTYPE html>")?;
+        open_tag(&mut w, "html", html_attrs)?;
+        elem(&mut w, "head", [], |w| write_head(w, opts, options))?;
+        open_tag(&mut w, "body", [])?;
+        open_tag(&mut w, "main", [])?;
+
+        if !opts.title.is_empty() {
+            elem(&mut w, "h1", [], |w| text(w, &opts.title))?;
         }
 
-        for node in &doc.nodes {
-            write_node(w, node, 0)?;
+        Ok(Self {
+            w,
+            refs: RefIndex::new(),
+            indexed: false,
+        })
+    }
+
+    /// Write everything that comes before the nodes of `doc`, with its anchors already added (so
+    /// refs to anchors further down also get their text).
+    fn start_document(w: W, doc: &Document, options: &HtmlOptions<'_>) -> io::Result<Self> {
+        let mut html = Self::start(w, &doc.options, options)?;
+        html.refs = RefIndex::of(doc);
+        html.indexed = true;
+        Ok(html)
+    }
+
+    pub fn write_node(&mut self, node: &Node3) -> io::Result<()> {
+        if !self.indexed {
+            self.refs.add_node(node);
         }
+        write_node(&mut self.w, &self.refs, node, 0)
+    }
 
-        Ok(())
+    /// Write the footnotes section (if there are any), after the nodes.
+    pub fn write_footnotes(&mut self, footnotes: &[Vec<Spanned<Term3>>]) -> io::Result<()> {
+        if footnotes.is_empty() {
+            return Ok(());
+        }
+
+        if !self.indexed {
+            for content in footnotes {
+                self.refs.add_terms(content);
+            }
+        }
+        let (w, refs) = (&mut self.w, &self.refs);
+        elem(w, "section", [("class", "acr-footnotes")], |w| {
+            elem(w, "hr", [], do_nothing)?;
+            elem(w, "ol", [], |w| {
+                for (i, content) in footnotes.iter().enumerate() {
+                    let n = i + 1;
+                    elem(w, "li", [("id", &*format!("fn-{n}"))], |w| {
+                        write_terms(w, refs, content)?;
+                        text(w, " ")?;
+                        let back = [
+                            ("href", &*format!("#fnref-{n}")),
+                            ("class", "acr-footnote-back"),
+                        ];
+                        elem(w, "a", back, |w| text(w, "↩"))
+                    })?;
+                    writeln!(w)?;
+                }
+                Ok(())
+            })
+        })?;
+        writeln!(w)
+    }
+
+    /// Write the end of the document, and give the writer back.
+    pub fn finish(mut self) -> io::Result<W> {
+        close_tag(&mut self.w, "main")?;
+        close_tag(&mut self.w, "body")?;
+        close_tag(&mut self.w, "html")?;
+        Ok(self.w)
+    }
+}
+
+fn write_head<W: Write>(
+    w: &mut W,
+    opts: &StandardOptions,
+    options: &HtmlOptions<'_>,
+) -> io::Result<()> {
+    write!(w, "{}", HEADER_METATAGS)?;
+    elem(w, "title", [], |w| text(w, &opts.title))?;
+
+    let write_meta = |w: &mut W, name: &str, content: &str| {
+        elem(
+            w,
+            "meta",
+            [("name", name), ("content", content)],
+            do_nothing,
+        )
     };
+    if let Some(author) = &opts.author {
+        write_meta(w, "author", author)?;
+    }
+    if let Some(description) = &opts.description {
+        write_meta(w, "description", description)?;
+    }
+    if let Some(date) = &opts.date {
+        write_meta(w, "date", &date.to_string())?;
+    }
+    if !opts.tags.is_empty() {
+        write_meta(w, "keywords", &opts.tags.join(", "))?;
+    }
 
-    write!(w, "<!DOCTYPE html>\n")?;
-    elem(w, "html", [], |w| {
-        elem(w, "head", [], write_head)?;
-        elem(w, "body", [], |w| elem(w, "main", [], write_article))
-    })
+    write_katex_macros(w, &opts.katex_macros)?;
+    write_katex_header(w, options.katex_path)?;
+    write!(w, "<style>{}</style>", DEFAULT_STYLE)?;
+    Ok(())
 }
 
 fn do_nothing<T>(_: &mut T) -> io::Result<()> {
@@ -69,7 +186,7 @@ fn do_nothing<T>(_: &mut T) -> io::Result<()> {
 }
 
 fn write_katex_header<W: Write>(w: &mut W, katex_path: &str) -> io::Result<()> {
-    let prefix = if katex_path.len() > 0 && !katex_path.ends_with("/") {
+    let prefix = if !katex_path.is_empty() && !katex_path.ends_with("/") {
         format!("{katex_path}/")
     } else {
         katex_path.to_owned()
@@ -94,6 +211,43 @@ fn write_katex_header<W: Write>(w: &mut W, katex_path: &str) -> io::Result<()> {
     Ok(())
 }
 
+/// Write the macros as JSON, for the init script to pass to KaTeX.
+fn write_katex_macros<W: Write>(w: &mut W, macros: &[(String, String)]) -> io::Result<()> {
+    if macros.is_empty() {
+        return Ok(());
+    }
+
+    let attrs = [("type", "application/json"), ("id", "acr-katex-macros")];
+    elem(w, "script", attrs, |w| {
+        write!(w, "{{")?;
+        for (i, (name, expansion)) in macros.iter().enumerate() {
+            if i > 0 {
+                write!(w, ",")?;
+            }
+            write!(w, "{}:{}", json_string(name), json_string(expansion))?;
+        }
+        write!(w, "}}")
+    })
+}
+
+/// Quote `s` as a JSON string that is also safe to put inside a `<script>` tag.
+fn json_string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            c if matches!(c, '<' | '>' | '&') || c.is_control() => {
+                ret.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
 fn attrs_to_iter<'a>(attrs: &'a AttrsMap<'a>) -> impl Iterator<Item = (&'a str, &'a str)> {
     attrs.iter().map(|(a, b)| (*a, b.as_str()))
 }
@@ -104,23 +258,31 @@ fn attrs_list_to_iter<'a>(
     attrs.iter().map(|(a, b)| (*a, b.as_str()))
 }
 
-pub fn write_node<W: Write>(w: &mut W, node: &Node3, indent: usize) -> io::Result<()> {
-    let mut attrs: AttrsMap<'_> = HashMap::new();
+pub fn write_node<W: Write>(
+    w: &mut W,
+    refs: &RefIndex,
+    node: &Node3,
+    indent: usize,
+) -> io::Result<()> {
+    let mut attrs: AttrsMap<'_> = BTreeMap::new();
+
+    // lets scripts map the output back to the source
+    attrs.insert("data-line", node.span.line.to_string());
 
     if indent > 0 {
         let style = format!("margin-left: {:.2}em;", indent as f32 * SPACE_PER_INDENT_EM);
         attrs.insert("style", style);
     }
 
-    fn is_fold_tag(term: &Term3) -> bool {
-        match term {
+    fn is_fold_tag(term: &Spanned<Term3>) -> bool {
+        match &term.value {
             Term3::Tag(tag) => tag == "-fold",
             _ => false,
         }
     }
 
     let write_text_line = |w: &mut W, tag: &str, line: &TextLine, attrs: &AttrsMap<'_>| {
-        elem(w, tag, attrs_to_iter(&attrs), |w| {
+        elem(w, tag, attrs_to_iter(attrs), |w| {
             if let Some(pfx) = &line.bullet {
                 match pfx {
                     BulletType::Dash => text(w, "-")?,
@@ -141,11 +303,11 @@ pub fn write_node<W: Write>(w: &mut W, node: &Node3, indent: usize) -> io::Resul
             }
 
             for term in &line.content {
-                write_term(w, term)?;
+                write_term(w, refs, term)?;
             }
             Ok(())
         })?;
-        write!(w, "\n")?;
+        writeln!(w)?;
 
         Ok(())
     };
@@ -154,14 +316,30 @@ pub fn write_node<W: Write>(w: &mut W, node: &Node3, indent: usize) -> io::Resul
         Line::Text(l) => {
             if l.content.iter().any(is_fold_tag) {
                 elem(w, "details", [], |w| {
-                    write_text_line(w, "summary", &l, &attrs)
+                    write_text_line(w, "summary", l, &attrs)
                 })?;
             } else {
-                write_text_line(w, "p", &l, &attrs)?;
+                write_text_line(w, "p", l, &attrs)?;
+            }
+        }
+        Line::Heading(l) => {
+            attrs.insert("id", l.id.clone());
+            let tag = format!("h{}", l.level + 1);
+            elem(w, &tag, attrs_to_iter(&attrs), |w| {
+                write_terms(w, refs, &l.content)
+            })?;
+            writeln!(w)?;
+        }
+        Line::Quote(l) => {
+            // the children are the rest of the quote, so it's only closed after them
+            open_tag(w, "blockquote", attrs_to_iter(&attrs))?;
+            if !l.content.is_empty() {
+                elem(w, "p", [], |w| write_terms(w, refs, &l.content))?;
+                writeln!(w)?;
             }
         }
         Line::Table(l) => {
-            write_table(w, l.columns, &l.items, &attrs)?;
+            write_table(w, refs, l, &attrs)?;
         }
         Line::CodeBlock(x) => {
             let mut cattrs = AttrsMap::new();
@@ -175,11 +353,17 @@ pub fn write_node<W: Write>(w: &mut W, node: &Node3, indent: usize) -> io::Resul
         }
         Line::DisplayMath(x) => {
             attrs.insert("class", "katex-display".into());
-            elem(w, "p", attrs_to_iter(&attrs), |w| text(w, &x))?;
+            elem(w, "p", attrs_to_iter(&attrs), |w| text(w, x))?;
+        }
+        Line::Rule => {
+            elem(w, "hr", attrs_to_iter(&attrs), do_nothing)?;
+            writeln!(w)?;
         }
         Line::DotGraph(x) => {
-            let svg_text =
-                dot_to_svg(&x.code, &x.engine).expect("failed to run dot TODO(proper error msg)");
+            let svg_text = dot_to_svg(&x.code, &x.engine).map_err(|e| {
+                let Span { line, column, .. } = node.span;
+                io::Error::other(format!("{line}:{column}: failed to render dot graph: {e}"))
+            })?;
 
             elem(w, "div", attrs_to_iter(&attrs), |w| {
                 write!(w, "{}", svg_text)
@@ -188,7 +372,7 @@ pub fn write_node<W: Write>(w: &mut W, node: &Node3, indent: usize) -> io::Resul
         Line::Image(x) => {
             attrs
                 .entry("style")
-                .or_insert_with(|| String::new())
+                .or_default()
                 .push_str(" text-align: center;");
 
             elem(w, "div", attrs_to_iter(&attrs), |w| {
@@ -196,37 +380,80 @@ pub fn write_node<W: Write>(w: &mut W, node: &Node3, indent: usize) -> io::Resul
                 if let Some(c) = &x.caption {
                     a_img.push(("alt", c.clone()));
                 }
+                if let Some(width) = &x.width {
+                    a_img.push(("width", width.clone()));
+                }
 
                 elem(w, "img", attrs_list_to_iter(&a_img), |_| Ok(()))?;
                 if let Some(c) = &x.caption {
-                    elem(w, "p", attrs_list_to_iter(&[]), |w| text(w, &c))?;
+                    elem(w, "p", attrs_list_to_iter(&[]), |w| text(w, c))?;
                 }
 
                 Ok(())
             })?;
         }
+        Line::Error(e) => {
+            attrs.insert("class", "acr-error".into());
+            elem(w, "p", attrs_to_iter(&attrs), |w| text(w, &e.to_string()))?;
+        }
     }
 
     if node.bottom_spacing {
         writeln!(w, r#"<div class="acr-spacing"></div>"#)?;
     }
 
+    // (the quote is already indented, and its children are inside it)
+    let child_indent = match node.line {
+        Line::Quote(_) => 0,
+        _ => indent + 1,
+    };
     for child in &node.children {
-        write_node(w, child, indent + 1)?;
+        write_node(w, refs, child, child_indent)?;
+    }
+
+    if let Line::Quote(l) = &node.line {
+        if let Some(by) = &l.attribution {
+            elem(w, "footer", [], |w| {
+                text(w, "— ")?;
+                elem(w, "cite", [], |w| text(w, by))
+            })?;
+        }
+        close_tag(w, "blockquote")?;
+        writeln!(w)?;
     }
 
     Ok(())
 }
 
-fn write_terms<W: Write>(w: &mut W, terms: &[Term3]) -> io::Result<()> {
+/// Where a `@link` to `target` goes in the HTML output, if anywhere.
+///
+/// Relative links to other acrylic files go to what they're rendered as instead, so notes that
+/// link to each other still do once they're all rendered. Targets that could run code (see
+/// [`is_link_target`]) don't go anywhere, as a document can be made without the parser checking
+/// them.
+fn link_href(target: &str) -> Option<Cow<'_, str>> {
+    if !is_link_target(target) {
+        return None;
+    }
+
+    let relative = !is_url(target) && !target.starts_with('/');
+    let (path, rest) = target.split_at(target.find(['#', '?']).unwrap_or(target.len()));
+
+    Some(match path.strip_suffix(".acr") {
+        Some(stem) if relative => Cow::Owned(format!("{stem}.html{rest}")),
+        _ => Cow::Borrowed(target),
+    })
+}
+
+fn write_terms<W: Write>(w: &mut W, refs: &RefIndex, terms: &[Spanned<Term3>]) -> io::Result<()> {
     for t in terms {
-        write_term(w, t)?;
+        write_term(w, refs, t)?;
     }
 
     Ok(())
 }
 
-fn write_term<W: Write>(w: &mut W, term: &Term3) -> io::Result<()> {
+fn write_term<W: Write>(w: &mut W, refs: &RefIndex, term: &Term3) -> io::Result<()> {
     use Term3::*;
 
     match term {
@@ -236,14 +463,51 @@ fn write_term<W: Write>(w: &mut W, term: &Term3) -> io::Result<()> {
             text(w, "%")?;
             text(w, x)
         })?,
-        Url(x) => elem(w, "a", [("href", x.as_str())], |w| text(w, x))?,
+        Url(x) => elem(w, "a", [("href", &**x)], |w| text(w, x))?,
         Math(x) => elem(w, "span", [("class", "katex-inline")], |w| text(w, x))?,
         Code(x) => write_inline_code(w, x)?,
-        Bold(x) => elem(w, "b", [], |w| text(w, x))?,
-        Italics(x) => elem(w, "i", [], |w| text(w, x))?,
+        Bold(x) => elem(w, "b", [], |w| write_terms(w, refs, x))?,
+        Italics(x) => elem(w, "i", [], |w| write_terms(w, refs, x))?,
+        Strikethrough(x) => elem(w, "s", [], |w| write_terms(w, refs, x))?,
+        Underline(x) => elem(w, "u", [], |w| write_terms(w, refs, x))?,
+        Highlight(x) => elem(w, "mark", [], |w| write_terms(w, refs, x))?,
+        Superscript(x) => elem(w, "sup", [], |w| write_terms(w, refs, x))?,
+        Subscript(x) => elem(w, "sub", [], |w| write_terms(w, refs, x))?,
+        Kbd(x) => elem(w, "kbd", [], |w| text(w, x))?,
         Ref { content, target } => {
-            elem(w, "span", [("class", "acr-href"), ("title", target)], |w| {
-                write_terms(w, content)
+            elem(
+                w,
+                "a",
+                [("href", &*format!("#{target}"))],
+                |w| match content.is_empty() {
+                    true => text(w, refs.text(target).unwrap_or(target)),
+                    false => write_terms(w, refs, content),
+                },
+            )?;
+        }
+        Link { content, target } => {
+            let href = link_href(target);
+            let attrs = href.as_deref().map(|href| ("href", href));
+            elem(w, "a", attrs, |w| match content.is_empty() {
+                true => text(w, target),
+                false => write_terms(w, refs, content),
+            })?;
+        }
+        Anchor(x) => elem(
+            w,
+            "span",
+            [("class", "acr-anchor"), ("id", &**x)],
+            do_nothing,
+        )?,
+        Footnote(n) => {
+            let attrs = [
+                ("class", "acr-footnote-ref"),
+                ("id", &*format!("fnref-{n}")),
+            ];
+            elem(w, "sup", attrs, |w| {
+                elem(w, "a", [("href", &*format!("#fn-{n}"))], |w| {
+                    write!(w, "{n}")
+                })
             })?;
         }
     }
@@ -259,18 +523,18 @@ fn write_inline_code<W: Write>(w: &mut W, content: &str) -> io::Result<()> {
 
 fn write_table<W: Write>(
     w: &mut W,
-    columns: usize,
-    items: &[TableItem],
+    refs: &RefIndex,
+    table: &TableLine,
     attrs: &AttrsMap<'_>,
 ) -> io::Result<()> {
-    let mut is_first_row = true;
+    let mut is_first_row = table.header;
 
-    let write_row = |w: &mut W, row: &[Vec<Term3>], cell_tag: &str| {
+    let write_row = |w: &mut W, row: &[Vec<Spanned<Term3>>], cell_tag: &str| {
         elem(w, "tr", [], |w| {
             for arg in row {
                 elem(w, cell_tag, [], |w| {
                     for term in arg {
-                        write_term(w, term)?;
+                        write_term(w, refs, term)?;
                     }
 
                     Ok(())
@@ -282,11 +546,15 @@ fn write_table<W: Write>(
     };
 
     elem(w, "table", attrs_to_iter(attrs), |w| {
-        for item in items {
+        if let Some(c) = &table.caption {
+            elem(w, "caption", [], |w| text(w, c))?;
+        }
+
+        for item in &table.items {
             match item {
                 TableItem::Row(row) => {
                     let cell_tag = if is_first_row { "th" } else { "td" };
-                    write_row(w, &row, cell_tag)?;
+                    write_row(w, row, cell_tag)?;
                     is_first_row = false;
                 }
                 // TableItem::Separator => write_row(w, &empty_row, "td")?,
@@ -294,7 +562,7 @@ fn write_table<W: Write>(
                     elem(
                         w,
                         "th",
-                        attrs_list_to_iter(&[("colspan", format!("{}", columns))]),
+                        attrs_list_to_iter(&[("colspan", format!("{}", table.columns))]),
                         |_| Ok(()),
                     )
                 })?,
@@ -307,7 +575,7 @@ fn write_table<W: Write>(
 
 fn dot_to_svg(input: &str, engine: &str) -> Result<String, String> {
     let mut child = Command::new("dot")
-        .args(&["-K", engine, "-T", "svg_inline"])
+        .args(["-K", engine, "-T", "svg_inline"])
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -335,3 +603,37 @@ fn dot_to_svg(input: &str, engine: &str) -> Result<String, String> {
         Err(format!("non-zero exit code; stderr output:\n{output}"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_hrefs() {
+        let cases = [
+            ("notes.acr", "notes.html"),
+            ("../projects/foo.acr#tasks", "../projects/foo.html#tasks"),
+            ("a.acr?x=1", "a.html?x=1"),
+            ("/abs/foo.acr", "/abs/foo.acr"),
+            ("https://example.com/foo.acr", "https://example.com/foo.acr"),
+            ("notes.acr.txt", "notes.acr.txt"),
+            ("#anchor.acr", "#anchor.acr"),
+        ];
+        for (target, href) in cases {
+            assert_eq!(link_href(target).as_deref(), Some(href), "{target:?}");
+        }
+        assert_eq!(link_href("javascript:alert(1)"), None);
+    }
+
+    #[test]
+    fn whole_documents_are_indexed_once() {
+        let (doc, _) = crate::parser::parse_recovering("@ref{nowhere}@fn{@ref{nowhere}}\n");
+        let options = HtmlOptions { katex_path: "" };
+        let mut html = HtmlWriter::start_document(Vec::new(), &doc, &options).unwrap();
+        for node in &doc.nodes {
+            html.write_node(node).unwrap();
+        }
+        html.write_footnotes(&doc.footnotes).unwrap();
+        assert_eq!(html.refs.dangling().len(), 2);
+    }
+}
@@ -12,6 +12,21 @@ pub fn elem<'a, W>(
 where
     W: io::Write,
 {
+    open_tag(writer, tag, attrs)?;
+    if !is_void_tag(tag) {
+        inside(writer)?;
+        close_tag(writer, tag)?;
+    }
+
+    Ok(())
+}
+
+/// Write the opening tag of an element (which is all there is to void elements).
+pub fn open_tag<'a>(
+    writer: &mut impl io::Write,
+    tag: &'a str,
+    attrs: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> io::Result<()> {
     write!(writer, "<{tag}")?;
     for (k, v) in attrs.into_iter() {
         write!(writer, " ")?;
@@ -20,15 +35,14 @@ where
         text(writer, v)?;
         write!(writer, "\"")?;
     }
-    if is_void_tag(tag) {
-        write!(writer, "/>")?;
-    } else {
-        write!(writer, ">")?;
-        inside(writer)?;
-        write!(writer, "</{tag}>")?;
+    match is_void_tag(tag) {
+        true => write!(writer, "/>"),
+        false => write!(writer, ">"),
     }
+}
 
-    Ok(())
+pub fn close_tag(writer: &mut impl io::Write, tag: &str) -> io::Result<()> {
+    write!(writer, "</{tag}>")
 }
 
 #[inline(always)]
@@ -54,21 +68,21 @@ pub fn text(w: &mut impl io::Write, text: &str) -> io::Result<()> {
 fn is_void_tag(tag: &str) -> bool {
     // FIXME: improve performance here (hash table or something ig)
 
-    match tag {
-        "area" => true,
-        "base" => true,
-        "br" => true,
-        "col" => true,
-        "embed" => true,
-        "hr" => true,
-        "img" => true,
-        "input" => true,
-        "link" => true,
-        "meta" => true,
-        "param" => true,
-        "source" => true,
-        "track" => true,
-        "wbr" => true,
-        _ => false,
-    }
+    matches!(
+        tag,
+        "area"
+            | "base"
+            | "br"
+            | "col"
+            | "embed"
+            | "hr"
+            | "img"
+            | "input"
+            | "link"
+            | "meta"
+            | "param"
+            | "source"
+            | "track"
+            | "wbr"
+    )
 }
@@ -1,5 +1,6 @@
 document.addEventListener("DOMContentLoaded", function() {
-    const macros = {};
+    const macros_elem = document.getElementById("acr-katex-macros");
+    const macros = macros_elem ? JSON.parse(macros_elem.textContent) : {};
     const opts = {
         throwOnError: false,
         macros: macros,
@@ -0,0 +1,28 @@
+//! Parser for acrylic (`.acr`) documents, along with its output backends.
+//!
+//! The usual entry point is [`parse`], which returns a [`Document`] tree that can be walked
+//! directly or rendered with one of the backends (e.g. [`write_html`]).
+//!
+//! ```
+//! use acrylic_parser::{Line, Term};
+//!
+//! let doc = acrylic_parser::parse("Hello, *world*!\n").unwrap();
+//! let Line::Text(line) = &doc.nodes[0].line else { panic!() };
+//! assert!(matches!(line.content[2].value, Term::Bold(_)));
+//! ```
+
+#![deny(unused_must_use)]
+
+pub mod acr;
+pub mod format;
+pub mod html;
+pub mod parser;
+
+pub use acr::write_acr;
+pub use format::{FormatStyle, format_acr};
+pub use html::{HtmlOptions, HtmlWriter, write_html};
+pub use parser::incremental::IncrementalDocument;
+pub use parser::stage3::{Document, Line, Node, Term};
+pub use parser::stream::StreamParser;
+pub use parser::visit::{Visit, VisitMut};
+pub use parser::{ErrorKind, ParseError, ReadError, parse, parse_recovering};
@@ -1,15 +1,14 @@
-#![allow(dead_code)]
 #![deny(unused_must_use)]
 
 mod cli;
-mod html;
-mod parser;
 
 use crate::cli::{CliArg, CliOption, CliParser};
-use crate::html::{write_html, HtmlOptions};
-use crate::parser::parse;
+use acrylic_parser::{
+    FormatStyle, HtmlOptions, HtmlWriter, ParseError, ReadError, StreamParser, format_acr, parse,
+    write_html,
+};
 use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
 
 // TODO: make tests for stage1 - conditions where each type of term parses
 //
@@ -21,6 +20,9 @@ pub type WriteFileGen = Box<dyn FnOnce() -> io::Result<Box<dyn Write>>>;
 pub struct Options {
     pub katex_path: String,
     pub backend: Backend,
+    pub file_name: String,
+    pub fmt_mode: FmtMode,
+    pub stream: bool,
     pub in_file_gen: ReadFileGen,
     pub out_file_gen: WriteFileGen,
 }
@@ -33,8 +35,23 @@ pub enum Backend {
     /// Rust debug print
     Debug,
 
-    /// Nothing (useful for validation)
+    /// Nothing, but report every error in the file (useful for validation)
     None,
+
+    /// The same file, formatted in the canonical layout
+    Fmt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FmtMode {
+    /// Write the formatted file to the output
+    Write,
+
+    /// Write nothing, but fail if the file isn't formatted
+    Check,
+
+    /// Write the formatted file back to where it was read from, if it changed
+    InPlace,
 }
 
 fn main() {
@@ -43,7 +60,7 @@ fn main() {
     match app(&args) {
         Ok(()) => {}
         Err(e) => {
-            if e.len() > 0 {
+            if !e.is_empty() {
                 eprintln!("error: {}", e);
             }
             std::process::exit(1);
@@ -54,38 +71,133 @@ fn main() {
 fn app(args: &[String]) -> Result<(), String> {
     let options = parse_options(args)?;
 
-    let file_contents = {
-        let mut s = String::new();
-        let mut f =
-            (options.in_file_gen)().map_err(|e| format!("failed to open input file: {:?}", e))?;
-        f.read_to_string(&mut s)
-            .map_err(|e| format!("failed to read from file: {:?}", e))?;
-        s
-    };
+    let mut input = (options.in_file_gen)()
+        .map(BufReader::new)
+        .map_err(|e| format!("failed to open input file: {:?}", e))?;
 
-    let result = parse(&file_contents)?;
+    match options.backend {
+        Backend::Html if !options.stream => {
+            let mut file_contents = String::new();
+            input
+                .read_to_string(&mut file_contents)
+                .map_err(|e| format!("failed to read from file: {:?}", e))?;
 
-    let mut file =
-        (options.out_file_gen)().map_err(|e| format!("failed to open output file: {:?}", e))?;
+            let result =
+                parse(&file_contents).map_err(|e| format!("{e}\n{}", e.excerpt(&file_contents)))?;
 
-    match options.backend {
-        Backend::Html => {
+            let mut file = (options.out_file_gen)()
+                .map_err(|e| format!("failed to open output file: {:?}", e))?;
             let html_options = HtmlOptions {
                 katex_path: &options.katex_path,
             };
             write_html(&mut file, &result, &html_options)
-                .map_err(|e| format!("failed to write to file: {:?}", e))?;
+                .map_err(|e| format!("failed to write HTML: {e}"))?;
+        }
+        Backend::Html => {
+            // the file is parsed and written one top-level node at a time, so big files don't
+            // have to fit in memory (on an error, the nodes before it have already been written,
+            // and refs to anchors further down show their ID instead of the anchor's text)
+            let mut parser = StreamParser::new(input).map_err(read_error_message)?;
+
+            let mut file = (options.out_file_gen)()
+                .map_err(|e| format!("failed to open output file: {:?}", e))?;
+            let html_options = HtmlOptions {
+                katex_path: &options.katex_path,
+            };
+            let write_error = |e: io::Error| format!("failed to write HTML: {e}");
+
+            let mut html = HtmlWriter::start(&mut file, parser.options(), &html_options)
+                .map_err(write_error)?;
+            while let Some(node) = parser.next_node().map_err(|e| match e {
+                ReadError::Parse(e) => parse_error_message(&parser, &e),
+                e => read_error_message(e),
+            })? {
+                html.write_node(&node).map_err(write_error)?;
+            }
+            html.write_footnotes(parser.footnotes())
+                .map_err(write_error)?;
+            html.finish().map_err(write_error)?;
         }
         Backend::Debug => {
-            write!(&mut file, "{result:#?}\n")
+            let mut file_contents = String::new();
+            input
+                .read_to_string(&mut file_contents)
+                .map_err(|e| format!("failed to read from file: {:?}", e))?;
+
+            let result =
+                parse(&file_contents).map_err(|e| format!("{e}\n{}", e.excerpt(&file_contents)))?;
+
+            let mut file = (options.out_file_gen)()
+                .map_err(|e| format!("failed to open output file: {:?}", e))?;
+            writeln!(&mut file, "{result:#?}")
                 .map_err(|e| format!("failed to write to file: {:?}", e))?;
         }
-        Backend::None => {}
+        Backend::Fmt => {
+            let mut file_contents = String::new();
+            input
+                .read_to_string(&mut file_contents)
+                .map_err(|e| format!("failed to read from file: {:?}", e))?;
+
+            let formatted = format_acr(&file_contents, &FormatStyle::default())
+                .map_err(|e| format!("{e}\n{}", e.excerpt(&file_contents)))?;
+
+            let changed = formatted != file_contents;
+            match options.fmt_mode {
+                FmtMode::Check if changed => {
+                    return Err(format!("{} is not formatted", options.file_name));
+                }
+                FmtMode::Check | FmtMode::InPlace if !changed => {}
+                _ => {
+                    let mut file = (options.out_file_gen)()
+                        .map_err(|e| format!("failed to open output file: {:?}", e))?;
+                    file.write_all(formatted.as_bytes())
+                        .map_err(|e| format!("failed to write to file: {:?}", e))?;
+                }
+            }
+        }
+        Backend::None => {
+            // validate the whole file, reporting every error instead of just the first one
+            let mut parser = StreamParser::recovering(input).map_err(read_error_message)?;
+            let mut count = 0;
+
+            loop {
+                let node = parser.next_node().map_err(read_error_message)?;
+
+                let mut errors = parser.take_errors();
+                errors.sort_by_key(|e| e.span.start);
+                for e in &errors {
+                    eprintln!("error: {}", parse_error_message(&parser, e));
+                }
+                count += errors.len();
+
+                if node.is_none() {
+                    break;
+                }
+            }
+
+            if count > 0 {
+                return Err(format!("found {count} error(s)"));
+            }
+        }
     }
 
     Ok(())
 }
 
+fn read_error_message(e: ReadError) -> String {
+    match e {
+        ReadError::Io(e) => format!("failed to read from file: {:?}", e),
+        ReadError::Parse(e) => e.to_string(),
+    }
+}
+
+fn parse_error_message<R: BufRead>(parser: &StreamParser<R>, e: &ParseError) -> String {
+    match parser.excerpt(e) {
+        Some(excerpt) => format!("{e}\n{excerpt}"),
+        None => e.to_string(),
+    }
+}
+
 fn parse_options(args: &[String]) -> Result<Options, String> {
     let mut p = CliParser::new(args[0].as_str());
     p.add_arg(CliArg {
@@ -102,7 +214,7 @@ fn parse_options(args: &[String]) -> Result<Options, String> {
     p.add_option(CliOption {
         name: "--backend".into(),
         short: "-b".into(),
-        help: "the output backend (options: html, debug, none; default: html)".into(),
+        help: "the output backend (options: html, debug, none, fmt; default: html)".into(),
         has_arg: true,
         value: None,
     });
@@ -114,42 +226,127 @@ fn parse_options(args: &[String]) -> Result<Options, String> {
         value: None,
     });
 
+    p.add_option(CliOption {
+        name: "--check".into(),
+        short: "-c".into(),
+        help: "with the fmt backend, fail if the file isn't formatted instead of writing it".into(),
+        has_arg: false,
+        value: None,
+    });
+    p.add_option(CliOption {
+        name: "--in-place".into(),
+        short: "-i".into(),
+        help: "with the fmt backend, write the formatted file back to FILE".into(),
+        has_arg: false,
+        value: None,
+    });
+
+    p.add_option(CliOption {
+        name: "--stream".into(),
+        short: "-s".into(),
+        help: "with the html backend, write each part as soon as it's read, for big files (refs to later anchors show their ID)".into(),
+        has_arg: false,
+        value: None,
+    });
+
     p.parse_args(&args[1..])?;
 
     let backend = match p.get_option("--backend").and_then(|x| x.value.as_deref()) {
         Some("html") | None => Backend::Html,
         Some("debug") => Backend::Debug,
         Some("none") => Backend::None,
+        Some("fmt") => Backend::Fmt,
         Some(x) => return Err(p.error_help(format!("Unknown backend {x:?}"))),
     };
 
     let katex_path = p
         .get_option("--katex-path")
         .and_then(|x| x.value.clone())
-        .unwrap_or_else(String::new);
+        .unwrap_or_default();
 
-    let in_file_gen: ReadFileGen = match p.get_arg("FILE").and_then(|x| x.value.as_deref()).unwrap()
-    {
+    let file_name = p.get_arg("FILE").and_then(|x| x.value.clone()).unwrap();
+
+    let is_given = |name: &str| p.get_option(name).is_some_and(CliOption::is_specified);
+    let fmt_mode = match (is_given("--check"), is_given("--in-place")) {
+        (false, false) => FmtMode::Write,
+        (true, false) => FmtMode::Check,
+        (false, true) => FmtMode::InPlace,
+        (true, true) => {
+            return Err(p.error_help("--check and --in-place can't be used together".into()));
+        }
+    };
+    if fmt_mode != FmtMode::Write && !matches!(backend, Backend::Fmt) {
+        return Err(p.error_help("--check and --in-place only work with the fmt backend".into()));
+    }
+    let stream = is_given("--stream");
+    if stream && !matches!(backend, Backend::Html) {
+        return Err(p.error_help("--stream only works with the html backend".into()));
+    }
+    if fmt_mode == FmtMode::InPlace && (file_name == "-" || is_given("--output")) {
+        return Err(p.error_help("--in-place needs an input file and no output file".into()));
+    }
+
+    let in_file_gen: ReadFileGen = match file_name.as_str() {
         "-" => Box::new(|| Ok(Box::new(io::stdin()))),
         path_ref => {
             let path = path_ref.to_owned();
-            Box::new(move || File::open(path).and_then(|x| Ok(Box::new(x) as Box<dyn Read>)))
+            Box::new(move || File::open(path).map(|x| Box::new(x) as Box<dyn Read>))
         }
     };
 
     let out_file_gen: WriteFileGen = match p.get_option("--output").and_then(|x| x.value.as_deref())
     {
+        _ if fmt_mode == FmtMode::InPlace => {
+            let path = file_name.clone();
+            Box::new(move || File::create(path).map(|x| Box::new(x) as Box<dyn Write>))
+        }
         Some("-") | None => Box::new(|| Ok(Box::new(io::stdout()))),
         Some(path_ref) => {
             let path = path_ref.to_owned();
-            Box::new(move || File::create(path).and_then(|x| Ok(Box::new(x) as Box<dyn Write>)))
+            Box::new(move || File::create(path).map(|x| Box::new(x) as Box<dyn Write>))
         }
     };
 
     Ok(Options {
         katex_path,
         backend,
+        file_name,
+        fmt_mode,
+        stream,
         in_file_gen,
         out_file_gen,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(input: &str, extra_args: &[&str]) -> String {
+        let dir = std::env::temp_dir().join(format!("acr-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (in_path, out_path) = (dir.join("in.acr"), dir.join("out.html"));
+        std::fs::write(&in_path, input).unwrap();
+
+        let mut args = vec!["acr", "-o", out_path.to_str().unwrap()];
+        args.extend(extra_args);
+        args.push(in_path.to_str().unwrap());
+        let args: Vec<String> = args.into_iter().map(String::from).collect();
+        app(&args).unwrap();
+
+        let output = std::fs::read_to_string(&out_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        output
+    }
+
+    #[test]
+    fn html_refs_to_later_anchors() {
+        let input = "see @ref{later-on}\n@h{Later *on*}\n";
+        let link = r##"<a href="#later-on">Later on</a>"##;
+        assert!(render(input, &[]).contains(link));
+
+        // when streaming, the heading isn't known yet
+        let link = r##"<a href="#later-on">later-on</a>"##;
+        assert!(render(input, &["--stream"]).contains(link));
+    }
+}
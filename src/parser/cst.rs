@@ -0,0 +1,1051 @@
+//! A lossless concrete syntax tree built over stage 1, for tools that rewrite the source.
+//!
+//! Stage 1 throws away what doesn't matter to the document: comments, the exact whitespace
+//! between terms, escape sequences and the hashes around raw arguments. The tree built here keeps
+//! all of it, as tokens holding the exact text they came from, so printing it (with
+//! [`Display`](std::fmt::Display)) gives back the source byte for byte.
+//!
+//! The tree is built by running stage 1 and splitting the source along the spans of its lines and
+//! terms, so the lexing rules live in stage 1 only. The other way around, the lines and terms of
+//! stage 1 can be read back off the tree (see [`SyntaxNode::lower`]), so the later stages can run
+//! on an edited tree without lexing it again.
+//!
+//! A tool can change a few tokens (e.g. rename a tag, or check a task) and print the tree back
+//! with everything else untouched.
+
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::parser::stage1::{self, LineLexer};
+use crate::parser::{
+    BulletType, Diagnostics, DocumentSt1, ErrorKind, FuncCall, Indent, KwArg, Line, ParseError,
+    Span, Spanned, StandardOptions, TaskFormat, TaskPrefix, TaskState, Term,
+};
+
+/// What a node or token of the tree stands for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntaxKind {
+    // nodes
+    /// The whole source.
+    Document,
+    /// The `%:key value` entries at the start, along with the blank lines after them.
+    Header,
+    HeaderEntry,
+    /// A line of the body, up to (and including) its line break.
+    Line,
+    Word,
+    Tag,
+    InlineCode,
+    InlineMath,
+    DisplayMath,
+    Bold,
+    Italics,
+    /// A function call (`@name{...}`).
+    Call,
+    /// A list (`@{...}`).
+    List,
+    /// The `[...]` keyword arguments of a call.
+    KwArgs,
+    KwArg,
+    /// An argument between `{}` or `()`.
+    Arg,
+    /// A raw argument (`#{...}#`).
+    RawArg,
+
+    // tokens
+    /// The UTF-8 byte order mark.
+    Bom,
+    Indent,
+    /// Whitespace that is a term of its own ([`Term::Space`]).
+    Space,
+    /// Whitespace that isn't a term (e.g. between keyword arguments).
+    Whitespace,
+    Newline,
+    /// A `%%` comment, up to the end of the line.
+    Comment,
+    Text,
+    /// A backslash and the character it escapes.
+    Escape,
+    /// The name of a function, keyword argument or header entry.
+    Name,
+    /// Fixed syntax, like the `@` of a call or the brackets around an argument.
+    Punct,
+    /// A bracket that is a term of its own ([`Term::MaybeDelim`]).
+    Delim,
+    Bullet,
+    TaskPrefix,
+    /// The contents of a line that failed to parse.
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxNode {
+    pub kind: SyntaxKind,
+    pub children: Vec<SyntaxElement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxToken {
+    pub kind: SyntaxKind,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+impl SyntaxNode {
+    pub fn new(kind: SyntaxKind, children: Vec<SyntaxElement>) -> Self {
+        Self { kind, children }
+    }
+
+    /// The first token of `kind` among the children of this node.
+    pub fn token(&self, kind: SyntaxKind) -> Option<&SyntaxToken> {
+        self.children.iter().find_map(|el| match el {
+            SyntaxElement::Token(t) if t.kind == kind => Some(t),
+            _ => None,
+        })
+    }
+
+    pub fn token_mut(&mut self, kind: SyntaxKind) -> Option<&mut SyntaxToken> {
+        self.children.iter_mut().find_map(|el| match el {
+            SyntaxElement::Token(t) if t.kind == kind => Some(t),
+            _ => None,
+        })
+    }
+
+    /// Call `f` on this node and every node inside it, parents before their children.
+    pub fn for_each_node(&self, f: &mut impl FnMut(&SyntaxNode)) {
+        f(self);
+        for el in &self.children {
+            if let SyntaxElement::Node(n) = el {
+                n.for_each_node(f);
+            }
+        }
+    }
+
+    pub fn for_each_node_mut(&mut self, f: &mut impl FnMut(&mut SyntaxNode)) {
+        f(self);
+        for el in &mut self.children {
+            if let SyntaxElement::Node(n) = el {
+                n.for_each_node_mut(f);
+            }
+        }
+    }
+
+    /// Read the stage 1 document off the tree, sending errors to `diag`.
+    ///
+    /// For a tree that wasn't edited into something stage 1 can't produce, this is the same as
+    /// [`stage1::parse_with`] on the printed tree. The lines and their terms come from the nodes
+    /// of the tree, but the header is still parsed from the printed tree (as the indent unit can
+    /// depend on the whole body), and so are the lines that failed to parse (as the tree doesn't
+    /// keep their errors).
+    pub fn lower(&self, diag: &mut Diagnostics) -> Result<DocumentSt1<'static>, ParseError> {
+        let text = self.to_string();
+        let (header, options, lexer) = stage1::parse_header(&text, diag)?;
+
+        let mut lowerer = Lowerer {
+            text: &text,
+            options: &options,
+            pos: Pos {
+                offset: lexer.offset(),
+                line: lexer.line(),
+                column: 1,
+            },
+        };
+        let mut lines = Vec::new();
+        for el in &self.children {
+            match el {
+                SyntaxElement::Node(n) if n.kind == SyntaxKind::Line => {
+                    lines.push(lowerer.line(n, diag)?);
+                }
+                // the header and the BOM come before where the lexer starts
+                _ => {}
+            }
+        }
+
+        Ok(DocumentSt1 {
+            header,
+            options,
+            lines,
+        })
+    }
+}
+
+impl fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.children.iter().try_for_each(|el| el.fmt(f))
+    }
+}
+
+impl fmt::Display for SyntaxToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl fmt::Display for SyntaxElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyntaxElement::Node(n) => n.fmt(f),
+            SyntaxElement::Token(t) => t.fmt(f),
+        }
+    }
+}
+
+/// Build the tree for `source`.
+///
+/// Errors never stop the parse: lines that fail to parse are kept as [`SyntaxKind::Error`]
+/// tokens, and the errors are returned along with the tree.
+pub fn parse(source: &str) -> (SyntaxNode, Vec<ParseError>) {
+    let mut diag = Diagnostics::recovering();
+    let builder = Builder { source };
+
+    let root = builder.document(&mut diag).unwrap_or_else(|e| {
+        // not possible when recovering, but just in case...
+        diag.report(e).ok();
+        let children = vec![builder.token(SyntaxKind::Error, 0, source.len())];
+        SyntaxNode::new(SyntaxKind::Document, children)
+    });
+
+    let mut errors = diag.into_errors();
+    errors.sort_by_key(|e| e.span.start);
+
+    (root, errors)
+}
+
+/// Builds the tree from the stage 1 lines, using their spans to find the text of each part.
+struct Builder<'s> {
+    source: &'s str,
+}
+
+impl Builder<'_> {
+    fn document(&self, diag: &mut Diagnostics) -> Result<SyntaxNode, ParseError> {
+        let mut children = Vec::new();
+
+        let bom = if self.source.starts_with('\u{feff}') {
+            '\u{feff}'.len_utf8()
+        } else {
+            0
+        };
+        if bom > 0 {
+            children.push(self.token(SyntaxKind::Bom, 0, bom));
+        }
+
+        let (_, options, mut lexer) = stage1::parse_header(self.source, diag)?;
+        if lexer.offset() > bom {
+            children.push(SyntaxElement::Node(self.header(bom, lexer.offset())));
+        }
+
+        loop {
+            let start = lexer.offset();
+            let Some(line) = lexer.next_line(&options, diag)? else {
+                break;
+            };
+            let end = lexer.offset();
+            let node = match self.line(&line, &options, start, end) {
+                Some(node) => node,
+                None => {
+                    diag.report(ParseError::new(
+                        ErrorKind::Internal,
+                        line.span,
+                        "failed to split the line along the terms of stage 1",
+                    ))?;
+                    // still kept as-is, so the tree prints back the same
+                    let children = vec![self.token(SyntaxKind::Error, start, end)];
+                    SyntaxNode::new(SyntaxKind::Line, children)
+                }
+            };
+            children.push(SyntaxElement::Node(node));
+        }
+
+        Ok(SyntaxNode::new(SyntaxKind::Document, children))
+    }
+
+    fn token(&self, kind: SyntaxKind, start: usize, end: usize) -> SyntaxElement {
+        SyntaxElement::Token(SyntaxToken {
+            kind,
+            text: self.source[start..end].to_owned(),
+        })
+    }
+
+    fn header(&self, start: usize, end: usize) -> SyntaxNode {
+        let mut children = Vec::new();
+        let mut entry: Option<Vec<SyntaxElement>> = None;
+        let flush = |children: &mut Vec<_>, entry: &mut Option<Vec<_>>| {
+            if let Some(entry) = entry.take() {
+                children.push(SyntaxElement::Node(SyntaxNode::new(
+                    SyntaxKind::HeaderEntry,
+                    entry,
+                )));
+            }
+        };
+
+        let mut pos = start;
+        while pos < end {
+            let content_end = pos
+                + self.source[pos..end]
+                    .find(['\r', '\n'])
+                    .unwrap_or(end - pos);
+            let line_end = content_end + newline_len(&self.source[content_end..end]);
+            let content = &self.source[pos..content_end];
+
+            if content.is_empty() {
+                flush(&mut children, &mut entry);
+                children.push(self.token(SyntaxKind::Newline, pos, line_end));
+            } else {
+                let mut tokens = Vec::new();
+                let mut p = pos;
+                if content.starts_with("%:") {
+                    flush(&mut children, &mut entry);
+                    tokens.push(self.token(SyntaxKind::Punct, p, p + 2));
+                    p += 2;
+                    let name_len = self.source[p..content_end]
+                        .find([' ', '\t'])
+                        .unwrap_or(content_end - p);
+                    tokens.push(self.token(SyntaxKind::Name, p, p + name_len));
+                    p += name_len;
+                }
+
+                let ws_len = whitespace_len(&self.source[p..content_end]);
+                if ws_len > 0 {
+                    tokens.push(self.token(SyntaxKind::Whitespace, p, p + ws_len));
+                    p += ws_len;
+                }
+                if p < content_end {
+                    tokens.push(self.token(SyntaxKind::Text, p, content_end));
+                }
+                tokens.push(self.token(SyntaxKind::Newline, content_end, line_end));
+
+                entry.get_or_insert_with(Vec::new).extend(tokens);
+            }
+
+            pos = line_end;
+        }
+
+        flush(&mut children, &mut entry);
+        SyntaxNode::new(SyntaxKind::Header, children)
+    }
+
+    /// Build a line of the body, which goes from `start` up to `end` (the start of the next one).
+    ///
+    /// This and the functions it calls fail if the text doesn't fit the spans of the terms (which
+    /// would be a bug, either here or in stage 1).
+    fn line(
+        &self,
+        line: &Line,
+        options: &StandardOptions,
+        start: usize,
+        end: usize,
+    ) -> Option<SyntaxNode> {
+        let mut children = Vec::new();
+
+        let indent_char = match options.indent {
+            Indent::Tab => '\t',
+            Indent::Space(_) => ' ',
+        };
+        let indent_len = self.source[start..line.span.end]
+            .find(|c| c != indent_char)
+            .unwrap_or(line.span.end - start);
+        if indent_len > 0 {
+            children.push(self.token(SyntaxKind::Indent, start, start + indent_len));
+        }
+
+        self.terms(
+            &mut children,
+            start + indent_len,
+            line.span.end,
+            &line.terms,
+        )?;
+        self.trivia(&mut children, line.span.end, end);
+
+        Some(SyntaxNode::new(SyntaxKind::Line, children))
+    }
+
+    /// Add `terms`, and the trivia around them, from `start` up to `end`.
+    fn terms(
+        &self,
+        out: &mut Vec<SyntaxElement>,
+        start: usize,
+        end: usize,
+        terms: &[Spanned<Term>],
+    ) -> Option<()> {
+        let mut pos = start;
+        for t in terms {
+            out.extend(self.trivia_and_term(pos, t)?);
+            pos = t.span.end;
+        }
+        (pos <= end).then(|| self.trivia(out, pos, end))
+    }
+
+    /// Build the trivia from `start` up to the term `t`, followed by the term.
+    fn trivia_and_term(&self, start: usize, t: &Spanned<Term>) -> Option<Vec<SyntaxElement>> {
+        if t.span.start < start {
+            return None;
+        }
+
+        let mut out = Vec::new();
+        self.trivia(&mut out, start, t.span.start);
+        out.push(self.term(t)?);
+        Some(out)
+    }
+
+    /// Add the text between terms: whitespace, line breaks, comments and anything else (as
+    /// punctuation).
+    fn trivia(&self, out: &mut Vec<SyntaxElement>, start: usize, end: usize) {
+        let mut pos = start;
+        while pos < end {
+            let rest = &self.source[pos..end];
+            let (kind, len) = if rest.starts_with("%%") {
+                (
+                    SyntaxKind::Comment,
+                    rest.find(['\r', '\n']).unwrap_or(rest.len()),
+                )
+            } else if newline_len(rest) > 0 {
+                (SyntaxKind::Newline, newline_len(rest))
+            } else if whitespace_len(rest) > 0 {
+                (SyntaxKind::Whitespace, whitespace_len(rest))
+            } else {
+                let first = rest.chars().next().map_or(1, char::len_utf8);
+                let len = rest[first..]
+                    .find([' ', '\t', '\r', '\n', '%'])
+                    .map_or(rest.len(), |n| first + n);
+                (SyntaxKind::Punct, len)
+            };
+            out.push(self.token(kind, pos, pos + len));
+            pos += len;
+        }
+    }
+
+    fn term(&self, t: &Spanned<Term>) -> Option<SyntaxElement> {
+        let Span { start, end, .. } = t.span;
+        let node = |kind, children| SyntaxElement::Node(SyntaxNode::new(kind, children));
+
+        let el = match &t.value {
+            Term::Space => self.token(SyntaxKind::Space, start, end),
+            Term::MaybeDelim(_) => self.token(SyntaxKind::Delim, start, end),
+            Term::BulletPrefix(_) => self.token(SyntaxKind::Bullet, start, end),
+            Term::TaskPrefix(_) => self.token(SyntaxKind::TaskPrefix, start, end),
+            Term::Error(_) => self.token(SyntaxKind::Error, start, end),
+            Term::Word(_) => node(
+                SyntaxKind::Word,
+                self.escaped(start, end, stage1::is::escapable_char),
+            ),
+            Term::Tag(_) => node(
+                SyntaxKind::Tag,
+                vec![
+                    self.token(SyntaxKind::Punct, start, start + 1),
+                    self.token(SyntaxKind::Text, start + 1, end),
+                ],
+            ),
+            Term::InlineCode(_) => {
+                let mut children = vec![self.token(SyntaxKind::Punct, start, start + 1)];
+                children.extend(self.escaped(start + 1, end - 1, |c| matches!(c, '`' | '\\')));
+                children.push(self.token(SyntaxKind::Punct, end - 1, end));
+                node(SyntaxKind::InlineCode, children)
+            }
+            Term::InlineMath(x) => node(SyntaxKind::InlineMath, self.math(start, end, 2, x)?),
+            Term::DisplayMath(x) => node(SyntaxKind::DisplayMath, self.math(start, end, 3, x)?),
+            Term::InlineBold(terms) | Term::InlineItalics(terms) => {
+                let mut children = vec![self.token(SyntaxKind::Punct, start, start + 1)];
+                self.terms(&mut children, start + 1, end - 1, terms)?;
+                children.push(self.token(SyntaxKind::Punct, end - 1, end));
+                let kind = match t.value {
+                    Term::InlineBold(_) => SyntaxKind::Bold,
+                    _ => SyntaxKind::Italics,
+                };
+                node(kind, children)
+            }
+            Term::FuncCall(fc) => node(
+                SyntaxKind::Call,
+                self.call(start, end, Some(&fc.name), &fc.kwargs, &fc.args)?,
+            ),
+            Term::List(args) => node(SyntaxKind::List, self.call(start, end, None, &[], args)?),
+        };
+        Some(el)
+    }
+
+    /// Split `start..end` in text and escape sequences (a backslash followed by a character for
+    /// which `escapable` holds).
+    fn escaped(&self, start: usize, end: usize, escapable: fn(char) -> bool) -> Vec<SyntaxElement> {
+        let mut out = Vec::new();
+        let mut text_start = start;
+        let mut chars = self.source[start..end].char_indices().peekable();
+
+        while let Some((i, c)) = chars.next() {
+            if c != '\\' {
+                continue;
+            }
+            match chars.next_if(|&(_, c)| escapable(c)) {
+                Some((j, c)) => {
+                    let (i, j) = (start + i, start + j + c.len_utf8());
+                    if text_start < i {
+                        out.push(self.token(SyntaxKind::Text, text_start, i));
+                    }
+                    out.push(self.token(SyntaxKind::Escape, i, j));
+                    text_start = j;
+                }
+                // some other character, which is kept as-is along with the backslash
+                None => _ = chars.next(),
+            }
+        }
+
+        if text_start < end {
+            out.push(self.token(SyntaxKind::Text, text_start, end));
+        }
+        out
+    }
+
+    /// Split math (`${...}`, `$:...`, or the same with `$$`) in its opening, its `content` (which
+    /// stage 1 takes from the source with its line endings as `\n`) and its closing bracket (if it
+    /// has one).
+    fn math(
+        &self,
+        start: usize,
+        end: usize,
+        open_len: usize,
+        content: &str,
+    ) -> Option<Vec<SyntaxElement>> {
+        let content_start = start + open_len;
+
+        // the math ends at the bracket that closes the opening one, as `$:` math can too
+        let mut depth = 1_usize;
+        let mut chars = self.source.get(content_start..end)?.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => depth += 1,
+                '}' => depth = depth.checked_sub(1)?,
+                '\\' => _ = chars.next(),
+                _ => {}
+            }
+        }
+        let content_end = if depth == 0 { end - 1 } else { end };
+
+        if normalize_newlines(&self.source[content_start..content_end]) != content {
+            return None;
+        }
+
+        let mut out = vec![self.token(SyntaxKind::Punct, start, content_start)];
+        if content_start < content_end {
+            out.push(self.token(SyntaxKind::Text, content_start, content_end));
+        }
+        if content_end < end {
+            out.push(self.token(SyntaxKind::Punct, content_end, end));
+        }
+        Some(out)
+    }
+
+    fn call(
+        &self,
+        start: usize,
+        end: usize,
+        name: Option<&str>,
+        kwargs: &[Spanned<KwArg>],
+        args: &[Vec<Spanned<Term>>],
+    ) -> Option<Vec<SyntaxElement>> {
+        let mut out = vec![self.token(SyntaxKind::Punct, start, start + 1)];
+        let mut pos = start + 1;
+
+        if let Some(name) = name {
+            out.push(self.token(SyntaxKind::Name, pos, pos + name.len()));
+            pos += name.len();
+        }
+
+        if !kwargs.is_empty() {
+            let mut children = vec![self.token(SyntaxKind::Punct, pos, pos + 1)];
+            pos += 1;
+            for kw in kwargs {
+                if kw.span.start < pos {
+                    return None;
+                }
+                self.trivia(&mut children, pos, kw.span.start);
+                children.push(self.kwarg(kw)?);
+                pos = kw.span.end;
+            }
+            let close = pos + self.source[pos..end].find(']')?;
+            self.trivia(&mut children, pos, close);
+            children.push(self.token(SyntaxKind::Punct, close, close + 1));
+            pos = close + 1;
+            out.push(SyntaxElement::Node(SyntaxNode::new(
+                SyntaxKind::KwArgs,
+                children,
+            )));
+        }
+
+        for arg in args {
+            let (node, arg_end) = self.arg(pos, arg)?;
+            out.push(node);
+            pos = arg_end;
+        }
+
+        (pos == end).then_some(out)
+    }
+
+    fn kwarg(&self, kw: &Spanned<KwArg>) -> Option<SyntaxElement> {
+        let Span { start, end, .. } = kw.span;
+        let name_end = start + kw.name.len();
+
+        let mut children = vec![
+            self.token(SyntaxKind::Name, start, name_end),
+            self.token(SyntaxKind::Punct, name_end, name_end + 1),
+        ];
+        let value = name_end + 1;
+        // a bare value can start with `#` too, but its word starts right away
+        let bare = kw.arg.first().is_some_and(|t| t.span.start == value);
+        if !bare {
+            children.push(self.arg(value, &kw.arg)?.0);
+        } else {
+            // a bare value, which is taken as-is (without escapes)
+            let text = vec![self.token(SyntaxKind::Text, value, end)];
+            children.push(SyntaxElement::Node(SyntaxNode::new(SyntaxKind::Word, text)));
+        }
+
+        Some(SyntaxElement::Node(SyntaxNode::new(
+            SyntaxKind::KwArg,
+            children,
+        )))
+    }
+
+    /// Build the argument starting at `start`, returning it along with where it ends.
+    fn arg(&self, start: usize, terms: &[Spanned<Term>]) -> Option<(SyntaxElement, usize)> {
+        let rest = &self.source[start..];
+        let hashes = rest.len() - rest.trim_start_matches('#').len();
+
+        if hashes > 0 {
+            // the contents are a single word
+            let content = terms.first()?.span;
+            let end = content.end + 1 + hashes;
+            let closing = self.source.get(content.end..end)?;
+            if !closing.starts_with('}') || closing[1..].len() != hashes {
+                return None;
+            }
+            let mut children = vec![self.token(SyntaxKind::Punct, start, content.start)];
+            if content.start < content.end {
+                children.push(self.token(SyntaxKind::Text, content.start, content.end));
+            }
+            children.push(self.token(SyntaxKind::Punct, content.end, end));
+            let node = SyntaxNode::new(SyntaxKind::RawArg, children);
+            return Some((SyntaxElement::Node(node), end));
+        }
+
+        let close_char = match rest.chars().next() {
+            Some('(') => ')',
+            _ => '}',
+        };
+
+        let mut children = vec![self.token(SyntaxKind::Punct, start, start + 1)];
+        let mut pos = start + 1;
+        for t in terms {
+            children.extend(self.trivia_and_term(pos, t)?);
+            pos = t.span.end;
+        }
+
+        // only line breaks and comments can come before the closing bracket
+        let mut close = pos;
+        loop {
+            let rest = &self.source[close..];
+            if rest.starts_with("%%") {
+                close += rest.find(['\r', '\n']).unwrap_or(rest.len());
+            } else if newline_len(rest) > 0 {
+                close += newline_len(rest);
+            } else {
+                break;
+            }
+        }
+        if !self.source[close..].starts_with(close_char) {
+            return None;
+        }
+
+        self.trivia(&mut children, pos, close);
+        children.push(self.token(SyntaxKind::Punct, close, close + 1));
+
+        Some((
+            SyntaxElement::Node(SyntaxNode::new(SyntaxKind::Arg, children)),
+            close + 1,
+        ))
+    }
+}
+
+/// A position in the printed tree.
+#[derive(Debug, Clone, Copy)]
+struct Pos {
+    offset: usize,
+    line: u32,
+    column: u32,
+}
+
+/// Reads the stage 1 lines off the tree, keeping track of where each part is in the printed tree
+/// to give them their spans.
+struct Lowerer<'t> {
+    text: &'t str,
+    options: &'t StandardOptions,
+    pos: Pos,
+}
+
+impl Lowerer<'_> {
+    /// Step over `text`, counting lines and columns the way stage 1 does.
+    fn skip(&mut self, text: &str) {
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\r' {
+                chars.next_if_eq(&'\n');
+            }
+            if matches!(c, '\r' | '\n') {
+                self.pos.line += 1;
+                self.pos.column = 1;
+            } else {
+                self.pos.column += 1;
+            }
+        }
+        self.pos.offset += text.len();
+    }
+
+    fn skip_element(&mut self, el: &SyntaxElement) {
+        match el {
+            SyntaxElement::Token(t) => self.skip(&t.text),
+            SyntaxElement::Node(n) => self.skip_node(n),
+        }
+    }
+
+    fn skip_node(&mut self, node: &SyntaxNode) {
+        for el in &node.children {
+            self.skip_element(el);
+        }
+    }
+
+    fn span_since(&self, start: Pos) -> Span {
+        Span {
+            start: start.offset,
+            end: self.pos.offset,
+            line: start.line,
+            column: start.column,
+        }
+    }
+
+    fn line(
+        &mut self,
+        node: &SyntaxNode,
+        diag: &mut Diagnostics,
+    ) -> Result<Line<'static>, ParseError> {
+        let start = self.pos;
+
+        if node.token(SyntaxKind::Error).is_some() {
+            let mut lexer = LineLexer::at(self.text, start.offset, start.line);
+            let line = lexer.next_line(self.options, diag)?;
+            self.skip_node(node);
+            return line.map(Line::into_owned).ok_or_else(|| {
+                ParseError::new(
+                    ErrorKind::Internal,
+                    self.span_since(start),
+                    "a line of the tree is missing from the printed tree",
+                )
+            });
+        }
+
+        let mut indent = 0;
+        let mut terms = Vec::new();
+        let mut end = None;
+        for el in &node.children {
+            match el {
+                SyntaxElement::Token(t) if t.kind == SyntaxKind::Indent => {
+                    indent = match self.options.indent {
+                        Indent::Tab => t.text.chars().count(),
+                        Indent::Space(n) => t.text.chars().count() / n,
+                    };
+                    self.skip(&t.text);
+                }
+                SyntaxElement::Token(t) if t.kind == SyntaxKind::Newline => {
+                    end.get_or_insert(self.pos);
+                    self.skip(&t.text);
+                }
+                el => terms.extend(self.term(el)),
+            }
+        }
+
+        let end = end.unwrap_or(self.pos);
+        Ok(Line {
+            indent,
+            terms,
+            span: Span {
+                end: end.offset,
+                ..self.span_since(start)
+            },
+        })
+    }
+
+    /// The terms among `children`, skipping the trivia between them.
+    fn terms(&mut self, children: &[SyntaxElement]) -> Vec<Spanned<Term<'static>>> {
+        children.iter().filter_map(|c| self.term(c)).collect()
+    }
+
+    /// Read the term `el` stands for, or step over it if it's trivia.
+    fn term(&mut self, el: &SyntaxElement) -> Option<Spanned<Term<'static>>> {
+        let start = self.pos;
+        let t = match el {
+            SyntaxElement::Token(t) => t,
+            SyntaxElement::Node(n) => return self.node_term(n),
+        };
+
+        self.skip(&t.text);
+        let term = match t.kind {
+            SyntaxKind::Space => Term::Space,
+            SyntaxKind::Delim => Term::MaybeDelim(t.text.chars().next()?),
+            SyntaxKind::Bullet => Term::BulletPrefix(match &*t.text {
+                "*" => BulletType::Star,
+                _ => BulletType::Dash,
+            }),
+            SyntaxKind::TaskPrefix => Term::TaskPrefix(task_prefix(&t.text)),
+            _ => return None,
+        };
+        Some(Spanned::new(term, self.span_since(start)))
+    }
+
+    fn node_term(&mut self, node: &SyntaxNode) -> Option<Spanned<Term<'static>>> {
+        let start = self.pos;
+        let term = match node.kind {
+            SyntaxKind::Word => Term::Word(self.text_of(node)),
+            SyntaxKind::Tag => Term::Tag(self.text_of(node)),
+            SyntaxKind::InlineCode => Term::InlineCode(self.text_of(node)),
+            SyntaxKind::InlineMath => Term::InlineMath(self.text_of(node)),
+            SyntaxKind::DisplayMath => Term::DisplayMath(self.text_of(node)),
+            SyntaxKind::Bold => Term::InlineBold(self.terms(&node.children)),
+            SyntaxKind::Italics => Term::InlineItalics(self.terms(&node.children)),
+            SyntaxKind::Call | SyntaxKind::List => self.call(node),
+            _ => {
+                self.skip_node(node);
+                return None;
+            }
+        };
+        Some(Spanned::new(term, self.span_since(start)))
+    }
+
+    /// The text of a word-like node, without its punctuation and with its escapes resolved.
+    fn text_of(&mut self, node: &SyntaxNode) -> Cow<'static, str> {
+        let mut text = String::new();
+        for el in &node.children {
+            if let SyntaxElement::Token(t) = el {
+                match t.kind {
+                    SyntaxKind::Text => text.push_str(&normalize_newlines(&t.text)),
+                    SyntaxKind::Escape => text.push_str(&t.text[1..]),
+                    _ => {}
+                }
+            }
+            self.skip_element(el);
+        }
+        Cow::Owned(text)
+    }
+
+    fn call(&mut self, node: &SyntaxNode) -> Term<'static> {
+        let mut name = None;
+        let mut kwargs = Vec::new();
+        let mut args = Vec::new();
+        for el in &node.children {
+            match el {
+                SyntaxElement::Token(t) if t.kind == SyntaxKind::Name => {
+                    name = Some(Cow::Owned(t.text.clone()));
+                    self.skip(&t.text);
+                }
+                SyntaxElement::Node(n) if n.kind == SyntaxKind::KwArgs => {
+                    for el in &n.children {
+                        match el {
+                            SyntaxElement::Node(kw) if kw.kind == SyntaxKind::KwArg => {
+                                kwargs.push(self.kwarg(kw));
+                            }
+                            el => self.skip_element(el),
+                        }
+                    }
+                }
+                SyntaxElement::Node(n) => args.push(self.arg(n)),
+                el => self.skip_element(el),
+            }
+        }
+
+        match name {
+            Some(name) => Term::FuncCall(FuncCall { name, args, kwargs }),
+            None => Term::List(args),
+        }
+    }
+
+    fn kwarg(&mut self, node: &SyntaxNode) -> Spanned<KwArg<'static>> {
+        let start = self.pos;
+        let mut name = Cow::Borrowed("");
+        let mut arg = Vec::new();
+        for el in &node.children {
+            match el {
+                SyntaxElement::Token(t) if t.kind == SyntaxKind::Name => {
+                    name = Cow::Owned(t.text.clone());
+                    self.skip(&t.text);
+                }
+                SyntaxElement::Node(n) => arg = self.arg(n),
+                el => self.skip_element(el),
+            }
+        }
+        Spanned::new(KwArg { name, arg }, self.span_since(start))
+    }
+
+    /// The terms of an argument, which can also be a raw argument or the bare value of a keyword
+    /// argument (each a single word).
+    fn arg(&mut self, node: &SyntaxNode) -> Vec<Spanned<Term<'static>>> {
+        match node.kind {
+            SyntaxKind::Arg => self.terms(&node.children),
+            SyntaxKind::RawArg => {
+                let Some((open, rest)) = node.children.split_first() else {
+                    return Vec::new();
+                };
+                self.skip_element(open);
+                let start = self.pos;
+                let mut text = String::new();
+                let mut end = start;
+                for el in rest {
+                    self.skip_element(el);
+                    match el {
+                        SyntaxElement::Token(t) if t.kind == SyntaxKind::Text => {
+                            text = normalize_newlines(&t.text);
+                            end = self.pos;
+                        }
+                        _ => {}
+                    }
+                }
+                let span = Span {
+                    end: end.offset,
+                    ..self.span_since(start)
+                };
+                vec![Spanned::new(Term::Word(Cow::Owned(text)), span)]
+            }
+            _ => self.node_term(node).into_iter().collect(),
+        }
+    }
+}
+
+/// Turn `\r\n` and lone `\r`s into `\n`, as stage 1 does in the text it copies.
+fn normalize_newlines(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+fn task_prefix(text: &str) -> TaskPrefix {
+    let format = match text.starts_with('[') {
+        true => TaskFormat::Square,
+        false => TaskFormat::Paren,
+    };
+    let state = match text.chars().nth(1) {
+        Some('x' | 'X') => TaskState::Done,
+        Some('-') => TaskState::Cancelled,
+        _ => TaskState::Todo,
+    };
+    TaskPrefix { state, format }
+}
+
+fn newline_len(s: &str) -> usize {
+    if s.starts_with("\r\n") {
+        2
+    } else if s.starts_with(['\r', '\n']) {
+        1
+    } else {
+        0
+    }
+}
+
+fn whitespace_len(s: &str) -> usize {
+    s.len() - s.trim_start_matches([' ', '\t']).len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCES: &[&str] = &[
+        include_str!("../../examples/all.acr"),
+        "",
+        "\u{feff}%:title Foo\r\n%:description a\r\n  b\r\n\r\nfoo %% comment\r\n",
+        "%:indent 4\n\n- (x) done  \n    * child %tag\n\n\n\tbad\n",
+        "@code[lang=#rust x={a b} y=#{}#]##{ raw }#\n}##\n@{a}(b) @f{ %% c\n x }\n",
+        "a\\*b `c\\`d\\e` $: x{y}\n$${ a\r\nb } *bold _it_ x* \\q\n",
+        "@foo{\n",
+        "${a\r\nb}\n${\r} $: {a} $: b}\r\n$$: c\\}\n",
+    ];
+
+    #[test]
+    fn prints_back_the_source() {
+        for source in SOURCES {
+            let (tree, _) = parse(source);
+            assert_eq!(tree.to_string(), *source);
+        }
+    }
+
+    #[test]
+    fn same_errors_as_stage1() {
+        // so no line failed to be split along its terms
+        for source in SOURCES {
+            let (_, errors) = parse(source);
+
+            let mut diag = Diagnostics::recovering();
+            stage1::parse_with(source, &mut diag).unwrap();
+            let mut expected = diag.into_errors();
+            expected.sort_by_key(|e| e.span.start);
+            assert_eq!(errors, expected, "{source:?}");
+        }
+    }
+
+    #[test]
+    fn mismatched_spans() {
+        let doc = stage1::parse("@f{a} `b` ${c}\n").unwrap();
+        let options = StandardOptions::default();
+        for other in ["@f{a) `b` ${c}\n", "@f{a} `b` $${c\n"] {
+            let builder = Builder { source: other };
+            assert_eq!(builder.line(&doc.lines[0], &options, 0, other.len()), None);
+        }
+    }
+
+    #[test]
+    fn edits_keep_other_bytes() {
+        let source = "( ) buy milk %errand %% today\n  %errand again\n";
+        let (mut tree, _) = parse(source);
+
+        tree.for_each_node_mut(&mut |node| match node.kind {
+            SyntaxKind::Line => {
+                if let Some(t) = node.token_mut(SyntaxKind::TaskPrefix) {
+                    t.text = "(x)".to_owned();
+                }
+            }
+            SyntaxKind::Tag => {
+                let t = node.token_mut(SyntaxKind::Text).unwrap();
+                if t.text == "errand" {
+                    t.text = "shopping".to_owned();
+                }
+            }
+            _ => {}
+        });
+
+        let printed = tree.to_string();
+        assert_eq!(
+            printed,
+            "(x) buy milk %shopping %% today\n  %shopping again\n"
+        );
+
+        let doc = tree.lower(&mut Diagnostics::strict()).unwrap();
+        assert_eq!(doc.lines, stage1::parse(&printed).unwrap().lines);
+    }
+
+    #[test]
+    fn lowers_to_stage1() {
+        for source in SOURCES {
+            let (tree, _) = parse(source);
+            let mut diag = Diagnostics::recovering();
+            let lowered = tree.lower(&mut diag).unwrap();
+
+            let mut expected_diag = Diagnostics::recovering();
+            let expected = stage1::parse_with(source, &mut expected_diag).unwrap();
+            // (with the spans of the terms, which comparing them leaves out)
+            assert_eq!(
+                format!("{:?}", lowered.lines),
+                format!("{:?}", expected.lines),
+                "{source:?}"
+            );
+            assert_eq!(lowered.header, expected.header);
+            assert_eq!(lowered.options, expected.options);
+            assert_eq!(diag.into_errors(), expected_diag.into_errors());
+        }
+    }
+}
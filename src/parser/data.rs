@@ -1,27 +1,101 @@
+use crate::parser::ParseError;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
 
+/// A term, with text borrowed from the source whenever possible.
+///
+/// Text only gets copied when it isn't a verbatim slice of the source (e.g. when it had escaped
+/// characters or CRLF line endings).
 #[derive(Debug, Clone, PartialEq)]
-pub enum Term {
+pub enum Term<'a> {
     /// Whitespace
     Space,
 
     /// A part of a word that does not have the potential to be a another kind of term.
-    Word(String), // TODO: rename to WordPart (as there can be adjacent ones)
+    Word(Cow<'a, str>), // TODO: rename to WordPart (as there can be adjacent ones)
 
     /// A word part that might be treated as a delimeter, needed for `FuncCall` and `List`.
     MaybeDelim(char),
 
-    Tag(String),
-    InlineMath(String),
-    DisplayMath(String),
-    InlineCode(String),
-    InlineBold(String),
-    InlineItalics(String),
-    FuncCall(FuncCall),
-    List(Vec<Vec<Term>>),
+    Tag(Cow<'a, str>),
+    InlineMath(Cow<'a, str>),
+    DisplayMath(Cow<'a, str>),
+    InlineCode(Cow<'a, str>),
+    InlineBold(Vec<Spanned<Term<'a>>>),
+    InlineItalics(Vec<Spanned<Term<'a>>>),
+    FuncCall(FuncCall<'a>),
+    List(Vec<Vec<Spanned<Term<'a>>>>),
 
     BulletPrefix(BulletType),
     TaskPrefix(TaskPrefix),
+
+    /// A line that failed to parse (only produced when recovering from errors).
+    Error(ParseError),
+}
+
+impl Term<'_> {
+    /// A short description of the term, for error messages.
+    pub fn describe(&self) -> String {
+        match self {
+            Term::Space => "a space".to_owned(),
+            Term::Word(x) => format!("the word {x:?}"),
+            Term::MaybeDelim(c) => format!("{c:?}"),
+            Term::Tag(x) => format!("the tag %{x}"),
+            Term::InlineMath(_) => "inline math".to_owned(),
+            Term::DisplayMath(_) => "display math".to_owned(),
+            Term::InlineCode(_) => "inline code".to_owned(),
+            Term::InlineBold(_) => "bold text".to_owned(),
+            Term::InlineItalics(_) => "italic text".to_owned(),
+            Term::FuncCall(fc) => format!("a call to `@{}`", fc.name),
+            Term::List(_) => "a list".to_owned(),
+            Term::BulletPrefix(_) => "a bullet".to_owned(),
+            Term::TaskPrefix(_) => "a task prefix".to_owned(),
+            Term::Error(_) => "an invalid line".to_owned(),
+        }
+    }
+
+    /// Copy all borrowed text, so the term doesn't depend on the source anymore.
+    pub fn into_owned(self) -> Term<'static> {
+        match self {
+            Term::Space => Term::Space,
+            Term::Word(x) => Term::Word(own(x)),
+            Term::MaybeDelim(c) => Term::MaybeDelim(c),
+            Term::Tag(x) => Term::Tag(own(x)),
+            Term::InlineMath(x) => Term::InlineMath(own(x)),
+            Term::DisplayMath(x) => Term::DisplayMath(own(x)),
+            Term::InlineCode(x) => Term::InlineCode(own(x)),
+            Term::InlineBold(x) => Term::InlineBold(own_terms(x)),
+            Term::InlineItalics(x) => Term::InlineItalics(own_terms(x)),
+            Term::FuncCall(fc) => Term::FuncCall(FuncCall {
+                name: own(fc.name),
+                args: fc.args.into_iter().map(own_terms).collect(),
+                kwargs: fc
+                    .kwargs
+                    .into_iter()
+                    .map(|kw| {
+                        kw.map(|kw| KwArg {
+                            name: own(kw.name),
+                            arg: own_terms(kw.arg),
+                        })
+                    })
+                    .collect(),
+            }),
+            Term::List(x) => Term::List(x.into_iter().map(own_terms).collect()),
+            Term::BulletPrefix(x) => Term::BulletPrefix(x),
+            Term::TaskPrefix(x) => Term::TaskPrefix(x),
+            Term::Error(e) => Term::Error(e),
+        }
+    }
+}
+
+fn own(s: Cow<'_, str>) -> Cow<'static, str> {
+    Cow::Owned(s.into_owned())
+}
+
+fn own_terms(terms: Vec<Spanned<Term<'_>>>) -> Vec<Spanned<Term<'static>>> {
+    terms.into_iter().map(|t| t.map(Term::into_owned)).collect()
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -50,9 +124,17 @@ pub enum TaskFormat {
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct FuncCall {
-    pub name: String,
-    pub args: Vec<Vec<Term>>,
+pub struct FuncCall<'a> {
+    pub name: Cow<'a, str>,
+    pub args: Vec<Vec<Spanned<Term<'a>>>>,
+    pub kwargs: Vec<Spanned<KwArg<'a>>>,
+}
+
+/// A keyword argument (`key=value`), from the `@name[...]` part of a function call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KwArg<'a> {
+    pub name: Cow<'a, str>,
+    pub arg: Vec<Spanned<Term<'a>>>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -61,36 +143,190 @@ pub enum Indent {
     Space(usize),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndentOrigin {
+    /// Given by the `%:indent` header entry.
+    Header,
+    /// Inferred from the leading whitespace of the body.
+    Inferred,
+    /// Nothing to infer from (no indented lines), so the default was used.
+    Default,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct StandardOptions {
     pub indent: Indent,
+    /// Where `indent` came from.
+    pub indent_origin: IndentOrigin,
     pub tags: Vec<String>,
     pub title: String,
+    pub author: Option<String>,
+    pub date: Option<Date>,
+    /// The language of the document, as a BCP 47 tag (e.g. `en`, `pt-BR`).
+    pub lang: Option<String>,
+    pub description: Option<String>,
+    /// KaTeX macros, as `(name, expansion)` pairs (e.g. `("\\RR", "\\mathbb{R}")`).
+    pub katex_macros: Vec<(String, String)>,
+}
+
+impl Default for StandardOptions {
+    fn default() -> Self {
+        Self {
+            indent: Indent::Space(2),
+            indent_origin: IndentOrigin::Default,
+            tags: Vec::new(),
+            title: String::new(),
+            author: None,
+            date: None,
+            lang: None,
+            description: None,
+            katex_macros: Vec::new(),
+        }
+    }
+}
+
+/// A calendar date, written as `YYYY-MM-DD`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl Date {
+    /// Parse a `YYYY-MM-DD` date, checking that it exists.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut it = s.split('-');
+        let mut field = |len: usize| {
+            it.next()
+                .filter(|x| x.len() == len && x.bytes().all(|c| c.is_ascii_digit()))
+                .and_then(|x| x.parse::<u16>().ok())
+        };
+
+        let year = field(4)?;
+        let month = field(2)?;
+        let day = field(2)?;
+        if it.next().is_some() {
+            return None;
+        }
+
+        let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let month_len = match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if leap => 29,
+            2 => 28,
+            _ => return None,
+        };
+        if !(1..=month_len).contains(&day) {
+            return None;
+        }
+
+        Some(Self {
+            year,
+            month: month as u8,
+            day: day as u8,
+        })
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// A region of the source text.
+///
+/// `start` and `end` are byte offsets (`end` is exclusive), while `line` and `column` are 1-based
+/// and point to `start`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Span {
+    /// Make a span that goes from the start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..self
+        }
+    }
+}
+
+/// A value along with the region of the source it came from.
+///
+/// The span is left out of comparisons, so two values parsed from different places in the source
+/// still compare equal.
+#[derive(Debug, Clone)]
+pub struct Spanned<T> {
+    pub value: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(value: T, span: Span) -> Self {
+        Self { value, span }
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Spanned<U> {
+        Spanned::new(f(self.value), self.span)
+    }
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: PartialEq> PartialEq for Spanned<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct Line {
+pub struct Line<'a> {
     pub indent: usize,
-    pub terms: Vec<Term>,
+    pub terms: Vec<Spanned<Term<'a>>>,
+    pub span: Span,
+}
+
+impl Line<'_> {
+    pub fn into_owned(self) -> Line<'static> {
+        Line {
+            indent: self.indent,
+            terms: own_terms(self.terms),
+            span: self.span,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
-pub struct DocumentSt1 {
+pub struct DocumentSt1<'a> {
     pub header: HashMap<String, String>,
     pub options: StandardOptions,
-    pub lines: Vec<Line>,
+    pub lines: Vec<Line<'a>>,
 }
 
 #[derive(Debug, Clone)]
-pub struct DocumentSt2 {
+pub struct DocumentSt2<'a> {
     pub header: HashMap<String, String>,
     pub options: StandardOptions,
-    pub nodes: Vec<Node>,
+    pub nodes: Vec<Node<'a>>,
 }
 
 #[derive(Debug, Clone)]
-pub struct Node {
-    pub contents: Vec<Term>,
-    pub children: Vec<Node>,
+pub struct Node<'a> {
+    pub contents: Vec<Spanned<Term<'a>>>,
+    pub children: Vec<Node<'a>>,
     pub bottom_spacing: bool,
+    pub span: Span,
 }
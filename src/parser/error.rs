@@ -0,0 +1,204 @@
+//! Error type shared by all the parsing stages.
+
+use crate::parser::Span;
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind: ErrorKind,
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A header entry with an invalid value (e.g. `%:indent foo`).
+    BadHeaderValue,
+
+    /// Leading whitespace that doesn't match the indent unit.
+    BadIndent,
+
+    /// Leading whitespace with both tabs and spaces (e.g. a tab in a space-indented document).
+    MixedIndent,
+
+    /// An indented line before any non-indented line.
+    OrphanIndent,
+
+    /// A line indented more than one level deeper than the previous one.
+    IndentLeap,
+
+    /// A delimited term (inline code, math, function argument...) that was never closed.
+    Unterminated,
+
+    /// Curly brackets that don't match.
+    UnbalancedBrackets,
+
+    /// A backslash followed by a character that can't be escaped.
+    BadEscape,
+
+    /// Input left on the line that couldn't be parsed as any term.
+    TrailingInput,
+
+    /// Function arguments nested too deep.
+    TooDeep,
+
+    /// A term that was expected to be alone in its line, but wasn't.
+    NotAlone,
+
+    /// A term that isn't allowed where it was found.
+    UnexpectedTerm,
+
+    /// A call to a function that doesn't exist.
+    UnknownFunction,
+
+    /// A block function (e.g. `@code`) used in the middle of a line.
+    MisplacedFunction,
+
+    /// A function call with the wrong amount or type of arguments.
+    BadArguments,
+
+    /// A table with rows of different sizes.
+    TableShape,
+
+    /// A `@ref` to an anchor or heading that doesn't exist.
+    DanglingRef,
+
+    /// A heading with the same explicit ID as an earlier heading or anchor, or an anchor with the
+    /// same ID as an earlier one.
+    DuplicateId,
+
+    /// Two parts of the parser that disagree about the source, which is a bug in the parser.
+    Internal,
+}
+
+impl ParseError {
+    pub fn new(kind: ErrorKind, span: Span, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            span,
+            message: message.into(),
+        }
+    }
+
+    /// Show the line where the error happened, with a marker pointing to the error column.
+    ///
+    /// `source` must be the same string that was parsed.
+    pub fn excerpt(&self, source: &str) -> String {
+        let mut start = self.span.start.min(source.len());
+        while !source.is_char_boundary(start) {
+            start -= 1;
+        }
+
+        let line_start = match source[..start].rfind('\n') {
+            Some(i) => i + 1,
+            // skip the BOM, like the parser does
+            None if source.starts_with('\u{feff}') && start >= 3 => 3,
+            None => 0,
+        };
+        let line_end = source[start..]
+            .find('\n')
+            .map(|i| start + i)
+            .unwrap_or(source.len());
+        let line_str = source[line_start..line_end].trim_end_matches('\r');
+
+        let prefix = format!("{:2} | ", self.span.line);
+        let margin = source[line_start..start].chars().count();
+
+        let mut ret = String::new();
+        ret.push_str(&prefix);
+        ret.push_str(line_str);
+        ret.push('\n');
+        ret.extend(std::iter::repeat_n(' ', prefix.len() + margin));
+        ret.push('^');
+        ret
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Span { line, column, .. } = self.span;
+        write!(f, "{line}:{column}: {}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// An error while parsing a document as it's read.
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "failed to read: {e}"),
+            ReadError::Parse(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> Self {
+        ReadError::Parse(e)
+    }
+}
+
+/// Where the stages send their errors to.
+///
+/// In strict mode, reporting an error aborts the parse. In recovering mode, errors are collected and
+/// the stages work around the bad input (usually by turning it into an error node) and keep going.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    recover: bool,
+    errors: Vec<ParseError>,
+}
+
+impl Diagnostics {
+    pub fn strict() -> Self {
+        Self::default()
+    }
+
+    pub fn recovering() -> Self {
+        Self {
+            recover: true,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Report an error.
+    ///
+    /// Returns it back as `Err` in strict mode (so it can be propagated with `?`), or stores it
+    /// and returns `Ok` in recovering mode.
+    pub fn report(&mut self, error: ParseError) -> Result<(), ParseError> {
+        if self.recover {
+            self.errors.push(error);
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+
+    pub fn errors(&self) -> &[ParseError] {
+        &self.errors
+    }
+
+    pub fn into_errors(self) -> Vec<ParseError> {
+        self.errors
+    }
+
+    /// Take the errors reported so far, leaving none behind.
+    pub fn take_errors(&mut self) -> Vec<ParseError> {
+        std::mem::take(&mut self.errors)
+    }
+}
@@ -0,0 +1,716 @@
+//! The `@functions` stage 3 knows about.
+//!
+//! Each function is a [`Function`], registered by name in a [`Functions`] registry. It declares
+//! whether it's a block (a whole line, like `@code`) or inline (a term inside a line, like
+//! `@kbd`), and which arguments it takes, which are checked before its handler is called.
+//!
+//! The builtin functions are registered in [`Functions::default`], and more can be added to it:
+//!
+//! ```
+//! use std::borrow::Cow;
+//! use acrylic_parser::parser::functions::{Call, Function, FunctionKind, Functions, Signature};
+//! use acrylic_parser::parser::{Diagnostics, ParseError};
+//! use acrylic_parser::Term;
+//!
+//! struct Jira;
+//!
+//! impl Function for Jira {
+//!     fn signature(&self) -> Signature {
+//!         Signature::new(FunctionKind::Inline, 1..=1)
+//!     }
+//!
+//!     fn inline<'a>(&self, call: Call<'a, '_>) -> Result<Term<'a>, ParseError> {
+//!         let issue = call.string_arg(0)?;
+//!         let url = format!("https://jira.example.org/browse/{issue}");
+//!         Ok(Term::Url(Cow::Owned(url)))
+//!     }
+//! }
+//!
+//! let mut functions = Functions::default();
+//! functions.register("jira", Jira);
+//!
+//! let mut diag = Diagnostics::strict();
+//! let doc = acrylic_parser::parser::parse_with("see @jira{ABC-12}\n", &functions, &mut diag);
+//! assert!(doc.is_ok());
+//! ```
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::LazyLock;
+
+use crate::parser::data::{FuncCall, KwArg, Span, Spanned, Term as Term2};
+use crate::parser::stage3::{
+    CodeBlockLine, Context, DotGraphLine, HeadingLine, ImageLine, Line, QuoteLine, TableItem,
+    TableLine, Term, is_link_target, plain_text, process_code_block_arg, process_terms, slug,
+};
+use crate::parser::{ErrorKind, ParseError};
+
+/// Where a function can be called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionKind {
+    /// Alone in its line, which it builds.
+    Block,
+    /// Anywhere in a line, building a term.
+    Inline,
+}
+
+/// What a function takes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub kind: FunctionKind,
+    /// How many positional arguments it takes.
+    pub args: RangeInclusive<usize>,
+    /// The names of the keyword arguments it takes (all optional).
+    pub kwargs: &'static [&'static str],
+}
+
+impl Signature {
+    /// A signature without keyword arguments.
+    pub fn new(kind: FunctionKind, args: RangeInclusive<usize>) -> Self {
+        Self {
+            kind,
+            args,
+            kwargs: &[],
+        }
+    }
+
+    pub fn with_kwargs(self, kwargs: &'static [&'static str]) -> Self {
+        Self { kwargs, ..self }
+    }
+
+    /// Describe the accepted argument count, for error messages.
+    fn describe_args(&self) -> String {
+        match (*self.args.start(), *self.args.end()) {
+            (0, 0) => "no arguments".to_owned(),
+            (1, 1) => "a single argument".to_owned(),
+            (a, b) if a == b => format!("{a} arguments"),
+            (a, b) if a + 1 == b => format!("{a} or {b} arguments"),
+            (a, usize::MAX) => format!("at least {a} arguments"),
+            (a, b) => format!("{a} to {b} arguments"),
+        }
+    }
+}
+
+/// A function that can be called with `@name`.
+///
+/// Only the handler for its [`FunctionKind`] gets called, and only after its arguments were
+/// checked against its [`Signature`].
+pub trait Function: Send + Sync {
+    fn signature(&self) -> Signature;
+
+    /// Build the line of a block function.
+    fn block<'a>(&self, call: Call<'a, '_>) -> Result<Line<'a>, ParseError> {
+        Err(call.misplaced(FunctionKind::Block))
+    }
+
+    /// Build the term of an inline function.
+    fn inline<'a>(&self, call: Call<'a, '_>) -> Result<Term<'a>, ParseError> {
+        Err(call.misplaced(FunctionKind::Inline))
+    }
+}
+
+/// A call to a function, as given to its handler.
+pub struct Call<'a, 'f> {
+    pub fc: Spanned<FuncCall<'a>>,
+    cx: Context<'a, 'f>,
+}
+
+impl<'a, 'f> Call<'a, 'f> {
+    pub(crate) fn new(fc: Spanned<FuncCall<'a>>, cx: Context<'a, 'f>) -> Self {
+        Self { fc, cx }
+    }
+
+    /// How deep the line of the call is nested (0 for top-level lines).
+    pub fn depth(&self) -> usize {
+        self.cx.depth
+    }
+
+    /// The span of the `i`-th argument (or of the whole call, if it's empty).
+    pub fn arg_span(&self, i: usize) -> Span {
+        terms_span(&self.fc.args[i]).unwrap_or(self.fc.span)
+    }
+
+    /// Stringify the `i`-th argument, failing if it isn't a plain string.
+    pub fn string_arg(&self, i: usize) -> Result<Cow<'a, str>, ParseError> {
+        try_stringify(&self.fc.args[i]).map_err(|span| {
+            ParseError::new(
+                ErrorKind::BadArguments,
+                span,
+                format!("`@{}` call expects a string as arg {}", self.fc.name, i + 1),
+            )
+        })
+    }
+
+    /// Process the terms of the `i`-th argument, as the content of a line.
+    ///
+    /// The argument is taken out of the call, so it's left empty.
+    pub fn terms_arg(&mut self, i: usize) -> Result<Vec<Spanned<Term<'a>>>, ParseError> {
+        let arg = std::mem::take(&mut self.fc.value.args[i]);
+        process_terms(&mut arg.into_iter(), self.cx)
+    }
+
+    pub fn kwarg(&self, name: &str) -> Option<&Spanned<KwArg<'a>>> {
+        self.fc.kwargs.iter().find(|kw| kw.name == name)
+    }
+
+    /// Stringify the keyword argument `name`, if it was given.
+    pub fn string_kwarg(&self, name: &str) -> Result<Option<String>, ParseError> {
+        let Some(kw) = self.kwarg(name) else {
+            return Ok(None);
+        };
+
+        match try_stringify(&kw.arg) {
+            Ok(x) => Ok(Some(x.into_owned())),
+            Err(span) => Err(ParseError::new(
+                ErrorKind::BadArguments,
+                span,
+                format!("`@{}` call expects a string for {name:?}", self.fc.name),
+            )),
+        }
+    }
+
+    /// Fail if the keyword argument `name` was given, for when it was already given positionally.
+    pub fn reject_kwarg(&self, name: &str) -> Result<(), ParseError> {
+        match self.kwarg(name) {
+            Some(kw) => Err(ParseError::new(
+                ErrorKind::BadArguments,
+                kw.span,
+                format!("{name:?} given both as keyword and positional argument"),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The error for a function called as a kind it isn't.
+    fn misplaced(&self, kind: FunctionKind) -> ParseError {
+        let what = match kind {
+            FunctionKind::Block => "a block",
+            FunctionKind::Inline => "inline",
+        };
+        ParseError::new(
+            ErrorKind::MisplacedFunction,
+            self.fc.span,
+            format!("function {:?} can't be used {what}", self.fc.name),
+        )
+    }
+}
+
+/// Check the arguments of a call against the signature of the function.
+pub(crate) fn check_call(fc: &Spanned<FuncCall>, signature: &Signature) -> Result<(), ParseError> {
+    for (i, kw) in fc.kwargs.iter().enumerate() {
+        if !signature.kwargs.contains(&&*kw.name) {
+            return Err(ParseError::new(
+                ErrorKind::BadArguments,
+                kw.span,
+                format!("`@{}` call has no keyword argument {:?}", fc.name, kw.name),
+            ));
+        }
+
+        if fc.kwargs[..i].iter().any(|x| x.name == kw.name) {
+            return Err(ParseError::new(
+                ErrorKind::BadArguments,
+                kw.span,
+                format!("keyword argument {:?} given more than once", kw.name),
+            ));
+        }
+    }
+
+    if !signature.args.contains(&fc.args.len()) {
+        return Err(ParseError::new(
+            ErrorKind::BadArguments,
+            fc.span,
+            format!(
+                "`@{}` call expects {}, {} given",
+                fc.name,
+                signature.describe_args(),
+                fc.args.len()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The functions that can be called, by name.
+pub struct Functions {
+    map: HashMap<String, Box<dyn Function>>,
+}
+
+static BUILTIN: LazyLock<Functions> = LazyLock::new(Functions::default);
+
+impl Functions {
+    /// A registry without any function, not even the builtin ones.
+    pub fn empty() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+
+    /// The builtin functions, shared.
+    pub fn builtin() -> &'static Functions {
+        &BUILTIN
+    }
+
+    /// Register `function` as `name`, replacing any function with that name.
+    pub fn register(&mut self, name: impl Into<String>, function: impl Function + 'static) {
+        self.map.insert(name.into(), Box::new(function));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Function> {
+        self.map.get(name).map(|f| &**f)
+    }
+}
+
+impl Default for Functions {
+    /// The builtin functions.
+    fn default() -> Self {
+        use FunctionKind::{Block, Inline};
+
+        let mut ret = Self::empty();
+        let mut block = |name, args, kwargs, f| {
+            let signature = Signature::new(Block, args).with_kwargs(kwargs);
+            ret.register(
+                name,
+                Builtin {
+                    signature,
+                    handler: Handler::Block(f),
+                },
+            );
+        };
+        block("code", 1..=2, &["lang"], code);
+        block("dot", 1..=2, &["engine"], dot);
+        block("h", 1..=1, &["level", "id"], heading);
+        block("image", 1..=2, &["caption", "width"], image);
+        block("quote", 0..=1, &["by"], quote);
+        block("table", 1..=1, &["header", "caption"], table);
+
+        let mut inline = |name, args, f| {
+            let signature = Signature::new(Inline, args);
+            ret.register(
+                name,
+                Builtin {
+                    signature,
+                    handler: Handler::Inline(f),
+                },
+            );
+        };
+        inline("anchor", 1..=1, |call| {
+            Ok(Term::Anchor(call.string_arg(0)?))
+        });
+        inline("c", 1..=1, |call| Ok(Term::Code(call.string_arg(0)?)));
+        inline("fn", 1..=1, footnote);
+        inline("kbd", 1..=1, |call| Ok(Term::Kbd(call.string_arg(0)?)));
+        inline("link", 1..=2, |call| {
+            let span = call.arg_span(0);
+            let (content, target) = target_and_content(call)?;
+            if !is_link_target(&target) {
+                return Err(ParseError::new(
+                    ErrorKind::BadArguments,
+                    span,
+                    format!("`@link` can only go to paths and `scheme://` URLs, not {target:?}"),
+                ));
+            }
+            Ok(Term::Link { content, target })
+        });
+        inline("ref", 1..=2, |call| {
+            let (content, target) = target_and_content(call)?;
+            Ok(Term::Ref { content, target })
+        });
+        inline("s", 1..=1, |mut call| {
+            Ok(Term::Strikethrough(call.terms_arg(0)?))
+        });
+        inline("u", 1..=1, |mut call| {
+            Ok(Term::Underline(call.terms_arg(0)?))
+        });
+        inline("mark", 1..=1, |mut call| {
+            Ok(Term::Highlight(call.terms_arg(0)?))
+        });
+        inline("sup", 1..=1, |mut call| {
+            Ok(Term::Superscript(call.terms_arg(0)?))
+        });
+        inline("sub", 1..=1, |mut call| {
+            Ok(Term::Subscript(call.terms_arg(0)?))
+        });
+
+        ret
+    }
+}
+
+impl fmt::Debug for Functions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<_> = self.map.keys().collect();
+        names.sort();
+        f.debug_set().entries(names).finish()
+    }
+}
+
+/// A builtin function, which is just its signature and a function pointer.
+struct Builtin {
+    signature: Signature,
+    handler: Handler,
+}
+
+enum Handler {
+    Block(for<'a, 'f> fn(Call<'a, 'f>) -> Result<Line<'a>, ParseError>),
+    Inline(for<'a, 'f> fn(Call<'a, 'f>) -> Result<Term<'a>, ParseError>),
+}
+
+impl Function for Builtin {
+    fn signature(&self) -> Signature {
+        self.signature.clone()
+    }
+
+    fn block<'a>(&self, call: Call<'a, '_>) -> Result<Line<'a>, ParseError> {
+        match self.handler {
+            Handler::Block(f) => f(call),
+            Handler::Inline(_) => Err(call.misplaced(FunctionKind::Block)),
+        }
+    }
+
+    fn inline<'a>(&self, call: Call<'a, '_>) -> Result<Term<'a>, ParseError> {
+        match self.handler {
+            Handler::Inline(f) => f(call),
+            Handler::Block(_) => Err(call.misplaced(FunctionKind::Inline)),
+        }
+    }
+}
+
+/// Get the span that covers all of `terms`, if there are any.
+pub(crate) fn terms_span<T>(terms: &[Spanned<T>]) -> Option<Span> {
+    let first = terms.first()?;
+    let last = terms.last()?;
+    Some(first.span.to(last.span))
+}
+
+/// Join the terms into a string, if they're all plain text, or get the span of the first one that
+/// isn't.
+///
+/// A single word is borrowed as-is.
+fn try_stringify<'a>(terms: &[Spanned<Term2<'a>>]) -> Result<Cow<'a, str>, Span> {
+    if let [
+        Spanned {
+            value: Term2::Word(w),
+            ..
+        },
+    ] = terms
+    {
+        return Ok(w.clone());
+    }
+
+    let mut ret = String::new();
+
+    for t in terms {
+        match &t.value {
+            Term2::Space => ret.push(' '),
+            Term2::Word(w) => ret.push_str(w),
+            Term2::MaybeDelim(c) => ret.push(*c),
+            _ => return Err(t.span),
+        }
+    }
+
+    Ok(Cow::Owned(ret))
+}
+
+fn footnote<'a>(mut call: Call<'a, '_>) -> Result<Term<'a>, ParseError> {
+    // numbered before its content, so the footnotes inside it come after it
+    let number = {
+        let mut footnotes = call.cx.footnotes.borrow_mut();
+        footnotes.push(Vec::new());
+        footnotes.len()
+    };
+    let content = call.terms_arg(0)?;
+    call.cx.footnotes.borrow_mut()[number - 1] = content;
+
+    Ok(Term::Footnote(number))
+}
+
+fn code<'a>(call: Call<'a, '_>) -> Result<Line<'a>, ParseError> {
+    let (lang, code) = match call.fc.args.len() {
+        1 => (call.string_kwarg("lang")?, call.string_arg(0)?),
+        _ => {
+            call.reject_kwarg("lang")?;
+            let lang = call.string_arg(0)?.into_owned();
+            (Some(lang), call.string_arg(1)?)
+        }
+    };
+
+    Ok(Line::CodeBlock(CodeBlockLine {
+        lang,
+        code: process_code_block_arg(&code),
+    }))
+}
+
+fn dot<'a>(call: Call<'a, '_>) -> Result<Line<'a>, ParseError> {
+    let (engine, code) = match call.fc.args.len() {
+        1 => (call.string_kwarg("engine")?, call.string_arg(0)?),
+        _ => {
+            call.reject_kwarg("engine")?;
+            let engine = call.string_arg(0)?.into_owned();
+            (Some(engine), call.string_arg(1)?)
+        }
+    };
+
+    Ok(Line::DotGraph(DotGraphLine {
+        engine: engine.unwrap_or_else(|| "dot".into()),
+        code,
+    }))
+}
+
+fn heading<'a>(mut call: Call<'a, '_>) -> Result<Line<'a>, ParseError> {
+    let level = match call.string_kwarg("level")? {
+        None => HeadingLine::level_at(call.depth()),
+        Some(x) => match x.parse() {
+            Ok(level) if (1..=HeadingLine::MAX_LEVEL).contains(&level) => level,
+            _ => {
+                return Err(ParseError::new(
+                    ErrorKind::BadArguments,
+                    call.kwarg("level").map_or(call.fc.span, |kw| kw.span),
+                    format!(
+                        "expected a number from 1 to {} for \"level\", got {x:?}",
+                        HeadingLine::MAX_LEVEL
+                    ),
+                ));
+            }
+        },
+    };
+    // an empty ID is the same as none
+    let id = call.string_kwarg("id")?.filter(|id| !id.is_empty());
+    let content = call.terms_arg(0)?;
+
+    // without an explicit ID, it's made unique once the whole document is processed
+    Ok(Line::Heading(HeadingLine {
+        level,
+        explicit_id: id.is_some(),
+        id: id.unwrap_or_else(|| slug(&plain_text(&content))),
+        content,
+    }))
+}
+
+fn image<'a>(call: Call<'a, '_>) -> Result<Line<'a>, ParseError> {
+    let (caption, url) = match call.fc.args.len() {
+        1 => (call.string_kwarg("caption")?, call.string_arg(0)?),
+        _ => {
+            call.reject_kwarg("caption")?;
+            let caption = call.string_arg(0)?.into_owned();
+            (Some(caption), call.string_arg(1)?)
+        }
+    };
+
+    Ok(Line::Image(ImageLine {
+        caption,
+        url: url.into_owned(),
+        width: call.string_kwarg("width")?,
+    }))
+}
+
+fn quote<'a>(mut call: Call<'a, '_>) -> Result<Line<'a>, ParseError> {
+    let content = match call.fc.args.len() {
+        0 => Vec::new(),
+        _ => call.terms_arg(0)?,
+    };
+
+    Ok(Line::Quote(QuoteLine {
+        content,
+        attribution: call.string_kwarg("by")?,
+    }))
+}
+
+fn table<'a>(mut call: Call<'a, '_>) -> Result<Line<'a>, ParseError> {
+    let header = match call.string_kwarg("header")?.as_deref() {
+        Some("yes") | None => true,
+        Some("no") => false,
+        Some(other) => {
+            return Err(ParseError::new(
+                ErrorKind::BadArguments,
+                call.kwarg("header").map_or(call.fc.span, |kw| kw.span),
+                format!("expected \"yes\" or \"no\" for \"header\", got {other:?}"),
+            ));
+        }
+    };
+    let caption = call.string_kwarg("caption")?;
+
+    let cx = call.cx;
+    let mut it = std::mem::take(&mut call.fc.value.args[0]).into_iter();
+    let mut get_next = || -> Result<Option<Spanned<TableItem>>, ParseError> {
+        loop {
+            let Some(t) = it.next() else {
+                return Ok(None);
+            };
+
+            match t.value {
+                Term2::Space => {}
+                Term2::List(row) => {
+                    let mut r = Vec::new();
+                    for arg in row.into_iter() {
+                        r.push(process_terms(&mut arg.into_iter(), cx)?);
+                    }
+                    return Ok(Some(Spanned::new(TableItem::Row(r), t.span)));
+                }
+                Term2::Word(s) if s == "---" => {
+                    return Ok(Some(Spanned::new(TableItem::Separator, t.span)));
+                }
+                other => {
+                    return Err(ParseError::new(
+                        ErrorKind::UnexpectedTerm,
+                        t.span,
+                        format!(
+                            "expected space, list or separator, got {}",
+                            other.describe()
+                        ),
+                    ));
+                }
+            }
+        }
+    };
+
+    let mut items = Vec::new();
+    let mut last_ncols = None;
+    while let Some(res) = get_next()? {
+        match res.value {
+            TableItem::Row(r) => {
+                match last_ncols {
+                    Some(x) if x != r.len() => {
+                        return Err(ParseError::new(
+                            ErrorKind::TableShape,
+                            res.span,
+                            format!(
+                                "got rows of different sizes (first {}, then {})",
+                                x,
+                                r.len()
+                            ),
+                        ));
+                    }
+                    Some(_) => {}
+                    None => last_ncols = Some(r.len()),
+                }
+
+                items.push(TableItem::Row(r));
+            }
+            TableItem::Separator => items.push(TableItem::Separator),
+        }
+    }
+
+    Ok(Line::Table(TableLine {
+        columns: last_ncols.unwrap_or(0),
+        items,
+        header,
+        caption,
+    }))
+}
+
+/// The arguments of `@ref` and `@link`: a target, then an optional content (empty if there's
+/// none).
+fn target_and_content<'a>(
+    mut call: Call<'a, '_>,
+) -> Result<(Vec<Spanned<Term<'a>>>, String), ParseError> {
+    let target = call.string_arg(0)?;
+    let content = match call.fc.args.len() {
+        1 => Vec::new(),
+        _ => call.terms_arg(1)?,
+    };
+
+    Ok((content, target.into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Diagnostics, parse_with};
+
+    struct Jira;
+
+    impl Function for Jira {
+        fn signature(&self) -> Signature {
+            Signature::new(FunctionKind::Inline, 1..=1).with_kwargs(&["project"])
+        }
+
+        fn inline<'a>(&self, call: Call<'a, '_>) -> Result<Term<'a>, ParseError> {
+            let project = call
+                .string_kwarg("project")?
+                .unwrap_or_else(|| "ABC".to_owned());
+            let issue = call.string_arg(0)?;
+            Ok(Term::Url(Cow::Owned(format!(
+                "https://jira.example.org/{project}-{issue}"
+            ))))
+        }
+    }
+
+    struct Aside;
+
+    impl Function for Aside {
+        fn signature(&self) -> Signature {
+            Signature::new(FunctionKind::Block, 1..=1)
+        }
+
+        fn block<'a>(&self, mut call: Call<'a, '_>) -> Result<Line<'a>, ParseError> {
+            let mut content = vec![Spanned::new(Term::Word("> ".into()), call.fc.span)];
+            content.extend(call.terms_arg(0)?);
+            Ok(Line::Text(crate::parser::stage3::TextLine {
+                bullet: None,
+                task: None,
+                content,
+            }))
+        }
+    }
+
+    fn parse_line<'a>(input: &'a str, functions: &Functions) -> Result<Line<'a>, ParseError> {
+        parse_with(input, functions, &mut Diagnostics::strict())
+            .map(|mut doc| doc.nodes.remove(0).line)
+    }
+
+    #[test]
+    fn custom_functions() {
+        let mut functions = Functions::default();
+        functions.register("jira", Jira);
+        functions.register("aside", Aside);
+
+        let Ok(Line::Text(l)) = parse_line("see @jira[project=XY]{12}", &functions) else {
+            panic!()
+        };
+        assert_eq!(
+            l.content[2].value,
+            Term::Url("https://jira.example.org/XY-12".into())
+        );
+
+        let Ok(Line::Text(l)) = parse_line("@aside{*a* @jira{3}}", &functions) else {
+            panic!()
+        };
+        assert!(matches!(l.content[1].value, Term::Bold(_)));
+        assert!(matches!(l.content[3].value, Term::Url(_)));
+
+        let errors = [
+            ("@jira{1}{2}", ErrorKind::BadArguments),
+            ("@jira[foo=bar]{1}", ErrorKind::BadArguments),
+            ("a @aside{b}", ErrorKind::MisplacedFunction),
+            ("@nope{x}", ErrorKind::UnknownFunction),
+        ];
+        for (input, kind) in errors {
+            assert_eq!(
+                parse_line(input, &functions).unwrap_err().kind,
+                kind,
+                "{input:?}"
+            );
+        }
+
+        // a string argument with other terms in it points at the first one
+        let err = parse_line("@jira{a *b*}", &functions).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadArguments);
+        assert_eq!((err.span.start, err.span.end), (8, 11));
+
+        // without them, they're just unknown
+        let err = parse_line("see @jira{12}", Functions::builtin()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UnknownFunction);
+    }
+
+    #[test]
+    fn argument_counts() {
+        let sig = |args| Signature::new(FunctionKind::Inline, args).describe_args();
+        assert_eq!(sig(0..=0), "no arguments");
+        assert_eq!(sig(1..=1), "a single argument");
+        assert_eq!(sig(1..=2), "1 or 2 arguments");
+        assert_eq!(sig(1..=3), "1 to 3 arguments");
+        assert_eq!(sig(2..=usize::MAX), "at least 2 arguments");
+    }
+}
@@ -0,0 +1,522 @@
+//! Incremental reparsing, for editors.
+//!
+//! The body of the document is split in regions, one per top-level node: from the start of its
+//! line up to the start of the next top-level line. After an edit, only the regions around it are
+//! lexed and processed again, while the rest are reused (with their spans shifted and their
+//! footnotes renumbered, if they come after the edit). The result is always the same as parsing
+//! the whole source again with [`parse_recovering`](crate::parser::parse_recovering).
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::parser::data::Line as Line1;
+use crate::parser::refs::RefIndex;
+use crate::parser::stage1::{self, IndentStats, LineLexer};
+use crate::parser::stage3::{self, Document, HeadingIds, Node, Term};
+use crate::parser::visit::{VisitMut, walk_term_mut};
+use crate::parser::{
+    Diagnostics, Functions, IndentOrigin, ParseError, Span, Spanned, StandardOptions, stage2,
+};
+
+type Footnote = Vec<Spanned<Term<'static>>>;
+
+/// A parsed document that can be edited, reparsing only the parts that changed.
+///
+/// Errors never stop the parse, as with [`parse_recovering`](crate::parser::parse_recovering).
+#[derive(Debug, Clone)]
+pub struct IncrementalDocument {
+    source: String,
+    document: Document<'static>,
+    header_errors: Vec<ParseError>,
+    /// The errors from giving headings their IDs, which is done on the whole document.
+    id_errors: Vec<ParseError>,
+    body_start: usize,
+    body_line: u32,
+    regions: Vec<Region>,
+}
+
+/// A top-level node, along with what's needed to reuse it.
+#[derive(Debug, Clone)]
+struct Region {
+    start: usize,
+    line: u32,
+    /// Only the region of a body without any node has none.
+    has_node: bool,
+    /// Whether lexing the region looked at the text after it, which lines with errors may do (an
+    /// unterminated argument is searched for up to the end of the source).
+    looks_ahead: bool,
+    stats: IndentStats,
+    /// How many footnotes its node has.
+    footnotes: usize,
+    /// The errors from each stage.
+    errors: [Vec<ParseError>; 3],
+}
+
+impl IncrementalDocument {
+    pub fn new(source: String) -> Self {
+        let mut diag = Diagnostics::recovering();
+        let (header, options, lexer) = match stage1::parse_header(&source, &mut diag) {
+            Ok(x) => x,
+            // not possible when recovering, but just in case...
+            Err(e) => {
+                diag.report(e).ok();
+                let lexer = LineLexer::at(&source, source.len(), 1);
+                (HashMap::new(), StandardOptions::default(), lexer)
+            }
+        };
+
+        let mut header_errors = diag.into_errors();
+        header_errors.sort_by_key(|e| e.span.start);
+
+        let (body_start, body_line) = (lexer.offset(), lexer.line());
+        let (regions, nodes, footnotes) =
+            lex_regions(&source, &options, body_start, body_line, 0, |_| false);
+
+        let mut ret = Self {
+            document: Document {
+                header,
+                options,
+                nodes,
+                footnotes,
+            },
+            source,
+            header_errors,
+            id_errors: Vec::new(),
+            body_start,
+            body_line,
+            regions,
+        };
+        ret.assign_heading_ids();
+        ret
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn document(&self) -> &Document<'static> {
+        &self.document
+    }
+
+    /// Every error found, in the order they appear in the source.
+    pub fn errors(&self) -> Vec<ParseError> {
+        // same order as a full parse: by stage, then sorted by position (an error can be found
+        // past its region, as an unterminated argument is searched for up to the end)
+        let mut errors: Vec<_> = self.header_errors.clone();
+        for stage in 0..3 {
+            errors.extend(
+                self.regions
+                    .iter()
+                    .flat_map(|r| r.errors[stage].iter().cloned()),
+            );
+        }
+        errors.extend(self.id_errors.iter().cloned());
+        // a ref can point anywhere, so these are checked on the whole document
+        errors.extend(RefIndex::of(&self.document).dangling());
+        errors.sort_by_key(|e| e.span.start);
+        errors
+    }
+
+    /// Replace the text in `range` (byte offsets) with `replacement`, and reparse.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds or doesn't lie on character boundaries.
+    pub fn edit(&mut self, range: Range<usize>, replacement: &str) {
+        let Range { start: a, end: b } = range;
+
+        // The header is read up to the first two lines of the body (an entry without a value can
+        // still get one from the next line), and anything can change with it, so start over.
+        let line_end = |from: usize| {
+            self.source[from..]
+                .find(['\n', '\r'])
+                .map_or(self.source.len(), |i| from + i)
+        };
+        let first_line_end = line_end(self.body_start);
+        let next_line = match self.source[first_line_end..].starts_with("\r\n") {
+            true => first_line_end + 2,
+            false => (first_line_end + 1).min(self.source.len()),
+        };
+        let header_reach = line_end(next_line);
+
+        self.source.replace_range(a..b, replacement);
+        let new_end = a + replacement.len();
+
+        if a <= header_reach {
+            *self = Self::new(std::mem::take(&mut self.source));
+            return;
+        }
+
+        // Redo the region where the edit starts and the one before it: the edit might turn the
+        // first line of its region into a child (or an empty line) of the previous one. And
+        // regions that looked ahead might have seen the edited text.
+        let containing = self.regions.partition_point(|r| r.start <= a);
+        let previous = containing.saturating_sub(2);
+        let first = self.regions[..previous]
+            .iter()
+            .position(|r| r.looks_ahead)
+            .unwrap_or(previous);
+
+        let (start, line) = self
+            .regions
+            .get(first)
+            .map_or((self.body_start, self.body_line), |r| (r.start, r.line));
+
+        let first_footnote = self.regions[..first].iter().map(|r| r.footnotes).sum();
+
+        // Stop at the first old region past the edit: from there on, nothing changed.
+        let old = &self.regions;
+        let mut reuse_from = old.len();
+        let mut line_shift = 0;
+        let (regions, nodes, footnotes) = lex_regions(
+            &self.source,
+            &self.document.options,
+            start,
+            line,
+            first_footnote,
+            |lexer| {
+                if lexer.offset() < new_end {
+                    return false;
+                }
+                let old_offset = lexer.offset() - new_end + b;
+                match old.binary_search_by_key(&old_offset, |r| r.start) {
+                    Ok(k) => {
+                        reuse_from = k;
+                        line_shift = i64::from(lexer.line()) - i64::from(old[k].line);
+                        true
+                    }
+                    Err(_) => false,
+                }
+            },
+        );
+
+        let shift = new_end as i64 - b as i64;
+        let first_node = self.regions[..first].iter().filter(|r| r.has_node).count();
+        let reused_node = first_node
+            + self.regions[first..reuse_from]
+                .iter()
+                .filter(|r| r.has_node)
+                .count();
+        let reused_footnote = first_footnote
+            + self.regions[first..reuse_from]
+                .iter()
+                .map(|r| r.footnotes)
+                .sum::<usize>();
+        let footnote_shift = (first_footnote + footnotes.len()) as i64 - reused_footnote as i64;
+
+        for r in &mut self.regions[reuse_from..] {
+            r.start = (r.start as i64 + shift) as usize;
+            r.line = (i64::from(r.line) + line_shift) as u32;
+            for e in r.errors.iter_mut().flatten() {
+                shift_span(&mut e.span, shift, line_shift);
+            }
+        }
+        let mut moved = Shift {
+            shift,
+            line_shift,
+            footnote_shift,
+        };
+        for n in &mut self.document.nodes[reused_node..] {
+            moved.visit_node_mut(n, 0);
+        }
+        for content in &mut self.document.footnotes[reused_footnote..] {
+            moved.visit_terms_mut(content);
+        }
+
+        self.regions.splice(first..reuse_from, regions);
+        self.document.nodes.splice(first_node..reused_node, nodes);
+        self.document
+            .footnotes
+            .splice(first_footnote..reused_footnote, footnotes);
+        // the new headings can take the IDs of the ones after them (or free theirs)
+        self.assign_heading_ids();
+
+        // the regions were lexed with the old indent, which the edit might have changed
+        let options = &self.document.options;
+        if options.indent_origin != IndentOrigin::Header {
+            // the stats of each region only add up to those of the whole body when no line
+            // failed to lex (as a bad line is skipped differently when counting)
+            let stats = if self.regions.iter().any(|r| r.looks_ahead) {
+                IndentStats::of(
+                    &self.source,
+                    self.body_start,
+                    self.body_line,
+                    self.source.len(),
+                )
+            } else {
+                self.regions
+                    .iter()
+                    .fold(IndentStats::default(), |acc, r| acc.merge(r.stats))
+            };
+
+            let inferred = match stats.indent() {
+                Some(indent) => (indent, IndentOrigin::Inferred),
+                None => (StandardOptions::default().indent, IndentOrigin::Default),
+            };
+            if inferred != (options.indent, options.indent_origin) {
+                *self = Self::new(std::mem::take(&mut self.source));
+            }
+        }
+    }
+
+    /// Give the headings their IDs again, as any of them might have changed.
+    fn assign_heading_ids(&mut self) {
+        let mut diag = Diagnostics::recovering();
+        // (can't fail when recovering)
+        HeadingIds::assign_document(&mut self.document, &mut diag).ok();
+        self.id_errors = diag.into_errors();
+    }
+}
+
+/// Lines of a region, as they're read.
+struct Group<'a> {
+    start: usize,
+    line: u32,
+    has_node: bool,
+    looks_ahead: bool,
+    lines: Vec<Line1<'a>>,
+    errors: Vec<ParseError>,
+}
+
+/// Lex the lines from `start` (the start of line number `line`) until `stop` returns true (it's
+/// asked at the start of every line) or the source ends, and turn them into regions.
+///
+/// Their footnotes are numbered after the `first_footnote` ones before them.
+fn lex_regions(
+    source: &str,
+    options: &StandardOptions,
+    start: usize,
+    line: u32,
+    first_footnote: usize,
+    mut stop: impl FnMut(&LineLexer) -> bool,
+) -> (Vec<Region>, Vec<Node<'static>>, Vec<Footnote>) {
+    let new_group = |start, line| Group {
+        start,
+        line,
+        has_node: false,
+        looks_ahead: false,
+        lines: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    let mut lexer = LineLexer::at(source, start, line);
+    let mut groups = vec![new_group(start, line)];
+    loop {
+        if lexer.offset() > start && stop(&lexer) {
+            break;
+        }
+
+        let (offset, line_number) = (lexer.offset(), lexer.line());
+        let mut diag = Diagnostics::recovering();
+        let Ok(Some(line)) = lexer.next_line(options, &mut diag) else {
+            break;
+        };
+
+        // the first non-empty line is always top-level, even if indented (an orphan indent)
+        let group = groups.last_mut().unwrap();
+        let top_level = !line.terms.is_empty() && (line.indent == 0 || !group.has_node);
+        if top_level && group.has_node {
+            groups.push(new_group(offset, line_number));
+        }
+
+        let group = groups.last_mut().unwrap();
+        group.has_node |= top_level;
+        let errors = diag.into_errors();
+        group.looks_ahead |= !errors.is_empty();
+        group.errors.extend(errors);
+        group.lines.push(line);
+    }
+
+    let end = lexer.offset();
+    let ends: Vec<_> = groups
+        .iter()
+        .skip(1)
+        .map(|g| g.start)
+        .chain([end])
+        .collect();
+
+    let mut regions = Vec::new();
+    let mut nodes = Vec::new();
+    let mut all_footnotes = Vec::new();
+    for (g, end) in groups.into_iter().zip(ends) {
+        let mut diag2 = Diagnostics::recovering();
+        let mut diag3 = Diagnostics::recovering();
+        let mut footnotes = Vec::new();
+        let processed = stage2::build_tree(g.lines, &mut diag2).and_then(|n| {
+            stage3::process_nodes(n, Functions::builtin(), &mut footnotes, &mut diag3)
+        });
+
+        // each region numbers its footnotes from 1
+        let mut renumber = Shift {
+            shift: 0,
+            line_shift: 0,
+            footnote_shift: (first_footnote + all_footnotes.len()) as i64,
+        };
+        match processed {
+            Ok(n) => nodes.extend(n.into_iter().map(|mut n| {
+                renumber.visit_node_mut(&mut n, 0);
+                n.into_owned()
+            })),
+            Err(e) => _ = diag3.report(e),
+        }
+        let footnote_count = footnotes.len();
+        all_footnotes.extend(footnotes.into_iter().map(|mut content| {
+            renumber.visit_terms_mut(&mut content);
+            content
+                .into_iter()
+                .map(|t| t.map(Term::into_owned))
+                .collect()
+        }));
+
+        regions.push(Region {
+            start: g.start,
+            line: g.line,
+            has_node: g.has_node,
+            looks_ahead: g.looks_ahead,
+            stats: IndentStats::of(source, g.start, g.line, end),
+            footnotes: footnote_count,
+            errors: [g.errors, diag2.into_errors(), diag3.into_errors()],
+        });
+    }
+
+    (regions, nodes, all_footnotes)
+}
+
+fn shift_span(span: &mut Span, shift: i64, line_shift: i64) {
+    span.start = (span.start as i64 + shift) as usize;
+    span.end = (span.end as i64 + shift) as usize;
+    span.line = (i64::from(span.line) + line_shift) as u32;
+}
+
+/// Moves every span in a node, for when the text before it changed, and renumbers its footnotes,
+/// for when the footnotes before it did.
+struct Shift {
+    shift: i64,
+    line_shift: i64,
+    footnote_shift: i64,
+}
+
+impl VisitMut for Shift {
+    fn enter_node_mut(&mut self, node: &mut Node<'_>, _depth: usize) {
+        shift_span(&mut node.span, self.shift, self.line_shift);
+    }
+
+    fn visit_error_mut(&mut self, error: &mut ParseError) {
+        shift_span(&mut error.span, self.shift, self.line_shift);
+    }
+
+    fn visit_term_mut(&mut self, term: &mut Spanned<Term<'_>>) {
+        shift_span(&mut term.span, self.shift, self.line_shift);
+        walk_term_mut(self, term);
+    }
+
+    fn visit_footnote_ref_mut(&mut self, number: &mut usize) {
+        *number = (*number as i64 + self.footnote_shift) as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_recovering;
+
+    fn assert_same_as_full_parse(inc: &IncrementalDocument) {
+        let (doc, errors) = parse_recovering(inc.source());
+        let source = inc.source();
+        assert_eq!(inc.document().header, doc.header, "{source:?}");
+        let inc_doc = inc.document();
+        assert_eq!(
+            format!(
+                "{:?}",
+                (&inc_doc.options, &inc_doc.nodes, &inc_doc.footnotes)
+            ),
+            format!("{:?}", (&doc.options, &doc.nodes, &doc.footnotes)),
+            "{source:?}",
+        );
+        assert_eq!(inc.errors(), errors, "{source:?}");
+    }
+
+    #[test]
+    fn edits_match_full_parse() {
+        let mut inc = IncrementalDocument::new("foo\n  bar\nbaz\n\nqux\n".to_owned());
+        assert_same_as_full_parse(&inc);
+
+        let edits: &[(Range<usize>, &str)] = &[
+            (4..4, "x"),                    // inside a child line
+            (0..0, "top\n"),                // at the very start
+            (8..8, "  child\n"),            // a new child line
+            (4..5, ""),                     // deleting a character
+            (9..9, "@foo{\n"),              // an unterminated argument
+            (14..14, "}"),                  // closing it, lines later
+            (3..4, ""),                     // merging lines
+            (4..4, "\tdeep\n\t\tdeeper\n"), // changing the inferred indent
+            (0..0, "%:indent 4\n\n"),
+        ];
+
+        for (range, replacement) in edits {
+            let end = range.end.min(inc.source().len());
+            let start = range.start.min(end);
+            inc.edit(start..end, replacement);
+            assert_same_as_full_parse(&inc);
+        }
+    }
+
+    #[test]
+    fn random_edits_match_full_parse() {
+        const SNIPPETS: &[&str] = &[
+            "",
+            "\n",
+            "\n\n",
+            "  ",
+            "\t",
+            "x",
+            "- a\n",
+            "@f{",
+            "}",
+            "@code{\n",
+            "*",
+            "_b_",
+            "${",
+            "%%",
+            "%tag",
+            "\r\n",
+            "@table{@{a}{b}}\n",
+            "\\",
+            "[ ] task",
+            "  - item\n",
+            "@fn{note}",
+            "x@fn{a @fn{b}}\n",
+            "@ref{a}",
+            "@anchor{a}",
+            "@h{A}\n",
+            "@h[id=a]{B}\n",
+        ];
+
+        let mut inc = IncrementalDocument::new(include_str!("../../examples/all.acr").to_owned());
+        assert_same_as_full_parse(&inc);
+
+        // a small LCG, so the test is deterministic
+        let mut state = 0x2545_f491_u64;
+        let mut next = |n: usize| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as usize % n
+        };
+
+        for _ in 0..300 {
+            let source = inc.source();
+            let mut a = next(source.len() + 1);
+            while !source.is_char_boundary(a) {
+                a -= 1;
+            }
+            let mut b = (a + next(8)).min(source.len());
+            while !source.is_char_boundary(b) {
+                b -= 1;
+            }
+
+            inc.edit(a..b, SNIPPETS[next(SNIPPETS.len())]);
+            assert_same_as_full_parse(&inc);
+        }
+    }
+}
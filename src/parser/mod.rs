@@ -3,27 +3,226 @@
 //! It's organized as a "pipeline" of sorts, with one module per stage:
 //!
 //! - [`stage1`]: does basic parsing, reading a string and returning a collection of lines with
-//! tokens (terms);
+//!   tokens (terms);
 //!
 //! - [`stage2`]: takes the lines and builds a tree from it, based on the indent;
 //!
 //! - [`stage3`]: "formalizes" the tree, with different types of lines, and guarantees terms in each
-//! line are valid;
+//!   line are valid;
 //!
-//! The data structures used here are all available in the [`data`] module.
+//! The `@functions` stage 3 knows about are in a [`Functions`] registry, where more can be added.
+//! After processing the document, it checks that every `@ref` points to an anchor (see [`refs`]).
+//!
+//! The data structures used here are all available in the [`data`] module, and errors from every
+//! stage are reported as a [`ParseError`].
+//!
+//! For big files, [`stream`] runs the stages one top-level node at a time as the document is read.
+//! For editors, [`incremental`] keeps a document up to date with edits to its source, only running
+//! the stages again on the part of it that changed.
+//!
+//! Tools that rewrite the source can use [`cst`] instead, a tree built over stage 1 that keeps
+//! every byte of it (comments and whitespace included).
+//!
+//! To go through a processed document, implement the traits in [`visit`] instead of matching on
+//! every kind of line and term by hand.
 
+pub mod cst;
 pub mod data;
+pub mod error;
+pub mod functions;
+pub mod incremental;
+pub mod refs;
 pub mod stage1;
 pub mod stage2;
+pub mod stream;
+pub mod visit;
 
 pub mod stage3;
 pub use stage3::{Document, Node as Node3, Term as Term3};
 
 pub use data::*;
+pub use error::{Diagnostics, ErrorKind, ParseError, ReadError};
+pub use functions::Functions;
 
-pub fn parse(input: &str) -> Result<Document, String> {
-    let s1 = stage1::parse(&input)?;
+pub fn parse(input: &str) -> Result<Document<'_>, ParseError> {
+    let s1 = stage1::parse(input)?;
     let s2 = stage2::parse(s1)?;
     let s3 = stage3::parse(s2)?;
     Ok(s3)
 }
+
+/// Parse the document with the given `functions` (instead of only the builtin ones), sending
+/// errors to `diag`.
+pub fn parse_with<'a>(
+    input: &'a str,
+    functions: &Functions,
+    diag: &mut Diagnostics,
+) -> Result<Document<'a>, ParseError> {
+    let s1 = stage1::parse_with(input, diag)?;
+    let s2 = stage2::parse_with(s1, diag)?;
+    stage3::parse_with(s2, functions, diag)
+}
+
+/// Parse the document without stopping at the first error.
+///
+/// Returns the (possibly partial) document, with bad lines replaced by [`stage3::Line::Error`],
+/// along with every error found.
+pub fn parse_recovering(input: &str) -> (Document<'_>, Vec<ParseError>) {
+    let mut diag = Diagnostics::recovering();
+
+    let result = parse_with(input, Functions::builtin(), &mut diag);
+
+    // the stages never fail when recovering, but just in case...
+    let doc = result.unwrap_or_else(|e| {
+        diag.report(e).ok();
+        Document {
+            header: Default::default(),
+            options: Default::default(),
+            nodes: Vec::new(),
+            footnotes: Vec::new(),
+        }
+    });
+
+    let mut errors = diag.into_errors();
+    errors.sort_by_key(|e| e.span.start);
+
+    (doc, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stage3::Line;
+
+    #[test]
+    fn recovering_parse() {
+        let input = "foo *bar\n      baz\nok @nope{x}\n  child\nfine\n";
+        assert!(parse(input).is_err());
+
+        let (doc, errors) = parse_recovering(input);
+        let kinds: Vec<_> = errors.iter().map(|e| (e.kind, e.span.line)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (ErrorKind::Unterminated, 1),
+                (ErrorKind::IndentLeap, 2),
+                (ErrorKind::UnknownFunction, 3),
+            ]
+        );
+
+        assert_eq!(doc.nodes.len(), 3);
+        assert!(matches!(doc.nodes[0].line, Line::Error(_)));
+        assert!(matches!(doc.nodes[0].children[0].line, Line::Text(_)));
+        assert!(matches!(doc.nodes[1].line, Line::Error(_)));
+        assert!(matches!(doc.nodes[1].children[0].line, Line::Text(_)));
+        assert!(matches!(doc.nodes[2].line, Line::Text(_)));
+    }
+
+    #[test]
+    fn recovering_from_bad_indents() {
+        let (doc, errors) = parse_recovering("%:indent 2\n\na\n      b\n      c\n        d\n  e\n");
+        let kinds: Vec<_> = errors.iter().map(|e| (e.kind, e.span.line)).collect();
+        assert_eq!(kinds, vec![(ErrorKind::IndentLeap, 4)]);
+
+        // b and c are both children of a, and d is a child of c
+        let a = &doc.nodes[0];
+        assert_eq!(doc.nodes.len(), 1);
+        assert_eq!(a.children.len(), 3);
+        assert_eq!(a.children[1].children.len(), 1);
+
+        let (doc, errors) = parse_recovering("  a\n  b\n    c\nd\n");
+        let kinds: Vec<_> = errors.iter().map(|e| (e.kind, e.span.line)).collect();
+        assert_eq!(kinds, vec![(ErrorKind::OrphanIndent, 1)]);
+        assert_eq!(doc.nodes.len(), 3);
+        assert_eq!(doc.nodes[1].children.len(), 1);
+    }
+
+    /// Inputs that used to crash the parser (or could, one day).
+    const MALFORMED: &[&str] = &[
+        "@foo{",
+        "@foo{bar",
+        "@foo(bar",
+        "@foo{bar\n\nbaz",
+        "@foo{a\\qb}",
+        "@foo#{ never closed",
+        "@foo##{ closed wrong }#",
+        "@{",
+        "@",
+        "@@@{{{",
+        "\\",
+        "foo \\q bar",
+        "*",
+        "*a",
+        "_a\\",
+        "`a\\",
+        "${",
+        "$${ x",
+        "$: \\",
+        "%:indent 0\n\n  foo",
+        "%:indent -1\n\nfoo",
+        "%:indent tab\n\n\t\t\tfoo",
+        "  foo",
+        "foo\n      bar",
+        "@code",
+        "@code{}{}{}",
+        "@code{ }",
+        "@code{*a*}",
+        "@table{foo}",
+        "@table{@{a}{b} @{c}}",
+        "@image{}{}{}",
+        "@dot{${x}}",
+        "foo @code{x}",
+        "@ref{}{}{}",
+        "@ref{@c{x}}",
+        "@c{}{}",
+        "$${x} trailing",
+        "- ",
+        "- [x",
+        "( )",
+        "\u{feff}\r\n\r",
+        "é@ü{ö",
+    ];
+
+    #[test]
+    fn malformed_inputs_dont_panic() {
+        for input in MALFORMED {
+            _ = parse(input);
+            let (_, errors) = parse_recovering(input);
+            for e in &errors {
+                _ = e.excerpt(input);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_inputs_dont_panic() {
+        let input = include_str!("../../examples/all.acr");
+
+        let boundaries = (0..input.len()).filter(|&i| input.is_char_boundary(i));
+        for i in boundaries.step_by(7) {
+            let (head, tail) = input.split_at(i);
+            _ = parse(head);
+            _ = parse_recovering(tail);
+
+            // and with some garbage in the middle
+            let mangled = format!("{head}@x{{\\{tail}");
+            _ = parse(&mangled);
+            _ = parse_recovering(&mangled);
+        }
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let input = format!("{}x{}", "@f{".repeat(10_000), "}".repeat(10_000));
+        let err = parse(&input).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::TooDeep);
+    }
+
+    #[test]
+    fn recovering_parse_without_errors() {
+        let input = include_str!("../../examples/all.acr");
+        let (doc, errors) = parse_recovering(input);
+        assert!(errors.is_empty());
+        assert_eq!(doc.nodes.len(), parse(input).unwrap().nodes.len());
+    }
+}
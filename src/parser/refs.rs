@@ -0,0 +1,129 @@
+//! Anchors and the `@ref`s that point to them.
+//!
+//! An anchor is either the ID of a heading or an `@anchor{id}` term, which points to the terms it's
+//! in. A [`RefIndex`] collects both as the nodes of a document are added to it, so it also works
+//! when they're read one at a time (see [`crate::parser::stream`]).
+
+use std::collections::HashMap;
+
+use crate::parser::stage3::{Document, HeadingLine, Node, Term, plain_text};
+use crate::parser::visit::{Visit, walk_term};
+use crate::parser::{ErrorKind, ParseError, Span, Spanned};
+
+/// The anchors of a document, and the refs to them.
+#[derive(Debug, Clone, Default)]
+pub struct RefIndex {
+    /// The text of what each anchor points to, by ID.
+    anchors: HashMap<String, String>,
+    /// The target of each ref, along with where it is.
+    refs: Vec<(String, Span)>,
+}
+
+impl RefIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index the nodes and footnotes of `doc`.
+    pub fn of(doc: &Document) -> Self {
+        let mut ret = Self::new();
+        Collector(&mut ret).visit_document(doc);
+        ret
+    }
+
+    pub fn add_node(&mut self, node: &Node) {
+        Collector(self).visit_node(node, 0);
+    }
+
+    /// Add loose terms, such as the content of a footnote.
+    pub fn add_terms(&mut self, terms: &[Spanned<Term>]) {
+        Collector(self).visit_terms(terms);
+    }
+
+    /// The text of what the anchor `id` points to, if there's such an anchor.
+    ///
+    /// When two anchors have the same ID, the first one wins.
+    pub fn text(&self, id: &str) -> Option<&str> {
+        self.anchors.get(id).map(|s| s.as_str())
+    }
+
+    /// An error for each ref whose target isn't an anchor, in the order they appear.
+    pub fn dangling(&self) -> Vec<ParseError> {
+        let mut errors: Vec<_> = self
+            .refs
+            .iter()
+            .filter(|(target, _)| !self.anchors.contains_key(target))
+            .map(|(target, span)| {
+                ParseError::new(
+                    ErrorKind::DanglingRef,
+                    *span,
+                    format!("no anchor or heading with the ID {target:?}"),
+                )
+            })
+            .collect();
+        errors.sort_by_key(|e| e.span.start);
+        errors
+    }
+}
+
+struct Collector<'i>(&'i mut RefIndex);
+
+impl Collector<'_> {
+    fn add_anchor(&mut self, id: &str, text: String) {
+        if !self.0.anchors.contains_key(id) {
+            self.0.anchors.insert(id.to_owned(), text.trim().to_owned());
+        }
+    }
+}
+
+impl<'a> Visit<'a> for Collector<'_> {
+    fn visit_heading(&mut self, heading: &'a HeadingLine<'_>) {
+        self.add_anchor(&heading.id, plain_text(&heading.content));
+        self.visit_terms(&heading.content);
+    }
+
+    fn visit_terms(&mut self, terms: &'a [Spanned<Term<'_>>]) {
+        for t in terms {
+            if let Term::Anchor(id) = &t.value {
+                self.add_anchor(id, plain_text(terms));
+            }
+            self.visit_term(t);
+        }
+    }
+
+    fn visit_term(&mut self, term: &'a Spanned<Term<'_>>) {
+        if let Term::Ref { target, .. } = &term.value {
+            self.0.refs.push((target.clone(), term.span));
+        }
+        walk_term(self, term);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, parse_recovering};
+
+    #[test]
+    fn anchors_and_refs() {
+        let input = concat!(
+            "@h{The *Intro*}\n",
+            "  see @ref{later} and @ref{the-intro}{here}@fn{or @ref{nowhere}}\n",
+            "  - *a point* @anchor{later}\n",
+            "@h{Later}\n",
+        );
+        let e = parse(input).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::DanglingRef);
+        assert_eq!(e.span.line, 2);
+
+        let (doc, errors) = parse_recovering(input);
+        let index = RefIndex::of(&doc);
+        assert_eq!(index.text("the-intro"), Some("The Intro"));
+        assert_eq!(index.text("later"), Some("a point"));
+        assert_eq!(index.text("later-2"), Some("Later"));
+        assert_eq!(index.text("nowhere"), None);
+
+        assert_eq!(index.dangling(), errors);
+        assert_eq!(errors.len(), 1);
+    }
+}
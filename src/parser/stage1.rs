@@ -1,58 +1,366 @@
 use crate::parser::{
-    BulletType, DocumentSt1, FuncCall, Indent, Line, StandardOptions, TaskFormat, TaskPrefix,
-    TaskState, Term,
+    BulletType, Date, Diagnostics, DocumentSt1, ErrorKind, FuncCall, Indent, IndentOrigin, KwArg,
+    Line, ParseError, ReadError, Span, Spanned, StandardOptions, TaskFormat, TaskPrefix, TaskState,
+    Term,
 };
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::io::BufRead;
 
-pub fn parse(document_str: &str) -> Result<DocumentSt1, String> {
+pub fn parse(document_str: &str) -> Result<DocumentSt1<'_>, ParseError> {
+    parse_with(document_str, &mut Diagnostics::strict())
+}
+
+/// Parse the document, sending errors to `diag`.
+///
+/// When recovering, lines that fail to parse become a line with a single [`Term::Error`].
+pub fn parse_with<'a>(
+    document_str: &'a str,
+    diag: &mut Diagnostics,
+) -> Result<DocumentSt1<'a>, ParseError> {
+    let (header, options, mut lexer) = parse_header(document_str, diag)?;
+
+    let mut lines = Vec::new();
+    while let Some(line) = lexer.next_line(&options, diag)? {
+        lines.push(line);
+    }
+
+    Ok(DocumentSt1 {
+        header,
+        options,
+        lines,
+    })
+}
+
+/// Parse the header, sending errors to `diag`.
+///
+/// Returns the remaining entries (the standard ones are taken out to the options), and a lexer
+/// positioned at the start of the body.
+pub fn parse_header<'a>(
+    document_str: &'a str,
+    diag: &mut Diagnostics,
+) -> Result<(HashMap<String, String>, StandardOptions, LineLexer<'a>), ParseError> {
     let mut p = DocParser::new(document_str);
 
     let mut header = HashMap::new();
-    while let Some((key, value)) = p.get_header_entry() {
+    let mut header_spans = HashMap::new();
+    while let Some((key, value, span)) = p.get_header_entry() {
+        header_spans.insert(key.clone(), span);
         header.insert(key, value);
     }
 
-    let indent = match header.remove("indent").as_ref().map(|s| s.trim()) {
-        Some("tab") => Indent::Tab,
-        Some(other) => other
-            .parse::<usize>()
-            .map(Indent::Space)
-            .map_err(|_| format!("failed to parse indent"))?,
-        None => Indent::Space(2),
-    };
+    p.skip_newlines();
 
-    let parse_tags = |tags: &str| -> Vec<String> {
-        tags.split(" ")
-            .filter(|x| !x.is_empty())
-            .map(String::from)
-            .collect()
+    let default_indent = StandardOptions::default().indent;
+    let (indent, indent_origin) = match header.remove("indent").as_ref().map(|s| s.trim()) {
+        Some("tab") => (Indent::Tab, IndentOrigin::Header),
+        Some(other) => match other.parse::<usize>() {
+            Ok(n) if n > 0 => (Indent::Space(n), IndentOrigin::Header),
+            _ => {
+                diag.report(ParseError::new(
+                    ErrorKind::BadHeaderValue,
+                    header_spans["indent"],
+                    format!("failed to parse indent {other:?} (expected \"tab\" or a number)"),
+                ))?;
+                (default_indent, IndentOrigin::Default)
+            }
+        },
+        None => match p.indent_stats(document_str.len()).indent() {
+            Some(indent) => (indent, IndentOrigin::Inferred),
+            None => (default_indent, IndentOrigin::Default),
+        },
     };
 
+    let parse_tags =
+        |tags: &str| -> Vec<String> { tags.split_whitespace().map(String::from).collect() };
+
+    // prose values can span several lines, but are a single line of text
+    let join_lines = |s: String| -> String { s.split_whitespace().collect::<Vec<_>>().join(" ") };
+
     let tags = header
         .remove("tags")
         .map(|s| parse_tags(&s))
-        .unwrap_or_else(|| Vec::new());
+        .unwrap_or_default();
+
+    let title = header.remove("title").map(join_lines).unwrap_or_default();
+    let author = header.remove("author").map(join_lines);
+    let description = header.remove("description").map(join_lines);
+    let lang = header.remove("lang").map(|s| s.trim().to_owned());
+
+    let date = match header.remove("date") {
+        Some(s) => match Date::parse(s.trim()) {
+            Some(d) => Some(d),
+            None => {
+                diag.report(ParseError::new(
+                    ErrorKind::BadHeaderValue,
+                    header_spans["date"],
+                    format!("invalid date {s:?} (expected YYYY-MM-DD)"),
+                ))?;
+                None
+            }
+        },
+        None => None,
+    };
 
-    let title = header.remove("title").unwrap_or_else(String::new);
+    let mut katex_macros = Vec::new();
+    if let Some(s) = header.remove("katex-macros") {
+        for line in s.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let (name, expansion) = line.split_once(is::inline_whitespace).unwrap_or((line, ""));
+            if name.len() < 2 || !name.starts_with('\\') {
+                diag.report(ParseError::new(
+                    ErrorKind::BadHeaderValue,
+                    header_spans["katex-macros"],
+                    format!("invalid KaTeX macro {line:?} (expected `\\name expansion`)"),
+                ))?;
+                continue;
+            }
+            katex_macros.push((name.to_owned(), expansion.trim().to_owned()));
+        }
+    }
 
     let options = StandardOptions {
         indent,
+        indent_origin,
         tags,
         title,
+        author,
+        date,
+        lang,
+        description,
+        katex_macros,
     };
 
-    p.skip_newlines();
+    Ok((header, options, LineLexer { p }))
+}
 
-    let mut lines = Vec::new();
-    while let Some(line) = p.get_line(&options)? {
-        lines.push(line);
+/// Reads the body of a document one line at a time.
+#[derive(Debug, Clone)]
+pub struct LineLexer<'a> {
+    p: DocParser<'a>,
+}
+
+impl<'a> LineLexer<'a> {
+    /// Start reading at `offset`, which must be the start of line number `line`.
+    pub fn at(source: &'a str, offset: usize, line: u32) -> Self {
+        Self {
+            p: DocParser::at(source, offset, line),
+        }
     }
 
-    Ok(DocumentSt1 {
-        header,
-        options,
-        lines,
-    })
+    /// The offset where the next line starts.
+    pub fn offset(&self) -> usize {
+        self.p.offset
+    }
+
+    /// The number of the next line.
+    pub fn line(&self) -> u32 {
+        self.p.line
+    }
+
+    /// Read the next line, or `None` at the end of the source.
+    ///
+    /// When recovering, a line that fails to parse becomes a line with a single [`Term::Error`].
+    pub fn next_line(
+        &mut self,
+        options: &StandardOptions,
+        diag: &mut Diagnostics,
+    ) -> Result<Option<Line<'a>>, ParseError> {
+        match self.p.get_line(options) {
+            Err(e) => {
+                diag.report(e.clone())?;
+                Ok(Some(self.p.skip_bad_line(options, e)))
+            }
+            x => x,
+        }
+    }
+}
+
+/// How much of the body is read ahead when streaming, to infer the indent unit from.
+const INFER_AHEAD: usize = 64 * 1024;
+
+/// Reads the lines of a document from `R` as they're needed, so only a few of them are kept in
+/// memory at a time.
+///
+/// A line is only lexed again with more input when lexing it reached the end of what was read
+/// (e.g. an argument that wasn't closed yet), so the lines are the same as when lexing the whole
+/// document at once. The exception is the indent unit: without an `%:indent` header entry, it's
+/// inferred from the start of the body only.
+#[derive(Debug)]
+pub struct LineReader<R> {
+    reader: R,
+    /// The text read so far (but not released), of which `buffer[start..]` wasn't lexed yet.
+    buffer: String,
+    start: usize,
+    /// The offset of `buffer` in the source.
+    base: usize,
+    line: u32,
+    eof: bool,
+    /// Set when lexing tries to look past the end of `buffer`.
+    hit_end: Cell<bool>,
+}
+
+impl<R: BufRead> LineReader<R> {
+    /// Read the header, sending errors to `diag`.
+    ///
+    /// Returns the remaining entries (like [`parse_header`]) along with the reader, positioned at
+    /// the start of the body.
+    pub fn new(
+        reader: R,
+        diag: &mut Diagnostics,
+    ) -> Result<(HashMap<String, String>, StandardOptions, Self), ReadError> {
+        let mut r = Self {
+            reader,
+            buffer: String::new(),
+            start: 0,
+            base: 0,
+            line: 1,
+            eof: false,
+            hit_end: Cell::new(false),
+        };
+
+        // the header ends before the first line that can't be a part of it
+        loop {
+            let line_start = r.buffer.len();
+            if !r.read_line()? {
+                break;
+            }
+
+            let line = r.buffer[line_start..].trim_start_matches('\u{feff}');
+            let in_header = line.starts_with("%:")
+                || line.starts_with(is::inline_whitespace)
+                || line.trim().is_empty();
+            if !in_header {
+                break;
+            }
+        }
+        while r.buffer.len() < INFER_AHEAD && r.read_line()? {}
+
+        let (header, options, lexer) = parse_header(&r.buffer, diag)?;
+        r.start = lexer.offset();
+        r.line = lexer.line();
+
+        Ok((header, options, r))
+    }
+
+    /// Read a line from the input into the buffer. Returns `false` at the end of the input.
+    fn read_line(&mut self) -> Result<bool, ReadError> {
+        if self.eof {
+            return Ok(false);
+        }
+
+        let n = self.reader.read_line(&mut self.buffer)?;
+        self.eof = n == 0;
+        Ok(!self.eof)
+    }
+
+    /// Read the next line, or `None` at the end of the input.
+    ///
+    /// When recovering, a line that fails to parse becomes a line with a single [`Term::Error`].
+    pub fn next_line(
+        &mut self,
+        options: &StandardOptions,
+        diag: &mut Diagnostics,
+    ) -> Result<Option<Line<'static>>, ReadError> {
+        if self.start == self.buffer.len() {
+            self.read_line()?;
+        }
+
+        loop {
+            let offset = self.base + self.start;
+            let hit_end = (!self.eof).then_some(&self.hit_end);
+            self.hit_end.set(false);
+            let mut lexer = LineLexer {
+                p: DocParser::partial(&self.buffer[self.start..], offset, self.line, hit_end),
+            };
+
+            // errors are only reported once the line is done
+            let mut line_diag = Diagnostics::recovering();
+            let line = lexer.next_line(options, &mut line_diag)?;
+
+            if self.hit_end.get() {
+                // The line might go on, so read more of it and try again. Reading as much as is
+                // buffered each time keeps long lines from taking quadratic time.
+                let target = 2 * (self.buffer.len() - self.start);
+                while self.buffer.len() - self.start < target && self.read_line()? {}
+                continue;
+            }
+
+            let line = line.map(Line::into_owned);
+            self.start += lexer.offset() - offset;
+            self.line = lexer.line();
+
+            for e in line_diag.into_errors() {
+                diag.report(e)?;
+            }
+
+            return Ok(line);
+        }
+    }
+
+    /// Let go of the text before `offset` (which must be the start of a line), as it won't be
+    /// needed anymore.
+    pub fn release(&mut self, offset: usize) {
+        let n = offset.saturating_sub(self.base).min(self.start);
+
+        // only move the text around once there's a good amount of it to drop
+        if n > 0 && n >= self.buffer.len() / 2 {
+            self.buffer.drain(..n);
+            self.base += n;
+            self.start -= n;
+        }
+    }
+
+    /// Show the line where `error` happened (see [`ParseError::excerpt`]), if it wasn't released
+    /// yet.
+    pub fn excerpt(&self, error: &ParseError) -> Option<String> {
+        let mut e = error.clone();
+        e.span.start = e.span.start.checked_sub(self.base)?;
+        e.span.end = e.span.end.saturating_sub(self.base);
+        Some(e.excerpt(&self.buffer))
+    }
+}
+
+/// Counts of the leading whitespace of a run of lines, used to infer the indent unit.
+///
+/// Counts from consecutive runs can be merged, so they can be kept per region of a document.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndentStats {
+    pub tab_lines: usize,
+    pub space_lines: usize,
+    /// The GCD of the amounts of leading spaces.
+    pub spaces_gcd: usize,
+}
+
+impl IndentStats {
+    /// Count the lines between `start` (the start of line number `line`) and `end`.
+    pub fn of(source: &str, start: usize, line: u32, end: usize) -> Self {
+        DocParser::at(source, start, line).indent_stats(end)
+    }
+
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            tab_lines: self.tab_lines + other.tab_lines,
+            space_lines: self.space_lines + other.space_lines,
+            spaces_gcd: gcd(self.spaces_gcd, other.spaces_gcd),
+        }
+    }
+
+    /// Uses tabs if most indented lines start with a tab, otherwise the GCD of the amounts of
+    /// leading spaces. Returns `None` if no line is indented.
+    pub fn indent(&self) -> Option<Indent> {
+        if self.tab_lines > self.space_lines {
+            Some(Indent::Tab)
+        } else if self.space_lines > 0 {
+            Some(Indent::Space(self.spaces_gcd))
+        } else {
+            None
+        }
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
 }
 
 macro_rules! literal_if {
@@ -66,7 +374,7 @@ macro_rules! literal_if {
 
 macro_rules! make_parse_math {
     ($fn_name:ident, expect_start: $expect_start:expr, end_on_bracket: $end_on_bracket:tt) => {
-        pub fn $fn_name(&mut self) -> Result<Option<String>, String> {
+        pub fn $fn_name(&mut self) -> Result<Option<Cow<'a, str>>, ParseError> {
             let mut p = self.clone();
 
             for &c in $expect_start.iter() {
@@ -77,29 +385,32 @@ macro_rules! make_parse_math {
 
             let mut bracket_stack_size: usize = 1;
 
-            let mut ret = String::new();
+            let mut ret = Text::new(&p);
             'blk: loop {
                 match p.peek() {
                     Some('{') => {
                         bracket_stack_size += 1;
-                        ret.push('{');
-                        p.step();
+                        ret.take(&mut p);
                     }
                     Some('}') => {
                         if bracket_stack_size > 0 {
                             bracket_stack_size -= 1;
-                            p.step();
                             if bracket_stack_size == 0 {
+                                p.step();
                                 break 'blk;
                             } else {
-                                ret.push('}');
+                                ret.take(&mut p);
                             }
                         } else {
                             literal_if!($end_on_bracket {
                                 p.step();
                                 break 'blk;
                             } else {
-                                return Err("too many closing brackets!".into());
+                                return Err(p.error_since(
+                                    self,
+                                    ErrorKind::UnbalancedBrackets,
+                                    "too many closing brackets",
+                                ));
                             })
                         }
                     }
@@ -109,16 +420,16 @@ macro_rules! make_parse_math {
 
                         match p2.peek() {
                             Some('\n') | None => {
-                                return Err(
-                                    "line abruptly ended!! while parsing inline math escape".into()
-                                );
+                                return Err(p2.error_since(
+                                    self,
+                                    ErrorKind::Unterminated,
+                                    "line ended abruptly while parsing math escape",
+                                ));
                             }
-                            Some(c) => {
+                            Some(_) => {
                                 // just forward it all to the latex parser :)
-                                ret.push('\\');
-                                ret.push(c);
-                                p2.step();
-                                p = p2;
+                                ret.take(&mut p);
+                                ret.take(&mut p);
                             }
                         }
                     }
@@ -130,12 +441,15 @@ macro_rules! make_parse_math {
                             })
                         }
 
-                        ret.push(c);
-                        p.step();
+                        ret.take(&mut p);
                     }
                     None => {
                         if bracket_stack_size > 0 {
-                            return Err("mismatched curly brackets!! while parsing inline math".into());
+                            return Err(p.error_since(
+                                self,
+                                ErrorKind::UnbalancedBrackets,
+                                "mismatched curly brackets while parsing math",
+                            ));
                         } else {
                             break 'blk;
                         }
@@ -144,34 +458,182 @@ macro_rules! make_parse_math {
             }
 
             *self = p;
-            Ok(Some(ret))
+            Ok(Some(ret.finish()))
         }
     }
 }
 
+/// How deep function arguments can be nested, to avoid running out of stack.
+const MAX_NESTING: u32 = 64;
+
 #[derive(Debug, Clone)]
 struct DocParser<'a> {
     line: u32,
     column: u32,
+    offset: usize,
+    depth: u32,
+    /// Which emphasis delimiters (`*` and `_`) are currently open.
+    in_bold: bool,
+    in_italics: bool,
     source: &'a str,
+    /// Set when peeking past the end of `source`, if it's only a part of the document.
+    hit_end: Option<&'a Cell<bool>>,
     // TODO: figure out a way to make this smaller?
 }
 
+/// Text read from the source, borrowed from it for as long as it's a verbatim slice of it.
+///
+/// Adding anything else (an escaped character, or a normalized line break) switches to an owned
+/// copy.
+struct Text<'a> {
+    /// The source, from where the text starts.
+    source: &'a str,
+    start: usize,
+    /// How much of `source` was taken, while still borrowing.
+    len: usize,
+    owned: Option<String>,
+}
+
+impl<'a> Text<'a> {
+    fn new(p: &DocParser<'a>) -> Self {
+        Self {
+            source: p.source,
+            start: p.offset,
+            len: 0,
+            owned: None,
+        }
+    }
+
+    /// Add the character at the parser's position and step over it.
+    fn take(&mut self, p: &mut DocParser<'a>) {
+        let Some(c) = p.peek() else {
+            return;
+        };
+
+        let contiguous = p.offset == self.start + self.len && !p.source.starts_with('\r');
+        p.step();
+        if self.owned.is_none() && contiguous {
+            self.len = p.offset - self.start;
+        } else {
+            self.push(c);
+        }
+    }
+
+    /// Add a character that doesn't come from the source as-is.
+    fn push(&mut self, c: char) {
+        let borrowed = &self.source[..self.len];
+        self.owned
+            .get_or_insert_with(|| borrowed.to_owned())
+            .push(c);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0 && self.owned.as_ref().is_none_or(|s| s.is_empty())
+    }
+
+    fn finish(self) -> Cow<'a, str> {
+        match self.owned {
+            Some(s) => Cow::Owned(s),
+            None => Cow::Borrowed(&self.source[..self.len]),
+        }
+    }
+}
+
 impl<'a> DocParser<'a> {
     /// Create a new instance of the parser.
     ///
     /// Initializes the line and column to 1.
+    ///
+    /// A leading UTF-8 BOM is skipped (offsets still count it, so spans match the given string).
     pub fn new(source: &'a str) -> Self {
+        let (source, offset) = match source.strip_prefix('\u{feff}') {
+            Some(rest) => (rest, '\u{feff}'.len_utf8()),
+            None => (source, 0),
+        };
+
         Self {
             source,
             line: 1,
             column: 1,
+            offset,
+            depth: 0,
+            in_bold: false,
+            in_italics: false,
+            hit_end: None,
+        }
+    }
+
+    /// Start at `offset`, which must be the start of line number `line`.
+    fn at(source: &'a str, offset: usize, line: u32) -> Self {
+        Self::partial(&source[offset..], offset, line, None)
+    }
+
+    /// Start at the start of `rest`, a part of the source that starts at `offset` (on line number
+    /// `line`) and might not go all the way to its end.
+    ///
+    /// `hit_end` gets set when trying to look past the end of `rest`.
+    fn partial(rest: &'a str, offset: usize, line: u32, hit_end: Option<&'a Cell<bool>>) -> Self {
+        Self {
+            source: rest,
+            line,
+            column: 1,
+            offset,
+            depth: 0,
+            in_bold: false,
+            in_italics: false,
+            hit_end,
+        }
+    }
+
+    /// Get the span between the position of `start` and the current position.
+    fn span_since(&self, start: &Self) -> Span {
+        Span {
+            start: start.offset,
+            end: self.offset,
+            line: start.line,
+            column: start.column,
+        }
+    }
+
+    /// Make an error spanning from the position of `start` to the current position.
+    fn error_since(&self, start: &Self, kind: ErrorKind, message: impl Into<String>) -> ParseError {
+        ParseError::new(kind, self.span_since(start), message)
+    }
+
+    /// Make an error pointing to the character under the cursor.
+    fn error_here(&self, kind: ErrorKind, message: impl Into<String>) -> ParseError {
+        let mut p = self.clone();
+        p.step();
+        p.error_since(self, kind, message)
+    }
+
+    /// Make an error for input that couldn't be parsed as any term.
+    fn unparsable_error(&self) -> ParseError {
+        match self.peek() {
+            Some('\\') => {
+                let mut p = self.clone();
+                p.step();
+                p.step();
+                p.error_since(self, ErrorKind::BadEscape, "bad escape sequence")
+            }
+            _ => self.error_here(ErrorKind::TrailingInput, "failed to parse entire line"),
         }
     }
 
     /// Get the character currently under the cursor.
+    ///
+    /// Line endings are normalized: `\r\n` and a lone `\r` are both seen as `\n`.
     fn peek(&self) -> Option<char> {
-        self.source.chars().next()
+        match self.source.chars().next() {
+            Some('\r') => Some('\n'),
+            Some(c) => Some(c),
+            None => {
+                if let Some(hit_end) = self.hit_end {
+                    hit_end.set(true);
+                }
+                None
+            }
+        }
     }
 
     /// Advance forward (to the next character).
@@ -180,6 +642,15 @@ impl<'a> DocParser<'a> {
             return;
         };
 
+        let len = if self.source.starts_with("\r\n") {
+            2
+        } else if c == '\n' {
+            // may be a lone `\r`
+            1
+        } else {
+            c.len_utf8()
+        };
+
         if c == '\n' {
             self.line += 1;
             self.column = 1;
@@ -187,7 +658,8 @@ impl<'a> DocParser<'a> {
             self.column += 1;
         }
 
-        self.source = &self.source[c.len_utf8()..];
+        self.offset += len;
+        self.source = &self.source[len..];
     }
 
     /// Get the current character and advance forward.
@@ -208,25 +680,32 @@ impl<'a> DocParser<'a> {
 
     fn count_while(&mut self, pred: impl Fn(char) -> bool) -> usize {
         let mut i = 0;
-        while let Some(_) = self.peek().filter(|&c| pred(c)) {
+        while self.peek().filter(|&c| pred(c)).is_some() {
             i += 1;
             self.step();
         }
         i
     }
 
-    fn collect(&mut self, pred: impl Fn(char) -> bool) -> String {
-        let mut ret = String::new();
-        while let Some(c) = self.peek().filter(|&c| pred(c)) {
-            ret.push(c);
+    /// Get the source text between the position of `start` and the current position.
+    fn slice_since(&self, start: &Self) -> &'a str {
+        &start.source[..self.offset - start.offset]
+    }
+
+    /// Skip characters while `pred` holds, returning the skipped text.
+    ///
+    /// `pred` must not accept line breaks, so the text is always a verbatim slice of the source.
+    fn collect(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let start = self.clone();
+        while self.peek().filter(|&c| pred(c)).is_some() {
             self.step();
         }
-        ret
+        self.slice_since(&start)
     }
 
     /// Does the same as [`Self::collect`], but only returns `Some` if the amount of characters collected
     /// is at least `n`.
-    fn collect_at_least(&mut self, n: usize, pred: impl Fn(char) -> bool) -> Option<String> {
+    fn collect_at_least(&mut self, n: usize, pred: impl Fn(char) -> bool) -> Option<&'a str> {
         Some(self.collect(pred)).filter(|x| x.len() >= n)
     }
 
@@ -246,6 +725,13 @@ impl<'a> DocParser<'a> {
         _ = self.count_while(is::inline_whitespace);
     }
 
+    /// Whether there's only whitespace left in the current line.
+    fn rest_is_blank(&self) -> bool {
+        let mut p = self.clone();
+        p.skip_inline_whitespace();
+        matches!(p.peek(), Some('\n') | None)
+    }
+
     fn expect_line_end(&mut self) -> bool {
         match self.peek() {
             Some('\n') | None => {
@@ -256,112 +742,112 @@ impl<'a> DocParser<'a> {
         }
     }
 
-    pub fn get_header_entry(&mut self) -> Option<(String, String)> {
+    /// Parse a `%:key value` header entry, returning the key, the value and the span of the value.
+    ///
+    /// The value can continue on the following lines, as long as they're indented. These are
+    /// joined with newlines, without their indentation:
+    ///
+    /// ```text
+    /// %:description A long description
+    ///   that spans two lines
+    /// ```
+    pub fn get_header_entry(&mut self) -> Option<(String, String, Span)> {
         let mut p = self.clone();
 
         p.expect_and_skip('%')?;
         p.expect_and_skip(':')?;
 
-        let key = p.collect(|c| !is::inline_whitespace(c));
-        if key.len() == 0 {
-            return None;
-        }
-
-        if p.count_while(is::inline_whitespace) == 0 {
+        let key = p
+            .collect(|c| !is::inline_whitespace(c) && c != '\n')
+            .to_owned();
+        if key.is_empty() {
             return None;
         }
 
-        let value = p.collect(|c| c != '\n');
-        if value.len() == 0 {
+        let has_space = p.count_while(is::inline_whitespace) > 0;
+        if !has_space && p.peek() != Some('\n') {
             return None;
         }
 
+        let value_start = p.clone();
+        let mut value = p.collect(|c| c != '\n').to_owned();
+        let mut value_end = p.clone();
         p.expect_and_skip('\n')?;
 
-        *self = p;
-        Some((key, value))
-    }
-
-    /// Make error message for a failed parse.
-    fn make_parse_error_msg(
-        start_p: &DocParser,
-        err_p: &DocParser,
-        msg: &str,
-        terms_so_far: &[Term],
-    ) -> String {
-        // TODO: improve this. not efficient at all... maybe add a DocParser::has_same_ptr to
-        // compare start_p and err_p?
+        loop {
+            let mut p2 = p.clone();
+            if p2.count_while(is::inline_whitespace) == 0 {
+                break;
+            }
 
-        let mut ret = String::new();
-        ret.push_str(msg);
-        ret.push('\n');
+            let cont = p2.collect(|c| c != '\n');
+            if cont.trim().is_empty() {
+                break;
+            }
+            let cont_end = p2.clone();
+            if p2.expect_and_skip('\n').is_none() {
+                break;
+            }
 
-        fn seek_to_line_end(s: &str) -> &str {
-            s.find('\n').map(|i| &s[..i]).unwrap_or(s)
+            if !value.is_empty() {
+                value.push('\n');
+            }
+            value.push_str(cont.trim_end());
+            value_end = cont_end;
+            p = p2;
         }
 
-        let line_to_end = seek_to_line_end(start_p.source);
-        let err_margin = line_to_end.len() - seek_to_line_end(err_p.source).len();
-
-        let prefix = format!("{:2} | ", start_p.line);
-        ret.push_str(&prefix);
-
-        ret.push_str(line_to_end);
-        ret.push('\n');
-
-        for _ in 0..(prefix.len() + err_margin) {
-            ret.push(' ');
+        if value.is_empty() {
+            return None;
         }
+        let span = value_end.span_since(&value_start);
 
-        ret.push_str("^\n");
-
-        ret.push_str(&format!(
-            "Succesfully parsed before (in the line): {terms_so_far:?}"
-        ));
-
-        ret
+        *self = p;
+        Some((key, value, span))
     }
 
-    pub fn get_term(&mut self, multiline: bool) -> Result<Option<Term>, String> {
-        Ok(loop {
-            if let Some(()) = self.get_inline_whitespace() {
-                break Some(Term::Space);
-            } else if let Some(_) = self.get_comment() {
-                // do nothing
+    pub fn get_term(&mut self, multiline: bool) -> Result<Option<Spanned<Term<'a>>>, ParseError> {
+        loop {
+            let start = self.clone();
+
+            let term = if let Some(()) = self.get_inline_whitespace() {
+                Term::Space
+            } else if self.get_comment().is_some() {
+                continue;
             } else if let Some(x) = self.get_symmetric_delimiter('`')? {
-                break Some(Term::InlineCode(x));
-            } else if let Some(x) = self.get_symmetric_delimiter('*')? {
-                break Some(Term::InlineBold(x));
-            } else if let Some(x) = self.get_symmetric_delimiter('_')? {
-                break Some(Term::InlineItalics(x));
+                Term::InlineCode(x)
+            } else if let Some(x) = self.get_emphasis('*')? {
+                Term::InlineBold(x)
+            } else if let Some(x) = self.get_emphasis('_')? {
+                Term::InlineItalics(x)
             } else if let Some(x) = self.get_tag() {
-                break Some(Term::Tag(x));
-            } else if let Some(x) = self.get_list_or_call() {
-                break Some(match x {
-                    (Some(name), args) => Term::FuncCall(FuncCall { name, args }),
-                    (None, args) => Term::List(args),
-                });
+                Term::Tag(x.into())
+            } else if let Some(x) = self.get_list_or_call()? {
+                x
             } else if let Some(x) = self.get_inline_math_a()? {
-                break Some(Term::InlineMath(x));
+                Term::InlineMath(x)
             } else if let Some(x) = self.get_inline_math_b()? {
-                break Some(Term::InlineMath(x));
+                Term::InlineMath(x)
             } else if let Some(x) = self.get_display_math_a()? {
-                break Some(Term::DisplayMath(x));
+                Term::DisplayMath(x)
             } else if let Some(x) = self.get_display_math_b()? {
-                break Some(Term::DisplayMath(x));
+                Term::DisplayMath(x)
             } else if let Some(x) = self.get_maybe_delim() {
-                break Some(Term::MaybeDelim(x));
+                Term::MaybeDelim(x)
             } else if let Some(x) = self.get_word_part() {
-                break Some(Term::Word(x));
+                Term::Word(x)
             } else if matches!(self.peek(), Some('\n')) && multiline {
                 self.next();
+                continue;
             } else {
-                break None;
-            }
-        })
+                return Ok(None);
+            };
+
+            return Ok(Some(Spanned::new(term, self.span_since(&start))));
+        }
     }
 
-    pub fn get_line(&mut self, options: &StandardOptions) -> Result<Option<Line>, String> {
+    pub fn get_line(&mut self, options: &StandardOptions) -> Result<Option<Line<'a>>, ParseError> {
         if self.peek().is_none() {
             return Ok(None);
         }
@@ -369,12 +855,35 @@ impl<'a> DocParser<'a> {
         let mut p = self.clone();
 
         let indent = match options.indent {
-            Indent::Tab => p.count_while(|c| c == '\t'),
+            Indent::Tab => {
+                let count = p.count_while(|c| c == '\t');
+
+                let mut p2 = p.clone();
+                if p2.count_while(|c| c == ' ') > 0 && p2.peek() == Some('\t') {
+                    return Err(p.error_here(
+                        ErrorKind::MixedIndent,
+                        "space in indentation, but this document is indented with tabs",
+                    ));
+                }
+
+                count
+            }
             Indent::Space(n) => {
                 let count = p.count_while(|c| c == ' ');
-                if count % n != 0 {
-                    return Err(format!(
-                        "bad indent: {count} spaces is not divisible by indent size {n}"
+                if p.peek() == Some('\t') && !p.rest_is_blank() {
+                    return Err(p.error_here(
+                        ErrorKind::MixedIndent,
+                        format!(
+                            "tab in indentation, but this document is indented with {n} spaces"
+                        ),
+                    ));
+                }
+
+                if !count.is_multiple_of(n) {
+                    return Err(p.error_since(
+                        self,
+                        ErrorKind::BadIndent,
+                        format!("bad indent: {count} spaces is not divisible by indent size {n}"),
                     ));
                 } else {
                     count / n
@@ -384,36 +893,94 @@ impl<'a> DocParser<'a> {
 
         let mut terms = Vec::new();
 
+        let start = p.clone();
         if let Some(pfx) = p.get_bullet_prefix() {
-            terms.push(Term::BulletPrefix(pfx));
+            terms.push(Spanned::new(Term::BulletPrefix(pfx), p.span_since(&start)));
         }
 
+        let start = p.clone();
         if let Some(pfx) = p.get_task_prefix() {
-            terms.push(Term::TaskPrefix(pfx));
+            terms.push(Spanned::new(Term::TaskPrefix(pfx), p.span_since(&start)));
         }
 
-        loop {
-            match p.get_term(false) {
-                Ok(Some(t)) => terms.push(t),
-                Ok(None) => break,
-                Err(e) => return Err(Self::make_parse_error_msg(self, &p, &e, &terms)),
-            }
+        while let Some(t) = p.get_term(false)? {
+            terms.push(t);
         }
 
         // skip trailing whitespace
         p.skip_inline_whitespace();
 
+        let span = p.span_since(self);
         if !p.expect_line_end() {
-            return Err(Self::make_parse_error_msg(
-                self,
-                &p,
-                "failed to parse entire line",
-                &terms,
-            ));
+            return Err(p.unparsable_error());
         }
 
         *self = p;
-        Ok(Some(Line { indent, terms }))
+        Ok(Some(Line {
+            indent,
+            terms,
+            span,
+        }))
+    }
+
+    /// Count the leading whitespace of the lines from here up to `end`, to guess the indent unit.
+    fn indent_stats(&self, end: usize) -> IndentStats {
+        let mut p = self.clone();
+        let mut tab_lines = 0;
+        let mut space_lines = 0;
+        let mut spaces_gcd = 0;
+
+        while p.offset < end && p.peek().is_some() {
+            let tabs = p.count_while(|c| c == '\t');
+            let spaces = p.count_while(|c| c == ' ');
+            if !p.rest_is_blank() {
+                if tabs > 0 {
+                    tab_lines += 1;
+                } else if spaces > 0 {
+                    space_lines += 1;
+                    spaces_gcd = gcd(spaces_gcd, spaces);
+                }
+            }
+
+            // skip the rest of the line, along with the inside of multi-line terms (like the
+            // contents of a code block)
+            while let Ok(Some(_)) = p.get_term(false) {}
+            p.count_while(|c| c != '\n');
+            p.step();
+        }
+
+        IndentStats {
+            tab_lines,
+            space_lines,
+            spaces_gcd,
+        }
+    }
+
+    /// Skip the current line entirely, turning it into an error line.
+    ///
+    /// Its indent is still counted (rounding down, if needed) so it can be placed in the tree.
+    fn skip_bad_line(&mut self, options: &StandardOptions, error: ParseError) -> Line<'a> {
+        let start = self.clone();
+
+        let indent = match options.indent {
+            Indent::Tab => self.count_while(|c| c == '\t'),
+            Indent::Space(n) => self.count_while(|c| c == ' ') / n,
+        };
+
+        let content_start = self.clone();
+        self.count_while(|c| c != '\n');
+        let span = self.span_since(&start);
+        let terms = vec![Spanned::new(
+            Term::Error(error),
+            self.span_since(&content_start),
+        )];
+        self.step();
+
+        Line {
+            indent,
+            terms,
+            span,
+        }
     }
 
     pub fn get_maybe_delim(&mut self) -> Option<char> {
@@ -428,11 +995,11 @@ impl<'a> DocParser<'a> {
         Some(c)
     }
 
-    pub fn get_word_part(&mut self) -> Option<String> {
+    pub fn get_word_part(&mut self) -> Option<Cow<'a, str>> {
         let mut p = self.clone();
 
         let mut first_char = true;
-        let mut ret = String::new();
+        let mut ret = Text::new(&p);
         'blk: loop {
             match p.peek() {
                 Some('\\') => {
@@ -449,59 +1016,60 @@ impl<'a> DocParser<'a> {
                         _ => break 'blk,
                     }
                 }
+                // stop before the end of an open emphasis
+                Some('*') if p.in_bold => break 'blk,
+                Some('_') if p.in_italics => break 'blk,
+                // a function call can start in the middle of a word (e.g. `x@sup{2}`)
+                Some('@') if !first_char => break 'blk,
                 Some(c) if is::word_char(c) => {
-                    ret.push(c);
-                    p.step();
+                    ret.take(&mut p);
                     first_char = false;
                 }
                 Some(c) if first_char && matches!(c, '$' | '%' | '*' | '_' | '`') => {
-                    ret.push(c);
-                    p.step();
+                    ret.take(&mut p);
                     first_char = false;
                 }
                 _ => break 'blk,
             }
         }
 
-        if ret.len() > 0 {
+        if !ret.is_empty() {
             *self = p;
-            Some(ret)
+            Some(ret.finish())
         } else {
             None
         }
     }
 
-    fn get_ident(&mut self) -> Option<String> {
+    fn get_ident(&mut self) -> Option<&'a str> {
         let mut p = self.clone();
-        let mut ret = String::new();
 
-        ret.extend(p.collect_at_least(1, |c| c.is_ascii_alphabetic())?.chars());
-        ret.extend(p.collect(|c| c.is_ascii_alphanumeric()).chars());
+        p.collect_at_least(1, |c| c.is_ascii_alphabetic())?;
+        p.collect(|c| c.is_ascii_alphanumeric());
 
+        let ret = p.slice_since(self);
         *self = p;
         Some(ret)
     }
 
-    pub fn get_list_or_call(&mut self) -> Option<(Option<String>, Vec<Vec<Term>>)> {
-        fn get_escaped_char(parser: &mut DocParser) -> Option<char> {
-            let mut p = parser.clone();
-            p.expect_and_skip('\\')?;
-            match p.peek() {
-                Some(c) if is::escapable_char(c) => {
-                    p.step();
-                    *parser = p;
-                    Some(c)
-                }
-                Some(_) | None => panic!("TODO: error message about bad escape char"),
-            }
-        }
-
-        fn get_raw_arg(parser: &mut DocParser) -> Option<String> {
+    /// Get a function call (`@name{...}`) or a list (`@{...}`).
+    ///
+    /// Function calls can start with keyword arguments between square brackets, as in
+    /// `@name[key=value key2={value 2}]{...}`.
+    pub fn get_list_or_call(&mut self) -> Result<Option<Term<'a>>, ParseError> {
+        /// Get a raw argument, returning its contents and their span.
+        fn get_raw_arg<'a>(
+            parser: &mut DocParser<'a>,
+        ) -> Result<Option<(Cow<'a, str>, Span)>, ParseError> {
             let mut p = parser.clone();
-            let mut ret = String::new();
 
             let hash_count = p.count_while(|c| c == '#');
-            p.expect_and_skip('{')?;
+            if p.expect_and_skip('{').is_none() {
+                return Ok(None);
+            }
+            let start = p.clone();
+            let mut ret = Text::new(&p);
+            let mut end = p.clone();
 
             fn expect_end(parser: &mut DocParser, hash_count: usize) -> bool {
                 let mut p = parser.clone();
@@ -518,69 +1086,176 @@ impl<'a> DocParser<'a> {
             }
 
             while !expect_end(&mut p, hash_count) {
-                let c = p.peek()?;
-                ret.push(c);
-                p.step();
+                if p.peek().is_none() {
+                    let closing = "#".repeat(hash_count);
+                    return Err(p.error_since(
+                        parser,
+                        ErrorKind::Unterminated,
+                        format!("missing \"}}{closing}\" at the end of raw argument"),
+                    ));
+                }
+                ret.take(&mut p);
+                end = p.clone();
             }
 
             *parser = p;
-            Some(ret)
+            Ok(Some((ret.finish(), end.span_since(&start))))
         }
 
-        fn get_arg(parser: &mut DocParser, delim: (char, char)) -> Option<Vec<Term>> {
+        fn get_arg<'a>(
+            parser: &mut DocParser<'a>,
+            delim: (char, char),
+        ) -> Result<Option<Vec<Spanned<Term<'a>>>>, ParseError> {
             let mut p = parser.clone();
             let (dl, dr) = delim;
 
-            p.expect_and_skip(dl)?;
+            if p.expect_and_skip(dl).is_none() {
+                return Ok(None);
+            }
+
+            p.depth += 1;
+            if p.depth > MAX_NESTING {
+                return Err(p.error_since(
+                    parser,
+                    ErrorKind::TooDeep,
+                    format!("arguments nested more than {MAX_NESTING} levels deep"),
+                ));
+            }
 
             let mut terms = Vec::new();
             loop {
-                match p.get_term(true) {
-                    Ok(Some(t @ Term::MaybeDelim(dm))) => {
-                        if dm == dr {
-                            break;
-                        } else {
-                            terms.push(t);
-                        }
+                match p.get_term(true)? {
+                    Some(t) if matches!(t.value, Term::MaybeDelim(dm) if dm == dr) => break,
+                    Some(t) => terms.push(t),
+                    None if p.peek().is_none() => {
+                        return Err(p.error_since(
+                            parser,
+                            ErrorKind::Unterminated,
+                            format!("missing {dr:?} at the end of argument"),
+                        ));
                     }
-                    Ok(Some(t)) => terms.push(t),
-                    Ok(None) => panic!("missing {dr:?} (TODO: proper error message)"),
-                    Err(e) => panic!(
-                        "TODO: proper error message idk {:?}",
-                        DocParser::make_parse_error_msg(parser, &p, &e, &terms)
-                    ),
+                    None => return Err(p.unparsable_error()),
                 }
             }
 
+            p.depth -= 1;
             *parser = p;
-            Some(terms)
+            Ok(Some(terms))
+        }
+
+        fn get_kwargs<'a>(
+            parser: &mut DocParser<'a>,
+        ) -> Result<Vec<Spanned<KwArg<'a>>>, ParseError> {
+            let mut p = parser.clone();
+            let skip_whitespace = |p: &mut DocParser| {
+                p.count_while(|c| is::inline_whitespace(c) || c == '\n');
+            };
+
+            if p.expect_and_skip('[').is_none() {
+                return Ok(Vec::new());
+            }
+
+            // only commit to this being a list of keyword arguments if it starts like one (on the
+            // same line, so a line never depends on what comes after it)
+            let mut p2 = p.clone();
+            p2.count_while(is::inline_whitespace);
+            if p2.get_ident().is_none() || p2.expect_and_skip('=').is_none() {
+                return Ok(Vec::new());
+            }
+
+            let mut kwargs = Vec::new();
+            loop {
+                skip_whitespace(&mut p);
+                if p.expect_and_skip(']').is_some() {
+                    break;
+                } else if p.peek().is_none() {
+                    return Err(p.error_since(
+                        parser,
+                        ErrorKind::Unterminated,
+                        "missing ']' at the end of keyword arguments",
+                    ));
+                }
+
+                let start = p.clone();
+                let Some(name) = p.get_ident() else {
+                    return Err(
+                        p.error_here(ErrorKind::BadArguments, "expected keyword argument name")
+                    );
+                };
+                if p.expect_and_skip('=').is_none() {
+                    return Err(p.error_here(
+                        ErrorKind::BadArguments,
+                        format!("expected '=' after keyword argument {name:?}"),
+                    ));
+                }
+
+                let arg = if let Some(arg) = get_arg(&mut p, ('{', '}'))? {
+                    arg
+                } else if let Some((arg, span)) = get_raw_arg(&mut p)? {
+                    vec![Spanned::new(Term::Word(arg), span)]
+                } else {
+                    let value_start = p.clone();
+                    let value = p.collect(|c| {
+                        !is::inline_whitespace(c) && !matches!(c, '\n' | '[' | ']' | '{' | '}')
+                    });
+                    if value.is_empty() {
+                        return Err(p.error_here(
+                            ErrorKind::BadArguments,
+                            format!("expected value for keyword argument {name:?}"),
+                        ));
+                    }
+                    vec![Spanned::new(
+                        Term::Word(value.into()),
+                        p.span_since(&value_start),
+                    )]
+                };
+
+                let name = name.into();
+                kwargs.push(Spanned::new(KwArg { name, arg }, p.span_since(&start)));
+            }
+
+            *parser = p;
+            Ok(kwargs)
         }
 
         let mut p = self.clone();
-        p.expect_and_skip('@')?;
+        if p.expect_and_skip('@').is_none() {
+            return Ok(None);
+        }
         let name = p.get_ident();
+        let kwargs = match name {
+            Some(_) => get_kwargs(&mut p)?,
+            None => Vec::new(),
+        };
         let mut args = Vec::new();
         'blk: loop {
-            if let Some(arg) = get_arg(&mut p, ('{', '}')) {
+            if let Some(arg) = get_arg(&mut p, ('{', '}'))? {
                 args.push(arg);
-            } else if let Some(arg) = get_arg(&mut p, ('(', ')')) {
+            } else if let Some(arg) = get_arg(&mut p, ('(', ')'))? {
                 args.push(arg);
-            } else if let Some(arg) = get_raw_arg(&mut p) {
-                args.push(vec![Term::Word(arg)]);
+            } else if let Some((arg, span)) = get_raw_arg(&mut p)? {
+                args.push(vec![Spanned::new(Term::Word(arg), span)]);
             } else {
                 break 'blk;
             }
         }
 
-        if args.len() == 0 {
-            return None;
+        if args.is_empty() && kwargs.is_empty() {
+            return Ok(None);
         }
 
         *self = p;
-        Some((name, args))
+        Ok(Some(match name {
+            Some(name) => Term::FuncCall(FuncCall {
+                name: name.into(),
+                args,
+                kwargs,
+            }),
+            None => Term::List(args),
+        }))
     }
 
-    pub fn get_comment(&mut self) -> Option<String> {
+    pub fn get_comment(&mut self) -> Option<&'a str> {
         let mut p = self.clone();
 
         p.expect_and_skip('%')?;
@@ -588,7 +1263,7 @@ impl<'a> DocParser<'a> {
 
         let ret = p.collect(|c| c != '\n');
 
-        if ret.len() > 0 {
+        if !ret.is_empty() {
             *self = p;
             Some(ret)
         } else {
@@ -596,13 +1271,13 @@ impl<'a> DocParser<'a> {
         }
     }
 
-    pub fn get_tag(&mut self) -> Option<String> {
+    pub fn get_tag(&mut self) -> Option<&'a str> {
         let mut p = self.clone();
 
         p.expect_and_skip('%')?;
         let ret = p.collect(|c| !is::inline_whitespace(c) && c != '\n');
 
-        if ret.len() > 0 {
+        if !ret.is_empty() {
             *self = p;
             Some(ret)
         } else {
@@ -647,7 +1322,10 @@ impl<'a> DocParser<'a> {
     }
 
     #[inline(always)]
-    pub fn get_symmetric_delimiter(&mut self, delim: char) -> Result<Option<String>, String> {
+    pub fn get_symmetric_delimiter(
+        &mut self,
+        delim: char,
+    ) -> Result<Option<Cow<'a, str>>, ParseError> {
         let mut p = self.clone();
 
         if p.expect_and_skip(delim).is_none() {
@@ -661,7 +1339,7 @@ impl<'a> DocParser<'a> {
             _ => {}
         }
 
-        let mut ret = String::new();
+        let mut ret = Text::new(&p);
         'blk: loop {
             match p.peek() {
                 Some(x) if x == delim => {
@@ -678,29 +1356,98 @@ impl<'a> DocParser<'a> {
                             p2.step();
                             p = p2;
                         }
-                        Some(c) => {
-                            ret.push('\\');
-                            ret.push(c);
-                            p2.step();
-                            p = p2;
+                        Some(_) => {
+                            ret.take(&mut p);
+                            ret.take(&mut p);
                         }
                         None => {
-                            return Err(format!("(delimiter {delim:?}) unexpected end of input"));
+                            return Err(p2.error_since(
+                                self,
+                                ErrorKind::Unterminated,
+                                format!("(delimiter {delim:?}) unexpected end of input"),
+                            ));
                         }
                     }
                 }
                 Some('\n') | None => {
-                    return Err(format!("(delimiter {delim:?}) unexpected end of line"));
+                    return Err(p.error_since(
+                        self,
+                        ErrorKind::Unterminated,
+                        format!("(delimiter {delim:?}) unexpected end of line"),
+                    ));
                 }
-                Some(c) => {
-                    ret.push(c);
+                Some(_) => ret.take(&mut p),
+            }
+        }
+
+        *self = p;
+        Ok(Some(ret.finish()))
+    }
+
+    /// Parse a bold (`*...*`) or italics (`_..._`) term, which can contain other terms.
+    ///
+    /// The same kind of emphasis can't be nested inside itself, but they can be nested inside each
+    /// other (e.g. `*bold _and italics_*`).
+    pub fn get_emphasis(
+        &mut self,
+        delim: char,
+    ) -> Result<Option<Vec<Spanned<Term<'a>>>>, ParseError> {
+        let already_open = match delim {
+            '*' => self.in_bold,
+            '_' => self.in_italics,
+            _ => unreachable!("bad emphasis delimiter {delim:?}"),
+        };
+        if already_open {
+            return Ok(None);
+        }
+
+        let mut p = self.clone();
+
+        if p.expect_and_skip(delim).is_none() {
+            return Ok(None);
+        }
+
+        match p.peek() {
+            Some('\n') => return Ok(None),
+            Some(' ') => return Ok(None),
+            None => return Ok(None),
+            _ => {}
+        }
+
+        let set_open = |p: &mut Self, open: bool| match delim {
+            '*' => p.in_bold = open,
+            _ => p.in_italics = open,
+        };
+        set_open(&mut p, true);
+
+        let mut terms = Vec::new();
+        loop {
+            match p.peek() {
+                Some(x) if x == delim => {
                     p.step();
+                    break;
+                }
+                Some('\n') | None => {
+                    return Err(p.error_since(
+                        self,
+                        ErrorKind::Unterminated,
+                        format!("(delimiter {delim:?}) unexpected end of line"),
+                    ));
                 }
+                _ => {}
+            }
+
+            match p.get_term(false)? {
+                Some(t) => terms.push(t),
+                // something that can't be inside this emphasis (e.g. the end of an outer one), so
+                // this isn't an emphasis at all
+                None => return Ok(None),
             }
         }
 
+        set_open(&mut p, false);
         *self = p;
-        Ok(Some(ret))
+        Ok(Some(terms))
     }
 
     make_parse_math!(get_inline_math_a, expect_start: ['$', '{'], end_on_bracket: true);
@@ -712,24 +1459,18 @@ impl<'a> DocParser<'a> {
 /// Collection of methods for checking a character.
 pub mod is {
     pub fn escapable_char(c: char) -> bool {
-        match c {
-            '\\' | '@' | '$' | '%' | '*' | '_' | '`' => true,
-            _ => false,
-        }
+        matches!(c, '\\' | '@' | '$' | '%' | '*' | '_' | '`')
     }
 
     pub fn inline_whitespace(c: char) -> bool {
-        match c {
-            ' ' | '\t' => true,
-            _ => false,
-        }
+        matches!(c, ' ' | '\t')
     }
 
     pub fn word_char(c: char) -> bool {
-        match c {
-            '\n' | ' ' | '\t' | '*' | '`' | '$' | '%' | '(' | ')' | '{' | '}' => false,
-            _ => true,
-        }
+        !matches!(
+            c,
+            '\n' | ' ' | '\t' | '*' | '`' | '$' | '%' | '(' | ')' | '{' | '}'
+        )
     }
 }
 
@@ -737,10 +1478,19 @@ pub mod is {
 mod tests {
     use super::{Term::*, *};
 
-    fn parse_single_line(x: &str) -> Vec<Term> {
+    fn parse_single_line(x: &str) -> Vec<Term<'_>> {
         let result = parse(x).unwrap();
         assert_eq!(result.lines.len(), 1);
-        return result.lines[0].terms.clone(); // FIXME: why can't I just move it out?
+        // FIXME: why can't I just move it out?
+        result.lines[0]
+            .terms
+            .iter()
+            .map(|t| t.value.clone())
+            .collect()
+    }
+
+    fn word(x: &str) -> Spanned<Term<'_>> {
+        Spanned::new(Term::Word(x.into()), Span::default())
     }
 
     macro_rules! assert_terms {
@@ -766,7 +1516,7 @@ mod tests {
 
     #[test]
     fn basic_assert_terms() {
-        let x = &[Space, Space, Word("".to_string())];
+        let x = &[Space, Space, Word("".into())];
         assert_terms!(x, [Space, Space, Word(_)]);
     }
 
@@ -785,7 +1535,9 @@ mod tests {
         let prs = |x: &str, expected: &str| {
             let terms = parse_single_line(x);
             assert!(terms.len() == 1);
-            let InlineCode(ref x) = terms[0] else { panic!("failed to unwrap code") };
+            let InlineCode(ref x) = terms[0] else {
+                panic!("failed to unwrap code")
+            };
             assert_eq!(x, expected);
         };
 
@@ -795,6 +1547,157 @@ mod tests {
         prs(r#"`bar\`baz`"#, "bar`baz");
     }
 
+    #[test]
+    fn header() {
+        let doc = parse(concat!(
+            "%:title A title\n",
+            "  that goes on\n",
+            "%:author Someone\n",
+            "%:date 2024-02-29\n",
+            "%:lang pt-BR\n",
+            "%:katex-macros\n",
+            "  \\RR \\mathbb{R}\n",
+            "  \\abs |#1|\n",
+            "%:custom foo\n",
+            "\n",
+            "body\n",
+        ))
+        .unwrap();
+
+        let opts = &doc.options;
+        assert_eq!(opts.title, "A title that goes on");
+        assert_eq!(opts.author.as_deref(), Some("Someone"));
+        assert_eq!(
+            opts.date.map(|d| d.to_string()).as_deref(),
+            Some("2024-02-29")
+        );
+        assert_eq!(opts.lang.as_deref(), Some("pt-BR"));
+        assert_eq!(opts.description, None);
+        assert_eq!(
+            opts.katex_macros,
+            [
+                ("\\RR".into(), "\\mathbb{R}".into()),
+                ("\\abs".into(), "|#1|".into())
+            ]
+        );
+        assert_eq!(doc.header.get("custom").map(|s| s.as_str()), Some("foo"));
+        assert_eq!(doc.lines.len(), 1);
+
+        for bad in ["2023-02-29", "2024-13-01", "2024-1-01", "yesterday"] {
+            let err = parse(&format!("%:date {bad}\n\nbody\n")).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::BadHeaderValue);
+            assert_eq!((err.span.line, err.span.column), (1, 8));
+        }
+
+        let err = parse("%:katex-macros RR foo\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadHeaderValue);
+    }
+
+    #[test]
+    fn line_endings_and_bom() {
+        let lf = parse("%:title Foo\n\nfoo *bar*\n  baz\n").unwrap();
+        let crlf = parse("\u{feff}%:title Foo\r\n\r\nfoo *bar*\r\n  baz\r\n").unwrap();
+        assert_eq!(crlf.options.title, "Foo");
+        fn values<'a>(doc: &DocumentSt1<'a>) -> Vec<Vec<Term<'a>>> {
+            doc.lines
+                .iter()
+                .map(|l| l.terms.iter().map(|t| t.value.clone()).collect())
+                .collect()
+        }
+        assert_eq!(values(&crlf), values(&lf));
+
+        let bar = &crlf.lines[0].terms[2].span;
+        assert_eq!((bar.line, bar.column), (3, 5));
+        let baz = &crlf.lines[1].terms[0].span;
+        assert_eq!((baz.line, baz.column), (4, 3));
+        assert_eq!(
+            baz.start,
+            "\u{feff}%:title Foo\r\n\r\nfoo *bar*\r\n  ".len()
+        );
+
+        // a lone `\r` is also a line break
+        assert_eq!(parse("foo\rbar").unwrap().lines.len(), 2);
+    }
+
+    #[test]
+    fn indent_inference() {
+        let indent_of = |s: &str| {
+            let doc = parse(s).unwrap();
+            (doc.options.indent, doc.options.indent_origin)
+        };
+
+        use IndentOrigin::*;
+        assert_eq!(
+            indent_of("a\n    b\n        c\n    d\n"),
+            (Indent::Space(4), Inferred)
+        );
+        assert_eq!(indent_of("a\n\tb\n\t\tc\n"), (Indent::Tab, Inferred));
+        assert_eq!(indent_of("a\nb\n"), (Indent::Space(2), Default));
+        assert_eq!(indent_of("%:indent 4\n\na\n"), (Indent::Space(4), Header));
+
+        // the inside of a code block doesn't count
+        let s = "a\n    @code#{\n   odd\n     indent\n    }#\n";
+        assert_eq!(indent_of(s), (Indent::Space(4), Inferred));
+
+        // the header still wins
+        should_parse(false, "%:indent 3\n\na\n    b\n");
+    }
+
+    #[test]
+    fn mixed_indent() {
+        let err = parse("foo\n  \tbar\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::MixedIndent);
+        assert_eq!((err.span.line, err.span.column), (2, 3));
+
+        let err = parse("%:indent tab\n\nfoo\n\t \tbar\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::MixedIndent);
+        assert_eq!((err.span.line, err.span.column), (4, 2));
+
+        // whitespace-only lines are fine
+        should_parse(true, "foo\n  \t\nbar");
+    }
+
+    #[test]
+    fn emphasis() {
+        fn inner<'a>(t: &Term<'a>) -> Vec<Term<'a>> {
+            match t {
+                InlineBold(x) | InlineItalics(x) => x.iter().map(|t| t.value.clone()).collect(),
+                _ => panic!("expected emphasis, got {t:?}"),
+            }
+        }
+
+        let res = parse_single_line("*see https://x.org*");
+        assert_terms!(&res, [InlineBold(_)]);
+        assert_terms!(inner(&res[0]), [Word(_), Space, Word(_)]);
+
+        let res = parse_single_line("_${x} and `y`_");
+        assert_terms!(&res, [InlineItalics(_)]);
+        assert_terms!(
+            inner(&res[0]),
+            [InlineMath(_), Space, Word(_), Space, InlineCode(_)]
+        );
+
+        // nesting
+        let res = parse_single_line("*bold _both_* _italics *both*_");
+        assert_terms!(&res, [InlineBold(_), Space, InlineItalics(_)]);
+        assert_terms!(inner(&res[0]), [Word(_), Space, InlineItalics(_)]);
+        assert_terms!(inner(&res[2]), [Word(_), Space, InlineBold(_)]);
+
+        // the end of an italics isn't part of a word
+        assert_terms!(inner(&parse_single_line("_foo_bar")[0]), [Word(_)]);
+
+        // not emphasis
+        assert_terms!("a * b", [Word(_), Space, Word(_), Space, Word(_)]);
+        assert_terms!("snake_case_word", [Word(_)]);
+        assert_terms!("_a *b_", [InlineItalics(_)]);
+        assert_terms!(
+            inner(&parse_single_line("*foo _bar*")[0]),
+            [Word(_), Space, Word(_)]
+        );
+
+        should_parse(false, "*foo");
+    }
+
     #[test]
     fn func_calls() {
         // Single arg
@@ -802,36 +1705,56 @@ mod tests {
         assert_terms!(&res, [FuncCall(_)]);
         let FuncCall(ref fc) = res[0] else { panic!() };
         assert_eq!(fc.name, "bar");
-        assert_eq!(fc.args, vec![vec![Term::Word("baz".into())]]);
+        assert_eq!(fc.args, vec![vec![word("baz")]]);
 
         // Two args
         let res = parse_single_line("@foo{bar}{baz}");
         assert_terms!(&res, [FuncCall(_)]);
         let FuncCall(ref fc) = res[0] else { panic!() };
         assert_eq!(fc.name, "foo");
-        assert_eq!(fc.args, vec![
-            vec![Term::Word("bar".into())],
-            vec![Term::Word("baz".into())]
-        ]);
+        assert_eq!(fc.args, vec![vec![word("bar")], vec![word("baz")]]);
 
         // Paren arg
         let res = parse_single_line("@foo(bar){baz}");
         assert_terms!(&res, [FuncCall(_)]);
         let FuncCall(ref fc) = res[0] else { panic!() };
         assert_eq!(fc.name, "foo");
-        assert_eq!(fc.args, vec![
-            vec![Term::Word("bar".into())],
-            vec![Term::Word("baz".into())]
-        ]);
+        assert_eq!(fc.args, vec![vec![word("bar")], vec![word("baz")]]);
 
         // Raw arg
         let res = parse_single_line("@bar#{ idk man { ksdljakld } }#");
         assert_terms!(&res, [FuncCall(_)]);
         let FuncCall(ref fc) = res[0] else { panic!() };
         assert_eq!(fc.name, "bar");
-        assert_eq!(fc.args, vec![
-            vec![Term::Word(" idk man { ksdljakld } ".into())],
-        ]);
+        assert_eq!(fc.args, vec![vec![word(" idk man { ksdljakld } ")],]);
+    }
+
+    #[test]
+    fn keyword_args() {
+        let res = parse_single_line("@image[caption={A *nice* cat} width=50%]{cat.png}");
+        assert_terms!(&res, [FuncCall(_)]);
+        let FuncCall(ref fc) = res[0] else { panic!() };
+        assert_eq!(fc.name, "image");
+        assert_eq!(fc.args, vec![vec![word("cat.png")]]);
+        assert_eq!(fc.kwargs.len(), 2);
+        assert_eq!(fc.kwargs[0].name, "caption");
+        assert_eq!(fc.kwargs[0].arg.len(), 5);
+        assert!(matches!(fc.kwargs[0].arg[2].value, InlineBold(_)));
+        assert_eq!(fc.kwargs[1].name, "width");
+        assert_eq!(fc.kwargs[1].arg, vec![word("50%")]);
+
+        // keyword args only, with a raw value
+        let res = parse_single_line("@dot[ engine=fdp lang=#{ a ] b }# ]");
+        let FuncCall(ref fc) = res[0] else { panic!() };
+        assert!(fc.args.is_empty());
+        assert_eq!(fc.kwargs[1].arg, vec![word(" a ] b ")]);
+
+        // not keyword args, so not a call either
+        assert_terms!("@user[1]", [Word(_)]);
+
+        should_parse(false, "@foo[a=b");
+        should_parse(false, "@foo[a=b c]{x}");
+        should_parse(false, "@foo[a=]{x}");
     }
 
     fn should_parse(should: bool, string: &str) {
@@ -861,4 +1784,45 @@ mod tests {
         should_parse(false, "${5 + 8");
         should_parse(false, "${{5 + 8}");
     }
+
+    #[test]
+    fn term_spans() {
+        let doc = parse("%:title x\n\nfoo\n  - bar @c{baz}\n").unwrap();
+        let spans: Vec<_> = doc.lines[1]
+            .terms
+            .iter()
+            .map(|t| (t.span.start, t.span.end, t.span.line, t.span.column))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (17, 18, 4, 3),
+                (18, 19, 4, 4),
+                (19, 22, 4, 5),
+                (22, 23, 4, 8),
+                (23, 30, 4, 9)
+            ]
+        );
+
+        let FuncCall(ref fc) = doc.lines[1].terms[4].value else {
+            panic!()
+        };
+        assert_eq!((fc.args[0][0].span.start, fc.args[0][0].span.end), (26, 29));
+    }
+
+    #[test]
+    fn error_spans() {
+        let err = parse("foo\nbar `baz\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Unterminated);
+        assert_eq!((err.span.line, err.span.column), (2, 5));
+        assert_eq!((err.span.start, err.span.end), (8, 12));
+
+        let err = parse("%:indent 2\n\nfoo\n   bar\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadIndent);
+        assert_eq!((err.span.line, err.span.column), (4, 1));
+
+        let err = parse("%:indent four\n\nfoo\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadHeaderValue);
+        assert_eq!((err.span.line, err.span.column), (1, 10));
+    }
 }
@@ -1,88 +1,144 @@
-use crate::parser::{DocumentSt1, DocumentSt2, Line, Node};
+use crate::parser::{Diagnostics, DocumentSt1, DocumentSt2, ErrorKind, Line, Node, ParseError};
 
-pub fn parse(doc: DocumentSt1) -> Result<DocumentSt2, String> {
-    let mut nodes = Vec::new();
+pub fn parse(doc: DocumentSt1<'_>) -> Result<DocumentSt2<'_>, ParseError> {
+    parse_with(doc, &mut Diagnostics::strict())
+}
 
-    // Stack with the current "hierarchy" of nodes being processed.
-    //
-    // stack.lest() is immediate parent of the lines with stack.len() indent.
-    let mut stack: Vec<Node> = Vec::new();
+/// Build the tree, sending errors to `diag`.
+///
+/// When recovering, lines indented too deep are treated as if they had the deepest valid indent,
+/// and the lines after them at the same indent as their siblings.
+pub fn parse_with<'a>(
+    doc: DocumentSt1<'a>,
+    diag: &mut Diagnostics,
+) -> Result<DocumentSt2<'a>, ParseError> {
+    Ok(DocumentSt2 {
+        header: doc.header,
+        options: doc.options,
+        nodes: build_tree(doc.lines, diag)?,
+    })
+}
 
-    fn line_to_node(line: Line) -> Node {
-        Node {
-            contents: line.terms,
-            children: Vec::new(),
-            bottom_spacing: false,
-        }
+/// Build the tree for a run of lines, sending errors to `diag`.
+pub fn build_tree<'a>(
+    lines: Vec<Line<'a>>,
+    diag: &mut Diagnostics,
+) -> Result<Vec<Node<'a>>, ParseError> {
+    let mut nodes = Vec::new();
+    let mut builder = TreeBuilder::new();
+
+    for line in lines.into_iter() {
+        nodes.extend(builder.push(line, diag)?);
     }
+    nodes.extend(builder.finish());
 
-    fn pop_to_parent(nodes: &mut Vec<Node>, stack: &mut Vec<Node>) {
-        let top = stack.pop().expect("stack is empty");
+    Ok(nodes)
+}
 
-        match stack.last_mut() {
-            Some(x) => x.children.push(top),
-            None => nodes.push(top),
-        }
+/// Builds the tree one line at a time, handing out each top-level node once it's complete.
+#[derive(Debug, Clone, Default)]
+pub struct TreeBuilder<'a> {
+    /// Stack with the current "hierarchy" of nodes being processed, along with the indent of
+    /// their lines.
+    ///
+    /// Without errors, the indents go 0, 1, 2... up to stack.len() - 1. A line indented too deep
+    /// keeps its own indent, so the lines after it at the same indent are its siblings.
+    stack: Vec<(Node<'a>, usize)>,
+}
+
+impl<'a> TreeBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    for (i, line) in doc.lines.into_iter().enumerate() {
-        // In this context, stack.len() corresponds to the indent a line has to be a child of the
-        // "current node".
+    /// Add a line to the tree, sending errors to `diag`.
+    ///
+    /// Returns the previous top-level node if this line closed it.
+    pub fn push(
+        &mut self,
+        line: Line<'a>,
+        diag: &mut Diagnostics,
+    ) -> Result<Option<Node<'a>>, ParseError> {
+        let stack = &mut self.stack;
+        let mut done = None;
 
         if line.terms.is_empty() {
             // Empty lines don't result in nodes, but they affect the previous node.
 
-            if let Some(x) = stack.last_mut() {
+            if let Some((x, _)) = stack.last_mut() {
                 x.bottom_spacing = true;
             }
-            continue;
+            return Ok(None);
         }
 
-        if stack.len() == 0 {
-            if line.indent == 0 {
-                stack.push(line_to_node(line));
-            } else {
-                return Err(format!(
-                    "line {}: indented line before any non-indented line",
-                    i + 1
-                ));
-            }
-        } else {
-            // pop from the stack if needed
-            while line.indent + 1 < stack.len() {
-                pop_to_parent(&mut nodes, &mut stack);
-            }
+        // pop the lines that can't be its parent (the ones indented as much as it or more), the
+        // last of which is its previous sibling, if it has one
+        let mut sibling_indent = None;
+        while let Some(&(_, indent)) = stack.last()
+            && indent >= line.indent
+        {
+            sibling_indent = Some(indent);
+            pop_to_parent(&mut done, stack);
+        }
 
-            if line.indent + 1 == stack.len() {
-                // the current line has the same indent as the last one
-                //
-                // pop the last line off the stack to its parent, and put the current line on the
-                // top of the stack.
-                pop_to_parent(&mut nodes, &mut stack);
-                stack.push(line_to_node(line));
-            } else if line.indent == stack.len() {
-                // the current line is a child of the last one
-                //
-                // push the current line onto the stack
-                stack.push(line_to_node(line));
-            } else {
-                return Err(format!(
-                    "line {}: indent leap (current {}, expected at most {})",
-                    i + 1,
-                    line.indent,
-                    stack.len()
-                ));
+        // a line indented too deep is only reported once, and not again for its siblings
+        if sibling_indent != Some(line.indent) {
+            match stack.last() {
+                None if line.indent != 0 => {
+                    diag.report(ParseError::new(
+                        ErrorKind::OrphanIndent,
+                        line.span,
+                        "indented line before any non-indented line",
+                    ))?;
+                }
+                Some(&(_, parent_indent)) if line.indent > parent_indent + 1 => {
+                    diag.report(ParseError::new(
+                        ErrorKind::IndentLeap,
+                        line.span,
+                        format!(
+                            "indent leap (current {}, expected at most {})",
+                            line.indent,
+                            parent_indent + 1
+                        ),
+                    ))?;
+                }
+                _ => {}
             }
         }
+
+        let indent = line.indent;
+        stack.push((line_to_node(line), indent));
+
+        Ok(done)
     }
 
-    while !stack.is_empty() {
-        pop_to_parent(&mut nodes, &mut stack);
+    /// Close the tree, returning the last top-level node.
+    pub fn finish(mut self) -> Option<Node<'a>> {
+        let mut done = None;
+        while !self.stack.is_empty() {
+            pop_to_parent(&mut done, &mut self.stack);
+        }
+        done
     }
+}
 
-    Ok(DocumentSt2 {
-        header: doc.header,
-        options: doc.options,
-        nodes: nodes,
-    })
+fn line_to_node(line: Line<'_>) -> Node<'_> {
+    Node {
+        contents: line.terms,
+        children: Vec::new(),
+        bottom_spacing: false,
+        span: line.span,
+    }
+}
+
+/// Pop the top of the stack into its parent, or into `done` if it's a top-level node.
+fn pop_to_parent<'a>(done: &mut Option<Node<'a>>, stack: &mut Vec<(Node<'a>, usize)>) {
+    let Some((top, _)) = stack.pop() else {
+        return;
+    };
+
+    match stack.last_mut() {
+        Some((x, _)) => x.children.push(top),
+        None => *done = Some(top),
+    }
 }
@@ -1,58 +1,123 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 
 pub use crate::parser::data::{BulletType, StandardOptions, TaskPrefix, TaskState};
 use crate::parser::{
-    data::{DocumentSt2, FuncCall, Node as Node2, Term as Term2},
+    Diagnostics, ErrorKind, ParseError,
+    data::{DocumentSt2, FuncCall, Node as Node2, Span, Spanned, Term as Term2},
+    functions::{Call, FunctionKind, Functions, check_call},
+    refs::RefIndex,
     stage1::is,
+    visit::{Visit, walk_term},
 };
 
-#[derive(Debug, Clone)]
-pub struct Document {
+/// The processed document.
+///
+/// Text is borrowed from the source string whenever possible; use [`Document::into_owned`] to
+/// detach it from the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document<'a> {
     pub header: HashMap<String, String>,
     pub options: StandardOptions,
-    pub nodes: Vec<Node>,
+    pub nodes: Vec<Node<'a>>,
+    /// The content of each footnote, in the order they appear (the one of [`Term::Footnote`]`(n)`
+    /// is `footnotes[n - 1]`).
+    pub footnotes: Vec<Vec<Spanned<Term<'a>>>>,
 }
 
 #[derive(Debug, Clone)]
-pub struct Node {
-    pub line: Line,
-    pub children: Vec<Node>,
+pub struct Node<'a> {
+    pub line: Line<'a>,
+    pub children: Vec<Node<'a>>,
     pub bottom_spacing: bool,
+    pub span: Span,
+}
+
+/// The span is left out of comparisons, like with [`Spanned`] values.
+impl PartialEq for Node<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.line == other.line
+            && self.children == other.children
+            && self.bottom_spacing == other.bottom_spacing
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum Line {
-    Text(TextLine),
-    Table(TableLine),
+pub enum Line<'a> {
+    Text(TextLine<'a>),
+    Heading(HeadingLine<'a>),
+    Quote(QuoteLine<'a>),
+    Table(TableLine<'a>),
     Image(ImageLine),
-    DotGraph(DotGraphLine),
+    DotGraph(DotGraphLine<'a>),
     CodeBlock(CodeBlockLine),
-    DisplayMath(String),
+    DisplayMath(Cow<'a, str>),
+    /// A horizontal rule (a line with only `---`).
+    Rule,
+
+    /// A line that failed to parse (only produced when recovering from errors).
+    Error(ParseError),
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct TextLine {
+pub struct TextLine<'a> {
     pub bullet: Option<BulletType>,
     pub task: Option<TaskPrefix>,
-    pub content: Vec<Term>,
+    pub content: Vec<Spanned<Term<'a>>>,
 }
 
+/// A section heading (`@h{...}`).
 #[derive(Debug, Clone, PartialEq)]
-pub struct TableLine {
+pub struct HeadingLine<'a> {
+    /// From 1 (a top-level section) to [`HeadingLine::MAX_LEVEL`].
+    pub level: u8,
+    /// The ID to link to the heading with (by default, the [`slug`] of its text, made unique in
+    /// the document by [`HeadingIds`]).
+    pub id: String,
+    /// Whether the ID was given with `id=...`, rather than made from the text.
+    pub explicit_id: bool,
+    pub content: Vec<Spanned<Term<'a>>>,
+}
+
+impl HeadingLine<'_> {
+    pub const MAX_LEVEL: u8 = 5;
+
+    /// The level of a heading without an explicit one, given how deep it's nested.
+    pub fn level_at(depth: usize) -> u8 {
+        (depth + 1).min(Self::MAX_LEVEL as usize) as u8
+    }
+}
+
+/// A block quote (`@quote{...}`), whose children are the rest of what's quoted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuoteLine<'a> {
+    /// The first line of the quote (empty with a bare `@quote[by=...]`).
+    pub content: Vec<Spanned<Term<'a>>>,
+    /// Who or what is being quoted (`by=...`).
+    pub attribution: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableLine<'a> {
     pub columns: usize,
-    pub items: Vec<TableItem>,
+    pub items: Vec<TableItem<'a>>,
+    /// Whether the first row is a header (`header=yes`, the default).
+    pub header: bool,
+    pub caption: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ImageLine {
     pub caption: Option<String>,
     pub url: String,
+    pub width: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct DotGraphLine {
+pub struct DotGraphLine<'a> {
     pub engine: String,
-    pub code: String,
+    pub code: Cow<'a, str>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -62,97 +127,555 @@ pub struct CodeBlockLine {
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum Term {
+pub enum Term<'a> {
     Space,
-    Word(String),
-    Tag(String),
-    Url(String),
-    Math(String),
-    Ref { content: Vec<Term>, target: String },
-    Code(String),
-    Bold(String),
-    Italics(String),
+    Word(Cow<'a, str>),
+    Tag(Cow<'a, str>),
+    Url(Cow<'a, str>),
+    Math(Cow<'a, str>),
+    /// A link to an anchor, by its ID. Without a content of its own (`@ref{target}`), the text of
+    /// the anchor is used instead (see [`crate::parser::refs`]).
+    Ref {
+        content: Vec<Spanned<Term<'a>>>,
+        target: String,
+    },
+    /// A place that can be linked to with `@ref`, by its ID (`@anchor{id}`).
+    Anchor(Cow<'a, str>),
+    /// A link to a URL or a path, with a label (`@link{target}{label}`). Without one, the target
+    /// itself is shown.
+    Link {
+        content: Vec<Spanned<Term<'a>>>,
+        target: String,
+    },
+    Code(Cow<'a, str>),
+    Bold(Vec<Spanned<Term<'a>>>),
+    Italics(Vec<Spanned<Term<'a>>>),
+    Strikethrough(Vec<Spanned<Term<'a>>>),
+    Underline(Vec<Spanned<Term<'a>>>),
+    Highlight(Vec<Spanned<Term<'a>>>),
+    Superscript(Vec<Spanned<Term<'a>>>),
+    Subscript(Vec<Spanned<Term<'a>>>),
+    /// A key or key combination (e.g. `Ctrl+C`).
+    Kbd(Cow<'a, str>),
+    /// The marker of a footnote, by its number (see [`Document::footnotes`]).
+    Footnote(usize),
+}
+
+impl Term<'_> {
+    /// Append the text of the term to `out`, without any formatting.
+    ///
+    /// This is the fallback for backends that don't know how to render a term. Footnote markers
+    /// have no text.
+    pub fn write_plain_text(&self, out: &mut String) {
+        match self {
+            Term::Space => out.push(' '),
+            Term::Footnote(_) | Term::Anchor(_) => {}
+            Term::Ref { content, target } | Term::Link { content, target }
+                if content.is_empty() =>
+            {
+                out.push_str(target)
+            }
+            Term::Word(x) | Term::Url(x) | Term::Math(x) | Term::Code(x) | Term::Kbd(x) => {
+                out.push_str(x)
+            }
+            Term::Tag(x) => {
+                out.push('%');
+                out.push_str(x);
+            }
+            Term::Ref { content: x, .. }
+            | Term::Link { content: x, .. }
+            | Term::Bold(x)
+            | Term::Italics(x)
+            | Term::Strikethrough(x)
+            | Term::Underline(x)
+            | Term::Highlight(x)
+            | Term::Superscript(x)
+            | Term::Subscript(x) => {
+                for t in x {
+                    t.write_plain_text(out);
+                }
+            }
+        }
+    }
+}
+
+/// Get the text of `terms`, without any formatting.
+pub fn plain_text(terms: &[Spanned<Term>]) -> String {
+    let mut ret = String::new();
+    for t in terms {
+        t.write_plain_text(&mut ret);
+    }
+    ret
+}
+
+/// Turn `text` into an ID, keeping only lowercase letters and digits, with runs of anything else
+/// replaced by a single `-` (e.g. `"What's new?"` becomes `"what-s-new"`).
+pub fn slug(text: &str) -> String {
+    let mut ret = String::new();
+    let mut pending_dash = false;
+
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            if pending_dash && !ret.is_empty() {
+                ret.push('-');
+            }
+            pending_dash = false;
+            ret.extend(c.to_lowercase());
+        } else {
+            pending_dash = true;
+        }
+    }
+
+    ret
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum TableItem {
-    Row(Vec<Vec<Term>>),
+pub enum TableItem<'a> {
+    Row(Vec<Vec<Spanned<Term<'a>>>>),
     Separator,
 }
 
-pub fn parse(doc: DocumentSt2) -> Result<Document, String> {
-    let mut nodes = Vec::new();
+fn own(s: Cow<'_, str>) -> Cow<'static, str> {
+    Cow::Owned(s.into_owned())
+}
+
+fn own_terms(terms: Vec<Spanned<Term<'_>>>) -> Vec<Spanned<Term<'static>>> {
+    terms.into_iter().map(|t| t.map(Term::into_owned)).collect()
+}
 
-    for node in doc.nodes.into_iter() {
-        nodes.push(process_node(node)?);
+impl Document<'_> {
+    /// Copy all borrowed text, so the document doesn't depend on the source anymore.
+    pub fn into_owned(self) -> Document<'static> {
+        Document {
+            header: self.header,
+            options: self.options,
+            nodes: self.nodes.into_iter().map(Node::into_owned).collect(),
+            footnotes: self.footnotes.into_iter().map(own_terms).collect(),
+        }
     }
+}
 
-    Ok(Document {
+impl Node<'_> {
+    pub fn into_owned(self) -> Node<'static> {
+        Node {
+            line: self.line.into_owned(),
+            children: self.children.into_iter().map(Node::into_owned).collect(),
+            bottom_spacing: self.bottom_spacing,
+            span: self.span,
+        }
+    }
+}
+
+impl Line<'_> {
+    pub fn into_owned(self) -> Line<'static> {
+        match self {
+            Line::Text(l) => Line::Text(TextLine {
+                bullet: l.bullet,
+                task: l.task,
+                content: own_terms(l.content),
+            }),
+            Line::Heading(l) => Line::Heading(HeadingLine {
+                level: l.level,
+                id: l.id,
+                explicit_id: l.explicit_id,
+                content: own_terms(l.content),
+            }),
+            Line::Quote(l) => Line::Quote(QuoteLine {
+                content: own_terms(l.content),
+                attribution: l.attribution,
+            }),
+            Line::Table(l) => Line::Table(TableLine {
+                columns: l.columns,
+                items: l
+                    .items
+                    .into_iter()
+                    .map(|item| match item {
+                        TableItem::Row(r) => TableItem::Row(r.into_iter().map(own_terms).collect()),
+                        TableItem::Separator => TableItem::Separator,
+                    })
+                    .collect(),
+                header: l.header,
+                caption: l.caption,
+            }),
+            Line::Image(l) => Line::Image(l),
+            Line::DotGraph(l) => Line::DotGraph(DotGraphLine {
+                engine: l.engine,
+                code: own(l.code),
+            }),
+            Line::CodeBlock(l) => Line::CodeBlock(l),
+            Line::DisplayMath(x) => Line::DisplayMath(own(x)),
+            Line::Rule => Line::Rule,
+            Line::Error(e) => Line::Error(e),
+        }
+    }
+}
+
+impl Term<'_> {
+    pub fn into_owned(self) -> Term<'static> {
+        match self {
+            Term::Space => Term::Space,
+            Term::Word(x) => Term::Word(own(x)),
+            Term::Tag(x) => Term::Tag(own(x)),
+            Term::Url(x) => Term::Url(own(x)),
+            Term::Math(x) => Term::Math(own(x)),
+            Term::Ref { content, target } => Term::Ref {
+                content: own_terms(content),
+                target,
+            },
+            Term::Code(x) => Term::Code(own(x)),
+            Term::Bold(x) => Term::Bold(own_terms(x)),
+            Term::Italics(x) => Term::Italics(own_terms(x)),
+            Term::Strikethrough(x) => Term::Strikethrough(own_terms(x)),
+            Term::Underline(x) => Term::Underline(own_terms(x)),
+            Term::Highlight(x) => Term::Highlight(own_terms(x)),
+            Term::Superscript(x) => Term::Superscript(own_terms(x)),
+            Term::Subscript(x) => Term::Subscript(own_terms(x)),
+            Term::Kbd(x) => Term::Kbd(own(x)),
+            Term::Footnote(n) => Term::Footnote(n),
+            Term::Anchor(x) => Term::Anchor(own(x)),
+            Term::Link { content, target } => Term::Link {
+                content: own_terms(content),
+                target,
+            },
+        }
+    }
+}
+
+pub fn parse(doc: DocumentSt2<'_>) -> Result<Document<'_>, ParseError> {
+    parse_with(doc, Functions::builtin(), &mut Diagnostics::strict())
+}
+
+/// Process the tree with the given `functions`, sending errors to `diag`.
+///
+/// When recovering, lines that fail to be processed become [`Line::Error`], but their children are
+/// still processed.
+pub fn parse_with<'a>(
+    doc: DocumentSt2<'a>,
+    functions: &Functions,
+    diag: &mut Diagnostics,
+) -> Result<Document<'a>, ParseError> {
+    let mut footnotes = Vec::new();
+    let nodes = process_nodes(doc.nodes, functions, &mut footnotes, diag)?;
+
+    let mut doc = Document {
         header: doc.header,
         options: doc.options,
         nodes,
-    })
+        footnotes,
+    };
+    HeadingIds::assign_document(&mut doc, diag)?;
+    for e in RefIndex::of(&doc).dangling() {
+        diag.report(e)?;
+    }
+
+    Ok(doc)
 }
 
-fn process_node(n: Node2) -> Result<Node, String> {
-    let mut it = n.contents.into_iter().peekable();
+/// Process a run of top-level nodes, sending errors to `diag`.
+///
+/// Their footnotes are added to `footnotes`, numbered after the ones already there.
+pub fn process_nodes<'a>(
+    nodes: Vec<Node2<'a>>,
+    functions: &Functions,
+    footnotes: &mut Vec<Vec<Spanned<Term<'a>>>>,
+    diag: &mut Diagnostics,
+) -> Result<Vec<Node<'a>>, ParseError> {
+    let mut processed = Vec::new();
+
+    for node in nodes.into_iter() {
+        processed.push(process_node(node, functions, footnotes, diag)?);
+    }
 
-    fn check_empty_line(it: &mut impl Iterator<Item = Term2>) -> Result<(), String> {
-        while let Some(t) = it.next() {
-            match t {
-                Term2::Space => {}
-                other => return Err(format!("term should be alone in line, got {:?}", other)),
-            }
+    Ok(processed)
+}
+
+/// Process a top-level node and its children, sending errors to `diag`.
+///
+/// Its footnotes are added to `footnotes`, numbered after the ones already there.
+pub fn process_node<'a>(
+    n: Node2<'a>,
+    functions: &Functions,
+    footnotes: &mut Vec<Vec<Spanned<Term<'a>>>>,
+    diag: &mut Diagnostics,
+) -> Result<Node<'a>, ParseError> {
+    let cell = RefCell::new(std::mem::take(footnotes));
+    let cx = Context {
+        functions,
+        footnotes: &cell,
+        depth: 0,
+    };
+    let result = process_node_in(n, cx, diag);
+    *footnotes = cell.into_inner();
+    result
+}
+
+/// Makes the IDs of the headings of a document unique, going through its nodes in order.
+///
+/// A heading without an explicit ID gets the [`slug`] of its text (or `section-N`, for the N-th
+/// heading, if that's empty), followed by `-2`, `-3`, ... if it's already taken, by a heading or
+/// by an `@anchor` added before it. An explicit ID or an anchor that's already taken is an error.
+#[derive(Debug, Clone, Default)]
+pub struct HeadingIds {
+    taken: HashSet<String>,
+    headings: usize,
+}
+
+impl HeadingIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the heading IDs of all of `doc`, sending errors to `diag`.
+    ///
+    /// The anchors of the whole document are added first, so the headings go around them even
+    /// when they come later.
+    pub fn assign_document(
+        doc: &mut Document<'_>,
+        diag: &mut Diagnostics,
+    ) -> Result<(), ParseError> {
+        let mut ids = Self::new();
+        for node in &doc.nodes {
+            ids.add_anchors(node, diag)?;
+        }
+        for content in &doc.footnotes {
+            ids.add_footnote_anchors(content, diag)?;
+        }
+        for node in &mut doc.nodes {
+            ids.assign(node, diag)?;
         }
+        Ok(())
+    }
 
+    /// Take the IDs of the `@anchor`s in `node` and its children (but not in their footnotes),
+    /// sending errors to `diag`.
+    pub fn add_anchors(
+        &mut self,
+        node: &Node<'_>,
+        diag: &mut Diagnostics,
+    ) -> Result<(), ParseError> {
+        let mut anchors = Anchors(Vec::new());
+        anchors.visit_node(node, 0);
+        self.take_anchors(anchors.0, diag)
+    }
+
+    /// Take the IDs of the `@anchor`s in the content of a footnote, sending errors to `diag`.
+    pub fn add_footnote_anchors(
+        &mut self,
+        content: &[Spanned<Term<'_>>],
+        diag: &mut Diagnostics,
+    ) -> Result<(), ParseError> {
+        let mut anchors = Anchors(Vec::new());
+        anchors.visit_terms(content);
+        self.take_anchors(anchors.0, diag)
+    }
+
+    fn take_anchors(
+        &mut self,
+        anchors: Vec<(&str, Span)>,
+        diag: &mut Diagnostics,
+    ) -> Result<(), ParseError> {
+        for (id, span) in anchors {
+            if !self.taken.insert(id.to_owned()) {
+                diag.report(ParseError::new(
+                    ErrorKind::DuplicateId,
+                    span,
+                    format!("another heading or anchor already has the ID {id:?}"),
+                ))?;
+            }
+        }
         Ok(())
     }
 
-    fn extract_only_func(
-        it: &mut impl Iterator<Item = Term2>,
-        name: &str,
-    ) -> Result<FuncCall, String> {
-        let Term2::FuncCall(fc) = it.next().unwrap() else {
-            return Err("not a function call".into());
-        };
+    /// Set the IDs of the headings in `node` and its children, sending errors to `diag`.
+    pub fn assign(
+        &mut self,
+        node: &mut Node<'_>,
+        diag: &mut Diagnostics,
+    ) -> Result<(), ParseError> {
+        if let Line::Heading(h) = &mut node.line {
+            self.headings += 1;
+
+            if h.explicit_id {
+                if !self.taken.insert(h.id.clone()) {
+                    diag.report(ParseError::new(
+                        ErrorKind::DuplicateId,
+                        node.span,
+                        format!("another heading or anchor already has the ID {:?}", h.id),
+                    ))?;
+                }
+            } else {
+                let base = match slug(&plain_text(&h.content)) {
+                    s if s.is_empty() => format!("section-{}", self.headings),
+                    s => s,
+                };
+
+                let mut id = base.clone();
+                let mut n = 1;
+                while self.taken.contains(&id) {
+                    n += 1;
+                    id = format!("{base}-{n}");
+                }
+                self.taken.insert(id.clone());
+                h.id = id;
+            }
+        }
 
-        assert!(fc.name == name, "expected {:?}, got {:?}", name, fc.name);
-        check_empty_line(it)?;
+        for c in &mut node.children {
+            self.assign(c, diag)?;
+        }
 
-        Ok(fc)
+        Ok(())
     }
+}
 
-    let line = match it.peek() {
-        Some(Term2::DisplayMath(_)) => {
-            let Term2::DisplayMath(x) = it.next().unwrap() else {
-                unreachable!()
-            };
-            check_empty_line(&mut it)?;
-            Line::DisplayMath(x)
+/// The `@anchor`s in what's visited, along with where they are.
+struct Anchors<'a>(Vec<(&'a str, Span)>);
+
+impl<'a> Visit<'a> for Anchors<'a> {
+    fn visit_term(&mut self, term: &'a Spanned<Term<'_>>) {
+        if let Term::Anchor(id) = &term.value {
+            self.0.push((id, term.span));
+        }
+        walk_term(self, term);
+    }
+}
+
+/// What processing a line depends on, other than the line itself.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Context<'a, 'f> {
+    pub functions: &'f Functions,
+    /// The footnotes found so far.
+    pub footnotes: &'f RefCell<Vec<Vec<Spanned<Term<'a>>>>>,
+    /// How deep the line is nested (0 for top-level lines).
+    pub depth: usize,
+}
+
+fn process_node_in<'a>(
+    n: Node2<'a>,
+    cx: Context<'a, '_>,
+    diag: &mut Diagnostics,
+) -> Result<Node<'a>, ParseError> {
+    let footnote_count = cx.footnotes.borrow().len();
+    let line = match process_contents(n.contents, cx) {
+        Ok(x) => x,
+        Err(e) => {
+            // the footnotes of the line went away with it
+            cx.footnotes.borrow_mut().truncate(footnote_count);
+            diag.report(e.clone())?;
+            Line::Error(e)
         }
-        Some(Term2::FuncCall(fc)) => match fc.name.as_str() {
-            "code" => process_code_block_line(extract_only_func(&mut it, "code")?),
-            "dot" => process_dot_line(extract_only_func(&mut it, "dot")?),
-            "table" => process_table_line(extract_only_func(&mut it, "table")?),
-            "image" => process_image_line(extract_only_func(&mut it, "image")?),
-            _ => process_line(&mut it),
-        }?,
-        _ => process_line(&mut it)?,
     };
 
     let mut children = Vec::new();
     for c in n.children.into_iter() {
-        children.push(process_node(c)?);
+        let cx = Context {
+            depth: cx.depth + 1,
+            ..cx
+        };
+        children.push(process_node_in(c, cx, diag)?);
     }
 
     Ok(Node {
         line,
         children,
         bottom_spacing: n.bottom_spacing,
+        span: n.span,
+    })
+}
+
+fn process_contents<'a>(
+    contents: Vec<Spanned<Term2<'a>>>,
+    cx: Context<'a, '_>,
+) -> Result<Line<'a>, ParseError> {
+    if is_rule(&contents) {
+        return Ok(Line::Rule);
+    }
+
+    let mut it = contents.into_iter().peekable();
+
+    fn check_empty_line<'a>(
+        it: &mut impl Iterator<Item = Spanned<Term2<'a>>>,
+    ) -> Result<(), ParseError> {
+        for t in it {
+            match t.value {
+                Term2::Space => {}
+                other => {
+                    return Err(ParseError::new(
+                        ErrorKind::NotAlone,
+                        t.span,
+                        format!("term should be alone in line, got {}", other.describe()),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn extract_only_func<'a>(
+        it: &mut impl Iterator<Item = Spanned<Term2<'a>>>,
+    ) -> Result<Spanned<FuncCall<'a>>, ParseError> {
+        let fc = match it.next() {
+            Some(Spanned {
+                value: Term2::FuncCall(fc),
+                span,
+            }) => Spanned::new(fc, span),
+            Some(other) => {
+                return Err(ParseError::new(
+                    ErrorKind::UnexpectedTerm,
+                    other.span,
+                    format!("expected function call, got {}", other.value.describe()),
+                ));
+            }
+            None => {
+                return Err(ParseError::new(
+                    ErrorKind::UnexpectedTerm,
+                    Span::default(),
+                    "expected function call, got end of line",
+                ));
+            }
+        };
+
+        check_empty_line(it)?;
+        Ok(fc)
+    }
+
+    Ok(match it.peek().map(|t| &t.value) {
+        Some(Term2::Error(_)) => {
+            let Term2::Error(e) = it.next().unwrap().value else {
+                unreachable!()
+            };
+            Line::Error(e)
+        }
+        Some(Term2::DisplayMath(_)) => {
+            let Term2::DisplayMath(x) = it.next().unwrap().value else {
+                unreachable!()
+            };
+            check_empty_line(&mut it)?;
+            Line::DisplayMath(x)
+        }
+        Some(Term2::FuncCall(fc)) => match cx.functions.get(&fc.name) {
+            Some(f) if f.signature().kind == FunctionKind::Block => {
+                let fc = extract_only_func(&mut it)?;
+                check_call(&fc, &f.signature())?;
+                f.block(Call::new(fc, cx))?
+            }
+            _ => process_line(&mut it, cx)?,
+        },
+        _ => process_line(&mut it, cx)?,
     })
 }
 
-fn process_code_block_arg(arg: &str) -> String {
+/// Check if the contents of a line are only `---` (which is a table separator inside `@table`).
+fn is_rule(contents: &[Spanned<Term2>]) -> bool {
+    let mut terms = contents.iter().filter(|t| !matches!(t.value, Term2::Space));
+    let first = terms.next().map(|t| &t.value);
+    matches!(first, Some(Term2::Word(w)) if w == "---") && terms.next().is_none()
+}
+
+/// Get the code from the argument of a code block, without its first and last blank lines and
+/// the indent its lines have in common.
+pub(crate) fn process_code_block_arg(arg: &str) -> String {
     let all_lines: Vec<&str> = arg.split("\n").collect();
 
     let lines = {
@@ -163,7 +686,8 @@ fn process_code_block_arg(arg: &str) -> String {
             false => 0,
         };
 
-        let end_idx = match all_len > 0 && all_lines[all_len - 1].trim().is_empty() {
+        // a single blank line is both the first and the last one
+        let end_idx = match all_len > start_idx && all_lines[all_len - 1].trim().is_empty() {
             true => all_len - 1,
             false => all_len,
         };
@@ -239,143 +763,14 @@ fn process_code_block_arg(arg: &str) -> String {
     ret
 }
 
-fn process_code_block_line(fc: FuncCall) -> Result<Line, String> {
-    match fc.args.len() {
-        1 => {
-            let Some(code) = try_stringify(&fc.args[0]) else {
-                return Err("`@code` call expects string argument, failed to do that...".into());
-            };
-
-            Ok(Line::CodeBlock(CodeBlockLine {
-                lang: None,
-                code: process_code_block_arg(&code),
-            }))
-        }
-        2 => {
-            let Some(lang) = try_stringify(&fc.args[0]) else {
-                return Err("`@code` call expects string argument, failed to do that...".into());
-            };
-
-            let Some(code) = try_stringify(&fc.args[1]) else {
-                return Err("`@code` call expects string argument, failed to do that...".into());
-            };
-
-            Ok(Line::CodeBlock(CodeBlockLine {
-                lang: Some(lang),
-                code: process_code_block_arg(&code),
-            }))
-        }
-        n => Err(format!("`@code` call expects 1 or 2 args, {n} given")),
-    }
-}
-
-fn process_dot_line(fc: FuncCall) -> Result<Line, String> {
-    match fc.args.len() {
-        1 => {
-            let code =
-                try_stringify(&fc.args[0]).ok_or_else(|| format!("failed to stringify arg 1"))?;
-            Ok(Line::DotGraph(DotGraphLine {
-                engine: "dot".into(),
-                code,
-            }))
-        }
-        2 => {
-            let mut it = fc.args.into_iter();
-            let engine = try_stringify(&it.next().unwrap())
-                .ok_or_else(|| format!("failed to stringify arg 1"))?;
-            let code = try_stringify(&it.next().unwrap())
-                .ok_or_else(|| format!("failed to stringify arg 2"))?;
-            Ok(Line::DotGraph(DotGraphLine { engine, code }))
-        }
-        n => Err(format!("`@dot` call expects 1 or 2 arguments, {n} given")),
-    }
-}
-
-fn process_image_line(fc: FuncCall) -> Result<Line, String> {
-    match fc.args.len() {
-        1 => {
-            let url =
-                try_stringify(&fc.args[0]).ok_or_else(|| format!("failed to stringify arg 1"))?;
-            Ok(Line::Image(ImageLine { caption: None, url }))
-        }
-        2 => {
-            let mut it = fc.args.into_iter();
-            let caption = try_stringify(&it.next().unwrap())
-                .ok_or_else(|| format!("failed to stringify arg 1"))?;
-            let url = try_stringify(&it.next().unwrap())
-                .ok_or_else(|| format!("failed to stringify arg 2"))?;
-            Ok(Line::Image(ImageLine {
-                caption: Some(caption),
-                url,
-            }))
-        }
-        n => Err(format!("`@image` call expects 1 or 2 arguments, {n} given")),
-    }
-}
-
-fn process_table_line(mut fc: FuncCall) -> Result<Line, String> {
-    if fc.args.len() != 1 {
-        return Err(format!(
-            "`@table` call expects one argument, {} given",
-            fc.args.len()
-        ));
-    }
-
-    let mut it = fc.args.remove(0).into_iter();
-    let mut get_next = || -> Result<Option<TableItem>, String> {
-        loop {
-            match it.next() {
-                None => return Ok(None),
-                Some(Term2::Space) => {}
-                Some(Term2::List(row)) => {
-                    let mut r = Vec::new();
-                    for arg in row.into_iter() {
-                        r.push(process_terms(&mut arg.into_iter())?);
-                    }
-                    return Ok(Some(TableItem::Row(r)));
-                }
-                Some(Term2::Word(s)) if s == "---" => return Ok(Some(TableItem::Separator)),
-                Some(other) => {
-                    return Err(format!("expected space, list or separator, got {other:?}"));
-                }
-            }
-        }
-    };
-
-    let mut items = Vec::new();
-    let mut last_ncols = None;
-    while let Some(res) = get_next()? {
-        match res {
-            TableItem::Row(r) => {
-                match last_ncols {
-                    Some(x) if x != r.len() => {
-                        return Err(format!(
-                            "got rows of different sizes (first {}, then {})",
-                            x,
-                            r.len()
-                        ));
-                    }
-                    Some(_) => {}
-                    None => last_ncols = Some(r.len()),
-                }
-
-                items.push(TableItem::Row(r));
-            }
-            TableItem::Separator => items.push(TableItem::Separator),
-        }
-    }
-
-    Ok(Line::Table(TableLine {
-        columns: last_ncols.unwrap_or(0),
-        items,
-    }))
-}
-
-fn process_line(it: &mut impl Iterator<Item = Term2>) -> Result<Line, String> {
+fn process_line<'a>(
+    it: &mut impl Iterator<Item = Spanned<Term2<'a>>>,
+    cx: Context<'a, '_>,
+) -> Result<Line<'a>, ParseError> {
     let mut it = it.peekable();
 
-    let bullet = if let Some(Term2::BulletPrefix(_)) = it.peek() {
-        let Term2::BulletPrefix(p) = it.next().unwrap() else {
+    let bullet = if let Some(Term2::BulletPrefix(_)) = it.peek().map(|t| &t.value) {
+        let Term2::BulletPrefix(p) = it.next().unwrap().value else {
             unreachable!()
         };
         Some(p)
@@ -383,8 +778,8 @@ fn process_line(it: &mut impl Iterator<Item = Term2>) -> Result<Line, String> {
         None
     };
 
-    let task = if let Some(Term2::TaskPrefix(_)) = it.peek() {
-        let Term2::TaskPrefix(p) = it.next().unwrap() else {
+    let task = if let Some(Term2::TaskPrefix(_)) = it.peek().map(|t| &t.value) {
+        let Term2::TaskPrefix(p) = it.next().unwrap().value else {
             unreachable!()
         };
         Some(p)
@@ -392,7 +787,7 @@ fn process_line(it: &mut impl Iterator<Item = Term2>) -> Result<Line, String> {
         None
     };
 
-    let content = process_terms(&mut it)?;
+    let content = process_terms(&mut it, cx)?;
 
     Ok(Line::Text(TextLine {
         bullet,
@@ -401,80 +796,72 @@ fn process_line(it: &mut impl Iterator<Item = Term2>) -> Result<Line, String> {
     }))
 }
 
-fn process_terms(it: &mut impl Iterator<Item = Term2>) -> Result<Vec<Term>, String> {
+/// Process the terms of a line (or of an argument that holds text).
+pub(crate) fn process_terms<'a>(
+    it: &mut impl Iterator<Item = Spanned<Term2<'a>>>,
+    cx: Context<'a, '_>,
+) -> Result<Vec<Spanned<Term<'a>>>, ParseError> {
     let mut it = it.peekable();
     let mut ret = Vec::new();
 
-    let mut word_acc = String::new();
+    // adjacent word parts are joined into a single word (which only allocates if there's more than
+    // one part)
+    let mut word_acc: Cow<'a, str> = Cow::Borrowed("");
+    let mut word_span = Span::default();
     loop {
-        if word_acc.len() > 0 {
-            match it.peek() {
-                Some(Term2::Word(x)) => {
-                    word_acc.push_str(x);
+        if !word_acc.is_empty() {
+            match it.peek().map(|t| (&t.value, t.span)) {
+                Some((Term2::Word(x), span)) => {
+                    word_acc.to_mut().push_str(x);
+                    word_span = word_span.to(span);
                     it.next();
                 }
-                Some(Term2::MaybeDelim(x)) => {
-                    word_acc.push(*x);
+                Some((Term2::MaybeDelim(x), span)) => {
+                    word_acc.to_mut().push(*x);
+                    word_span = word_span.to(span);
                     it.next();
                 }
                 _ => {
-                    if is_url(&word_acc) {
-                        ret.push(Term::Url(word_acc));
+                    let word = std::mem::take(&mut word_acc);
+                    if is_url(&word) {
+                        ret.push(Spanned::new(Term::Url(word), word_span));
                     } else {
-                        ret.push(Term::Word(word_acc));
+                        ret.push(Spanned::new(Term::Word(word), word_span));
                     }
-                    word_acc = String::new();
                 }
             }
-        } else if let Some(val) = it.next() {
-            match val {
-                Term2::Space => ret.push(Term::Space),
-                Term2::Word(w) => word_acc = w,
-                Term2::MaybeDelim(c) => word_acc.push(c),
-                Term2::Tag(t) => ret.push(Term::Tag(t)),
-                Term2::InlineMath(x) => ret.push(Term::Math(x)),
-                Term2::InlineCode(x) => ret.push(Term::Code(x)),
-                Term2::InlineBold(x) => ret.push(Term::Bold(x)),
-                Term2::InlineItalics(x) => ret.push(Term::Italics(x)),
-                Term2::FuncCall(fc) => ret.push(match fc.name.as_str() {
-                    "c" => {
-                        if fc.args.len() != 1 {
-                            return Err("`@c` call should have a single argument".into());
-                        } else {
-                            Term::Code(
-                                try_stringify(&fc.args[0])
-                                    .ok_or_else(|| format!("failed to stringify argument"))?,
-                            )
-                        }
-                    }
-                    "ref" => match fc.args.len() {
-                        1 => {
-                            let target = try_stringify(&fc.args[0])
-                                .ok_or_else(|| format!("failed to stringify argument"))?;
-                            let content = vec![Term::Word(target.clone())];
-                            Term::Ref { content, target }
-                        }
-                        2 => {
-                            let mut it = fc.args.into_iter();
-                            let target = try_stringify(&it.next().unwrap())
-                                .ok_or_else(|| format!("failed to stringify argument"))?;
-                            let content = process_terms(&mut it.next().unwrap().into_iter())?;
-                            Term::Ref { content, target }
-                        }
-                        n => return Err(format!("`@ref` call must have 1 or 2 args, got {n}")),
-                    },
-                    name @ ("code" | "dot" | "table" | "image") => {
-                        return Err(format!(
-                            "function {name:?} should be on the beginning of the line"
-                        ))
-                    }
-                    name => return Err(format!("unknown function {name:?}")),
-                }),
+        } else if let Some(Spanned { value: val, span }) = it.next() {
+            let term = match val {
+                Term2::Space => Term::Space,
+                Term2::Word(w) => {
+                    word_acc = w;
+                    word_span = span;
+                    continue;
+                }
+                Term2::MaybeDelim(c) => {
+                    word_acc = Cow::Owned(c.to_string());
+                    word_span = span;
+                    continue;
+                }
+                Term2::Tag(t) => Term::Tag(t),
+                Term2::InlineMath(x) => Term::Math(x),
+                Term2::InlineCode(x) => Term::Code(x),
+                Term2::InlineBold(x) => Term::Bold(process_terms(&mut x.into_iter(), cx)?),
+                Term2::InlineItalics(x) => Term::Italics(process_terms(&mut x.into_iter(), cx)?),
+                Term2::FuncCall(fc) => process_inline_func(Spanned::new(fc, span), cx)?,
                 Term2::List(_)
                 | Term2::DisplayMath(_)
                 | Term2::BulletPrefix(_)
-                | Term2::TaskPrefix(_) => return Err(format!("unexpected {val:?}")),
-            }
+                | Term2::TaskPrefix(_)
+                | Term2::Error(_) => {
+                    return Err(ParseError::new(
+                        ErrorKind::UnexpectedTerm,
+                        span,
+                        format!("unexpected {}", val.describe()),
+                    ));
+                }
+            };
+            ret.push(Spanned::new(term, span));
         } else {
             break;
         }
@@ -483,13 +870,40 @@ fn process_terms(it: &mut impl Iterator<Item = Term2>) -> Result<Vec<Term>, Stri
     Ok(ret)
 }
 
+fn process_inline_func<'a>(
+    fc: Spanned<FuncCall<'a>>,
+    cx: Context<'a, '_>,
+) -> Result<Term<'a>, ParseError> {
+    let Some(f) = cx.functions.get(&fc.name) else {
+        return Err(ParseError::new(
+            ErrorKind::UnknownFunction,
+            fc.span,
+            format!("unknown function {:?}", fc.name),
+        ));
+    };
+
+    let signature = f.signature();
+    if signature.kind == FunctionKind::Block {
+        return Err(ParseError::new(
+            ErrorKind::MisplacedFunction,
+            fc.span,
+            format!(
+                "function {:?} should be on the beginning of the line",
+                fc.name
+            ),
+        ));
+    }
+
+    check_call(&fc, &signature)?;
+    f.inline(Call::new(fc, cx))
+}
+
 #[rustfmt::skip]
 pub fn is_url(s: &str) -> bool {
     let mut it = s.chars().peekable();
 
     let mut pfx_cnt: usize = 0;
-    loop {
-        let Some(c) = it.peek() else { break; };
+    while let Some(c) = it.peek() {
         if !c.is_ascii_alphabetic() { break; }
         _ = it.next();
         pfx_cnt += 1;
@@ -502,8 +916,7 @@ pub fn is_url(s: &str) -> bool {
     let Some('/') = it.next() else { return false; };
     let Some('/') = it.next() else { return false; };
 
-    loop {
-        let Some(c) = it.peek() else { break; };
+    while let Some(c) = it.peek() {
         if is::inline_whitespace(*c) { return false; }
         _ = it.next();
     }
@@ -511,31 +924,381 @@ pub fn is_url(s: &str) -> bool {
     it.next().is_none()
 }
 
-fn try_stringify(terms: &[Term2]) -> Option<String> {
-    let mut ret = String::new();
-
-    for t in terms {
-        match t {
-            Term2::Space => ret.push(' '),
-            Term2::Word(w) => ret.push_str(w),
-            Term2::MaybeDelim(c) => ret.push(*c),
-            x => {
-                eprintln!("info: stringify failed because found {x:?}");
-                return None;
-            }
-        }
+/// Whether `target` can be linked to: a path, or a URL accepted by [`is_url`] whose scheme can't
+/// run code (unlike `javascript://`).
+pub fn is_link_target(target: &str) -> bool {
+    // browsers skip these when reading a link, so they could hide a scheme
+    let hidden = |c: char| c.is_ascii_whitespace() || c.is_ascii_control();
+    if target.starts_with(hidden) || target.contains(['\t', '\n', '\r']) {
+        return false;
     }
 
-    Some(ret)
+    let before_path = &target[..target.find(['/', '?', '#']).unwrap_or(target.len())];
+    let Some((scheme, _)) = before_path.split_once(':') else {
+        return true;
+    };
+
+    let runs_code = ["javascript", "vbscript", "data"]
+        .iter()
+        .any(|x| scheme.eq_ignore_ascii_case(x));
+    is_url(target) && !runs_code
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn node_and_term_spans() {
+        let doc = crate::parser::parse("foo\n  bar(baz) ${x}\n").unwrap();
+        let child = &doc.nodes[0].children[0];
+        assert_eq!(
+            (child.span.start, child.span.end, child.span.line),
+            (4, 19, 2)
+        );
+
+        let Line::Text(ref l) = child.line else {
+            panic!()
+        };
+        assert_eq!(l.content[0].value, Term::Word("bar(baz)".into()));
+        assert_eq!((l.content[0].span.start, l.content[0].span.end), (6, 14));
+        assert_eq!(
+            (l.content[2].span.start, l.content[2].span.column),
+            (15, 12)
+        );
+    }
+
+    fn parse_line(input: &str) -> Result<Line<'_>, ParseError> {
+        crate::parser::parse(input).map(|mut doc| doc.nodes.remove(0).line)
+    }
+
+    #[test]
+    fn keyword_args() {
+        let Ok(Line::CodeBlock(x)) = parse_line("@code[lang=rust]{let x = 1;}") else {
+            panic!()
+        };
+        assert_eq!(x.lang.as_deref(), Some("rust"));
+
+        let Ok(Line::DotGraph(x)) = parse_line("@dot[engine=fdp]#{a -> b}#") else {
+            panic!()
+        };
+        assert_eq!(x.engine, "fdp");
+
+        let Ok(Line::Image(x)) = parse_line("@image[width=300 caption={A cat}]{cat.png}") else {
+            panic!()
+        };
+        assert_eq!(x.caption.as_deref(), Some("A cat"));
+        assert_eq!(x.width.as_deref(), Some("300"));
+
+        let Ok(Line::Table(x)) = parse_line("@table[header=no caption=Foo]{@{a}{b}}") else {
+            panic!()
+        };
+        assert!(!x.header);
+        assert_eq!(x.caption.as_deref(), Some("Foo"));
+
+        let bad = [
+            "@code[foo=bar]{x}",
+            "@code[lang=a lang=b]{x}",
+            "@code[lang=a]{python}{x}",
+            "@table[header=maybe]{@{a}}",
+            "foo @c[x=y]{z}",
+        ];
+        for input in bad {
+            let err = parse_line(input).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::BadArguments, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn inline_functions() {
+        let Ok(Line::Text(l)) =
+            parse_line("@s{old} @u{*u*} @mark{m} x@sup{2} H@sub{2}O @kbd{Ctrl+C}")
+        else {
+            panic!()
+        };
+        let terms: Vec<_> = l.content.iter().map(|t| &t.value).collect();
+        assert!(matches!(terms[0], Term::Strikethrough(_)));
+        assert!(matches!(terms[2], Term::Underline(x) if matches!(x[0].value, Term::Bold(_))));
+        assert!(matches!(terms[4], Term::Highlight(_)));
+        assert!(matches!(terms[7], Term::Superscript(_)));
+        assert!(matches!(terms[13], Term::Kbd(x) if x == "Ctrl+C"));
+        assert_eq!(plain_text(&l.content), "old u m x2 H2O Ctrl+C");
+
+        // not a call, so it's still a single word
+        let Ok(Line::Text(l)) = parse_line("user@example.com") else {
+            panic!()
+        };
+        assert!(matches!(&l.content[..], [t] if t.value == Term::Word("user@example.com".into())));
+
+        for input in ["@s{a}{b}", "@kbd{*x*}", "@sup[x=y]{2}"] {
+            let err = parse_line(input).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::BadArguments, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn headings() {
+        let doc = crate::parser::parse(concat!(
+            "@h{What's *new*?}\n",
+            "  @h{Parser}\n",
+            "    @h[level=1 id=top]{Back up}\n",
+            "      @h{x}\n",
+            "        @h{y}\n",
+            "          @h{Still 5}\n",
+        ))
+        .unwrap();
+
+        let mut headings = Vec::new();
+        let mut nodes = &doc.nodes;
+        while let [node, ..] = &nodes[..] {
+            let Line::Heading(ref h) = node.line else {
+                panic!()
+            };
+            headings.push((h.level, &*h.id));
+            nodes = &node.children;
+        }
+        assert_eq!(
+            headings,
+            [
+                (1, "what-s-new"),
+                (2, "parser"),
+                (1, "top"),
+                (4, "x"),
+                (5, "y"),
+                (5, "still-5")
+            ]
+        );
+
+        for input in [
+            "@h[level=0]{x}",
+            "@h[level=6]{x}",
+            "@h[level=a]{x}",
+            "@h{a}{b}",
+        ] {
+            let err = parse_line(input).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::BadArguments, "{input:?}");
+        }
+        let err = parse_line("foo @h{x}").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::MisplacedFunction);
+    }
+
+    #[test]
+    fn unique_heading_ids() {
+        let doc = crate::parser::parse(concat!(
+            "@h{Intro}\n",
+            "  @h{Intro}\n",
+            "@h{?!}\n",
+            "@h{Intro 2}\n",
+            "@h[id=intro-3]{x}\n",
+            "@h[id={}]{Intro}\n",
+        ))
+        .unwrap();
+
+        let mut ids = Vec::new();
+        let n = &doc.nodes;
+        for node in [&n[0], &n[0].children[0], &n[1], &n[2], &n[3], &n[4]] {
+            let Line::Heading(ref h) = node.line else {
+                panic!()
+            };
+            ids.push((&*h.id, h.explicit_id));
+        }
+        assert_eq!(
+            ids,
+            [
+                ("intro", false),
+                ("intro-2", false),
+                ("section-3", false),
+                ("intro-2-2", false),
+                ("intro-3", true),
+                ("intro-4", false),
+            ]
+        );
+
+        let err = crate::parser::parse("@h{A}\n@h[id=x]{b}\n  @h[id=a]{c}\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::DuplicateId);
+        assert_eq!(err.span.line, 3);
+    }
+
+    #[test]
+    fn heading_ids_and_anchors() {
+        // auto IDs go around anchors, even later ones
+        let input = "@h{Intro}\n  x @anchor{intro}@fn{@anchor{intro-2}}\n";
+        let doc = crate::parser::parse(input).unwrap();
+        let Line::Heading(ref h) = doc.nodes[0].line else {
+            panic!()
+        };
+        assert_eq!(h.id, "intro-3");
+
+        let errors = |input| {
+            let (_, errors) = crate::parser::parse_recovering(input);
+            let errors: Vec<_> = errors.iter().map(|e| (e.kind, e.span.line)).collect();
+            errors
+        };
+        let dup = ErrorKind::DuplicateId;
+        assert_eq!(errors("a @anchor{x}\nb @anchor{x}\n"), [(dup, 2)]);
+        assert_eq!(errors("a @anchor{x}\n@h[id=x]{b}\n"), [(dup, 2)]);
+        // (anchors are taken first, so the heading is the duplicate)
+        assert_eq!(errors("@h[id=x]{b}\na @fn{@anchor{x}}\n"), [(dup, 1)]);
+        assert_eq!(errors("a @anchor{x}@anchor{y}\n@h{X}\n"), []);
+    }
+
+    #[test]
+    fn quotes() {
+        let doc = crate::parser::parse("@quote[by={A. Person}]{*Hi*, there}\n  more\n").unwrap();
+        let Line::Quote(ref q) = doc.nodes[0].line else {
+            panic!()
+        };
+        assert!(matches!(q.content[0].value, Term::Bold(_)));
+        assert_eq!(q.attribution.as_deref(), Some("A. Person"));
+        assert_eq!(doc.nodes[0].children.len(), 1);
+
+        let Ok(Line::Quote(q)) = parse_line("@quote[by=me]") else {
+            panic!()
+        };
+        assert!(q.content.is_empty());
+
+        let err = parse_line("@quote{a}{b}").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadArguments);
+    }
+
+    #[test]
+    fn rules() {
+        assert_eq!(parse_line("---"), Ok(Line::Rule));
+        assert_eq!(parse_line("--- %% comment"), Ok(Line::Rule));
+        for input in ["- ---", "--- x", "----", "*---*"] {
+            assert!(matches!(parse_line(input), Ok(Line::Text(_))), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn links() {
+        let Ok(Line::Text(l)) = parse_line("@link{../a b.acr}{*the* note} @link{x.acr}") else {
+            panic!()
+        };
+        let Term::Link { content, target } = &l.content[0].value else {
+            panic!()
+        };
+        assert_eq!(target, "../a b.acr");
+        assert_eq!(plain_text(content), "the note");
+        assert_eq!(plain_text(&l.content), "the note x.acr");
+
+        let err = parse_line("@link{a}{b}{c}").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadArguments);
+
+        for target in [
+            "javascript:alert(1)",
+            "data:text/html,x",
+            " javascript:alert(1)",
+        ] {
+            let err = parse_line(&format!("@link{{{target}}}{{x}}")).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::BadArguments, "{target:?}");
+        }
+    }
+
+    #[test]
+    fn footnotes() {
+        let doc = crate::parser::parse("a@fn{*one*}\n  b@fn{two @fn{three}} c@fn{four}\n").unwrap();
+        let numbers = |terms: &[Spanned<Term>]| -> Vec<usize> {
+            let mut ret = Vec::new();
+            for t in terms {
+                if let Term::Footnote(n) = t.value {
+                    ret.push(n);
+                }
+            }
+            ret
+        };
+
+        let Line::Text(ref l) = doc.nodes[0].line else {
+            panic!()
+        };
+        assert_eq!(numbers(&l.content), [1]);
+        let Line::Text(ref l) = doc.nodes[0].children[0].line else {
+            panic!()
+        };
+        assert_eq!(numbers(&l.content), [2, 4]);
+
+        let texts: Vec<_> = doc.footnotes.iter().map(|f| plain_text(f)).collect();
+        assert_eq!(texts, ["one", "two ", "three", "four"]);
+        assert_eq!(numbers(&doc.footnotes[1]), [3]);
+
+        // the footnotes of a line that failed to parse are left out
+        let (doc, _) = crate::parser::parse_recovering("a@fn{x} *b\nc@fn{y}\n");
+        assert!(matches!(doc.nodes[0].line, Line::Error(_)));
+        let texts: Vec<_> = doc.footnotes.iter().map(|f| plain_text(f)).collect();
+        assert_eq!(texts, ["y"]);
+    }
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slug("  Hello, World!  "), "hello-world");
+        assert_eq!(slug("Ação 2"), "ação-2");
+        assert_eq!(slug("--"), "");
+    }
+
+    #[test]
+    fn borrowed_text() {
+        let source = String::from("foo `code` ${x} %tag http://x.org \\*esc @kbd{K}\n");
+        let Ok(Line::Text(l)) = parse_line(&source) else {
+            panic!()
+        };
+
+        let is_borrowed = |t: &Term| match t {
+            Term::Word(x)
+            | Term::Code(x)
+            | Term::Math(x)
+            | Term::Tag(x)
+            | Term::Url(x)
+            | Term::Kbd(x) => {
+                matches!(x, Cow::Borrowed(_))
+            }
+            _ => panic!("unexpected {t:?}"),
+        };
+        let texts: Vec<_> = l
+            .content
+            .iter()
+            .filter(|t| t.value != Term::Space)
+            .collect();
+        let borrowed: Vec<_> = texts.iter().map(|t| is_borrowed(t)).collect();
+        assert_eq!(borrowed, [true, true, true, true, true, false, true]);
+
+        fn detached(source: String) -> Document<'static> {
+            crate::parser::parse(&source).unwrap().into_owned()
+        }
+        let doc = detached(source);
+        let Line::Text(ref l) = doc.nodes[0].line else {
+            panic!()
+        };
+        assert_eq!(l.content[0].value, Term::Word("foo".into()));
+    }
+
     #[test]
     fn valid_urls() {
         assert!(!is_url(""));
         assert!(is_url("https://google.com/"));
     }
+
+    #[test]
+    fn link_targets() {
+        for target in [
+            "",
+            "a b.acr",
+            "../x.acr#y",
+            "/abs",
+            "#frag",
+            "a/b:c",
+            "http://x.org/a:b",
+        ] {
+            assert!(is_link_target(target), "{target:?}");
+        }
+        for target in [
+            "javascript:alert(1)",
+            "JavaScript://%0aalert(1)",
+            "data:text/html,x",
+            " javascript:alert(1)",
+            "java\tscript:alert(1)",
+            "mailto:a@b.c",
+        ] {
+            assert!(!is_link_target(target), "{target:?}");
+        }
+    }
 }
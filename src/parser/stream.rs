@@ -0,0 +1,287 @@
+//! Parsing documents as they're read, for files too big to keep in memory.
+//!
+//! Lines are read from a [`BufRead`] and put in the tree as they come, and each top-level node is
+//! processed and handed out as soon as the next one starts. Only the current top-level node (and
+//! what's left of the read buffer) is kept around from the text.
+//!
+//! Some things are about the whole document, though, and are kept until the end: the footnotes
+//! (which are written after the nodes), the anchors and refs (to check the refs at the end), and
+//! the IDs taken by headings and anchors (to keep them unique). They grow with the number of
+//! footnotes, anchors, refs and headings, so memory isn't flat for documents that have a lot of
+//! them.
+
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use crate::parser::refs::RefIndex;
+use crate::parser::stage1::LineReader;
+use crate::parser::stage2::TreeBuilder;
+use crate::parser::stage3::HeadingIds;
+use crate::parser::{
+    Diagnostics, Functions, Node3, ParseError, ReadError, Spanned, StandardOptions, Term3, stage3,
+};
+
+/// Parses a document from `R` one top-level node at a time.
+///
+/// The nodes are the same as the ones from [`parse`](crate::parser::parse), except that:
+///
+/// - without an `%:indent` header entry, the indent unit is inferred from the start of the body
+///   only;
+/// - headings without an explicit ID only go around the IDs of the anchors before them (and in
+///   the same node), so an anchor with the ID a heading got before it is a duplicate.
+#[derive(Debug)]
+pub struct StreamParser<R> {
+    lines: LineReader<R>,
+    tree: TreeBuilder<'static>,
+    header: HashMap<String, String>,
+    options: StandardOptions,
+    /// The footnotes of the nodes read so far, kept until the end.
+    footnotes: Vec<Vec<Spanned<Term3<'static>>>>,
+    /// The heading and anchor IDs taken by the nodes read so far, kept until the end.
+    heading_ids: HeadingIds,
+    /// The anchors and refs of the nodes read so far, kept to check the refs at the end.
+    refs: RefIndex,
+    diag: Diagnostics,
+    /// Where the node being read starts (the text before it can be let go of).
+    node_start: usize,
+    done: bool,
+}
+
+impl<R: BufRead> StreamParser<R> {
+    /// Read the header, stopping at the first error.
+    pub fn new(reader: R) -> Result<Self, ReadError> {
+        Self::with(reader, Diagnostics::strict())
+    }
+
+    /// Read the header, without stopping at errors (see [`Self::take_errors`]).
+    ///
+    /// Bad lines are replaced by [`stage3::Line::Error`], as with
+    /// [`parse_recovering`](crate::parser::parse_recovering).
+    pub fn recovering(reader: R) -> Result<Self, ReadError> {
+        Self::with(reader, Diagnostics::recovering())
+    }
+
+    fn with(reader: R, mut diag: Diagnostics) -> Result<Self, ReadError> {
+        let (header, options, lines) = LineReader::new(reader, &mut diag)?;
+
+        Ok(Self {
+            lines,
+            tree: TreeBuilder::new(),
+            header,
+            options,
+            footnotes: Vec::new(),
+            heading_ids: HeadingIds::new(),
+            refs: RefIndex::new(),
+            diag,
+            node_start: 0,
+            done: false,
+        })
+    }
+
+    pub fn header(&self) -> &HashMap<String, String> {
+        &self.header
+    }
+
+    pub fn options(&self) -> &StandardOptions {
+        &self.options
+    }
+
+    /// The footnotes of the nodes read so far (see [`stage3::Document::footnotes`]).
+    ///
+    /// Unlike the nodes, they're kept until the end, so they can be written after them.
+    pub fn footnotes(&self) -> &[Vec<Spanned<Term3<'static>>>] {
+        &self.footnotes
+    }
+
+    /// Read up to the end of the next top-level node, and return it (or `None` at the end of the
+    /// document).
+    ///
+    /// After an error (in strict mode, or when reading fails), there are no more nodes.
+    pub fn next_node(&mut self) -> Result<Option<Node3<'static>>, ReadError> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let result = self.read_node();
+        if !matches!(result, Ok(Some(_))) {
+            self.done = true;
+        }
+        result
+    }
+
+    fn read_node(&mut self) -> Result<Option<Node3<'static>>, ReadError> {
+        // the text of the previous node was only kept to show its errors
+        self.lines.release(self.node_start);
+        let footnote_count = self.footnotes.len();
+
+        let mut node = loop {
+            match self.lines.next_line(&self.options, &mut self.diag)? {
+                Some(line) => {
+                    let start = line.span.start;
+                    if let Some(node) = self.tree.push(line, &mut self.diag)? {
+                        self.node_start = start;
+                        break Some(stage3::process_node(
+                            node,
+                            Functions::builtin(),
+                            &mut self.footnotes,
+                            &mut self.diag,
+                        )?);
+                    }
+                }
+                None => match std::mem::take(&mut self.tree).finish() {
+                    Some(node) => {
+                        break Some(stage3::process_node(
+                            node,
+                            Functions::builtin(),
+                            &mut self.footnotes,
+                            &mut self.diag,
+                        )?);
+                    }
+                    None => break None,
+                },
+            }
+        };
+
+        match &mut node {
+            Some(node) => {
+                self.heading_ids.add_anchors(node, &mut self.diag)?;
+                for content in &self.footnotes[footnote_count..] {
+                    self.heading_ids
+                        .add_footnote_anchors(content, &mut self.diag)?;
+                }
+                self.heading_ids.assign(node, &mut self.diag)?;
+                self.refs.add_node(node);
+            }
+            None => {
+                for content in &self.footnotes {
+                    self.refs.add_terms(content);
+                }
+                for e in self.refs.dangling() {
+                    self.diag.report(e)?;
+                }
+            }
+        }
+
+        Ok(node)
+    }
+
+    /// Take the errors found so far, when recovering.
+    ///
+    /// They come in the order they were found, which isn't always the order they appear in.
+    pub fn take_errors(&mut self) -> Vec<ParseError> {
+        self.diag.take_errors()
+    }
+
+    /// Show the line where `error` happened (see [`ParseError::excerpt`]).
+    ///
+    /// Only works for errors from the last node read (or, in strict mode, the error that stopped
+    /// the parse), as the text before it is let go.
+    pub fn excerpt(&self, error: &ParseError) -> Option<String> {
+        self.lines.excerpt(error)
+    }
+}
+
+impl<R: BufRead> Iterator for StreamParser<R> {
+    type Item = Result<Node3<'static>, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_node().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{ErrorKind, parse, parse_recovering};
+    use std::io::BufReader;
+
+    type Footnotes = Vec<Vec<Spanned<Term3<'static>>>>;
+
+    /// Parse `input` as a stream, reading a few bytes at a time.
+    fn parse_stream(
+        input: &str,
+        recover: bool,
+    ) -> Result<(Vec<Node3<'static>>, Footnotes, Vec<ParseError>), ReadError> {
+        let reader = BufReader::with_capacity(7, input.as_bytes());
+        let mut parser = match recover {
+            true => StreamParser::recovering(reader)?,
+            false => StreamParser::new(reader)?,
+        };
+
+        let nodes = parser.by_ref().collect::<Result<Vec<_>, _>>()?;
+        let mut errors = parser.take_errors();
+        errors.sort_by_key(|e| e.span.start);
+        Ok((nodes, parser.footnotes().to_vec(), errors))
+    }
+
+    fn assert_same_as_full_parse(input: &str) {
+        let full = parse(input).map(|doc| format!("{:?}", (doc.nodes, doc.footnotes)));
+        let streamed = parse_stream(input, false)
+            .map(|(nodes, footnotes, _)| format!("{:?}", (nodes, footnotes)));
+        match (full, streamed) {
+            (Ok(a), Ok(b)) => assert_eq!(a, b, "{input:?}"),
+            (Err(a), Err(ReadError::Parse(b))) => assert_eq!(a, b, "{input:?}"),
+            (a, b) => panic!("{input:?}: {a:?} vs {b:?}"),
+        }
+
+        let (doc, errors) = parse_recovering(input);
+        let (nodes, footnotes, streamed_errors) = parse_stream(input, true).unwrap();
+        assert_eq!(
+            format!("{:?}", (doc.nodes, doc.footnotes)),
+            format!("{:?}", (nodes, footnotes)),
+            "{input:?}"
+        );
+        assert_eq!(errors, streamed_errors, "{input:?}");
+    }
+
+    #[test]
+    fn same_as_full_parse() {
+        let input = include_str!("../../examples/all.acr");
+        assert_same_as_full_parse(input);
+
+        let boundaries = (0..input.len()).filter(|&i| input.is_char_boundary(i));
+        for i in boundaries.step_by(97) {
+            assert_same_as_full_parse(&input[..i]);
+            assert_same_as_full_parse(&format!("{}@x{{\\{}", &input[..i], &input[i..]));
+        }
+    }
+
+    #[test]
+    fn anchors_after_headings() {
+        let input = "@h{X}\na @anchor{x}\n";
+        let (nodes, _, errors) = parse_stream(input, true).unwrap();
+        let stage3::Line::Heading(ref h) = nodes[0].line else {
+            panic!()
+        };
+        assert_eq!(h.id, "x");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            (errors[0].kind, errors[0].span.line),
+            (ErrorKind::DuplicateId, 2)
+        );
+
+        // unlike when the whole document is there
+        let doc = parse(input).unwrap();
+        let stage3::Line::Heading(ref h) = doc.nodes[0].line else {
+            panic!()
+        };
+        assert_eq!(h.id, "x-2");
+    }
+
+    #[test]
+    fn lines_past_the_read_ahead() {
+        // enough lines that the ones at the end are only read as they're needed
+        let filler = "some text\n  a child\n\n".repeat(8000);
+        let tails = [
+            "@code{\n  multi-line\n\n  code\n}\nafter\n",
+            "unterminated @f{\n  never closed\n\nnext\n",
+            "$${ x\n+ y }\n",
+            "last line without a newline",
+            "ends on a CR\r",
+        ];
+
+        for tail in tails {
+            assert_same_as_full_parse(&format!("%:indent 2\n\n{filler}{tail}"));
+        }
+    }
+}
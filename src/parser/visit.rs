@@ -0,0 +1,464 @@
+//! Walking a processed [`Document`], without writing the recursion by hand.
+//!
+//! [`Visit`] goes through the document by reference, and [`VisitMut`] mutably. Every method has
+//! a default that keeps walking into what's inside (with the `walk_*` functions), so implementing
+//! one only takes overriding the methods for what it cares about, and it keeps working when new
+//! kinds of lines or terms are added.
+//!
+//! ```
+//! use acrylic_parser::parser::visit::Visit;
+//!
+//! #[derive(Default)]
+//! struct Tags<'a>(Vec<&'a str>);
+//!
+//! impl<'a> Visit<'a> for Tags<'a> {
+//!     fn visit_tag(&mut self, tag: &'a str) {
+//!         self.0.push(tag);
+//!     }
+//! }
+//!
+//! let doc = acrylic_parser::parse("foo %a\n  bar %b\n").unwrap();
+//! let mut tags = Tags::default();
+//! tags.visit_document(&doc);
+//! assert_eq!(tags.0, ["a", "b"]);
+//! ```
+
+use std::borrow::Cow;
+
+use crate::parser::stage3::{
+    CodeBlockLine, Document, DotGraphLine, HeadingLine, ImageLine, Line, Node, QuoteLine,
+    TableItem, TableLine, Term, TextLine,
+};
+use crate::parser::{ParseError, Spanned};
+
+/// A walk through a document, by reference.
+///
+/// The lifetime is the one of the borrow of the document, so what's visited can be kept around
+/// for as long as the document is.
+pub trait Visit<'a> {
+    fn visit_document(&mut self, doc: &'a Document<'_>) {
+        walk_document(self, doc);
+    }
+
+    /// Called before visiting the line and the children of `node`. Top-level nodes have a depth
+    /// of 0.
+    fn enter_node(&mut self, _node: &'a Node<'_>, _depth: usize) {}
+
+    /// Called after visiting the line and the children of `node`.
+    fn exit_node(&mut self, _node: &'a Node<'_>, _depth: usize) {}
+
+    fn visit_node(&mut self, node: &'a Node<'_>, depth: usize) {
+        walk_node(self, node, depth);
+    }
+
+    fn visit_line(&mut self, line: &'a Line<'_>) {
+        walk_line(self, line);
+    }
+
+    fn visit_text_line(&mut self, line: &'a TextLine<'_>) {
+        self.visit_terms(&line.content);
+    }
+
+    fn visit_heading(&mut self, heading: &'a HeadingLine<'_>) {
+        self.visit_terms(&heading.content);
+    }
+
+    fn visit_quote(&mut self, quote: &'a QuoteLine<'_>) {
+        self.visit_terms(&quote.content);
+    }
+
+    fn visit_table(&mut self, table: &'a TableLine<'_>) {
+        walk_table(self, table);
+    }
+
+    fn visit_image(&mut self, _image: &'a ImageLine) {}
+    fn visit_dot_graph(&mut self, _graph: &'a DotGraphLine<'_>) {}
+    fn visit_code_block(&mut self, _code: &'a CodeBlockLine) {}
+    fn visit_display_math(&mut self, _math: &'a str) {}
+    fn visit_rule(&mut self) {}
+    fn visit_error(&mut self, _error: &'a ParseError) {}
+
+    fn visit_terms(&mut self, terms: &'a [Spanned<Term<'_>>]) {
+        for t in terms {
+            self.visit_term(t);
+        }
+    }
+
+    fn visit_term(&mut self, term: &'a Spanned<Term<'_>>) {
+        walk_term(self, term);
+    }
+
+    fn visit_space(&mut self) {}
+    fn visit_word(&mut self, _word: &'a str) {}
+    fn visit_tag(&mut self, _tag: &'a str) {}
+    fn visit_url(&mut self, _url: &'a str) {}
+    fn visit_math(&mut self, _math: &'a str) {}
+    fn visit_code(&mut self, _code: &'a str) {}
+    fn visit_kbd(&mut self, _keys: &'a str) {}
+
+    fn visit_anchor(&mut self, _id: &'a str) {}
+
+    /// Called for the marker of a footnote (its content is visited after the nodes).
+    fn visit_footnote_ref(&mut self, _number: usize) {}
+
+    fn visit_footnote(&mut self, _number: usize, content: &'a [Spanned<Term<'_>>]) {
+        self.visit_terms(content);
+    }
+
+    fn visit_ref(&mut self, content: &'a [Spanned<Term<'_>>], _target: &'a str) {
+        self.visit_terms(content);
+    }
+
+    fn visit_link(&mut self, content: &'a [Spanned<Term<'_>>], _target: &'a str) {
+        self.visit_terms(content);
+    }
+
+    fn visit_bold(&mut self, terms: &'a [Spanned<Term<'_>>]) {
+        self.visit_terms(terms);
+    }
+
+    fn visit_italics(&mut self, terms: &'a [Spanned<Term<'_>>]) {
+        self.visit_terms(terms);
+    }
+
+    fn visit_strikethrough(&mut self, terms: &'a [Spanned<Term<'_>>]) {
+        self.visit_terms(terms);
+    }
+
+    fn visit_underline(&mut self, terms: &'a [Spanned<Term<'_>>]) {
+        self.visit_terms(terms);
+    }
+
+    fn visit_highlight(&mut self, terms: &'a [Spanned<Term<'_>>]) {
+        self.visit_terms(terms);
+    }
+
+    fn visit_superscript(&mut self, terms: &'a [Spanned<Term<'_>>]) {
+        self.visit_terms(terms);
+    }
+
+    fn visit_subscript(&mut self, terms: &'a [Spanned<Term<'_>>]) {
+        self.visit_terms(terms);
+    }
+}
+
+/// Visit the nodes, and then the content of each footnote.
+pub fn walk_document<'a, V: Visit<'a> + ?Sized>(v: &mut V, doc: &'a Document<'_>) {
+    for node in &doc.nodes {
+        v.visit_node(node, 0);
+    }
+    for (i, content) in doc.footnotes.iter().enumerate() {
+        v.visit_footnote(i + 1, content);
+    }
+}
+
+pub fn walk_node<'a, V: Visit<'a> + ?Sized>(v: &mut V, node: &'a Node<'_>, depth: usize) {
+    v.enter_node(node, depth);
+    v.visit_line(&node.line);
+    for child in &node.children {
+        v.visit_node(child, depth + 1);
+    }
+    v.exit_node(node, depth);
+}
+
+pub fn walk_line<'a, V: Visit<'a> + ?Sized>(v: &mut V, line: &'a Line<'_>) {
+    match line {
+        Line::Text(l) => v.visit_text_line(l),
+        Line::Heading(l) => v.visit_heading(l),
+        Line::Quote(l) => v.visit_quote(l),
+        Line::Table(l) => v.visit_table(l),
+        Line::Image(l) => v.visit_image(l),
+        Line::DotGraph(l) => v.visit_dot_graph(l),
+        Line::CodeBlock(l) => v.visit_code_block(l),
+        Line::DisplayMath(x) => v.visit_display_math(x),
+        Line::Rule => v.visit_rule(),
+        Line::Error(e) => v.visit_error(e),
+    }
+}
+
+pub fn walk_table<'a, V: Visit<'a> + ?Sized>(v: &mut V, table: &'a TableLine<'_>) {
+    for item in &table.items {
+        if let TableItem::Row(cells) = item {
+            for cell in cells {
+                v.visit_terms(cell);
+            }
+        }
+    }
+}
+
+pub fn walk_term<'a, V: Visit<'a> + ?Sized>(v: &mut V, term: &'a Spanned<Term<'_>>) {
+    match &term.value {
+        Term::Space => v.visit_space(),
+        Term::Word(x) => v.visit_word(x),
+        Term::Tag(x) => v.visit_tag(x),
+        Term::Url(x) => v.visit_url(x),
+        Term::Math(x) => v.visit_math(x),
+        Term::Code(x) => v.visit_code(x),
+        Term::Kbd(x) => v.visit_kbd(x),
+        Term::Anchor(x) => v.visit_anchor(x),
+        Term::Footnote(n) => v.visit_footnote_ref(*n),
+        Term::Ref { content, target } => v.visit_ref(content, target),
+        Term::Link { content, target } => v.visit_link(content, target),
+        Term::Bold(x) => v.visit_bold(x),
+        Term::Italics(x) => v.visit_italics(x),
+        Term::Strikethrough(x) => v.visit_strikethrough(x),
+        Term::Underline(x) => v.visit_underline(x),
+        Term::Highlight(x) => v.visit_highlight(x),
+        Term::Superscript(x) => v.visit_superscript(x),
+        Term::Subscript(x) => v.visit_subscript(x),
+    }
+}
+
+/// A walk through a document that can change it along the way.
+///
+/// Text is given as the [`Cow`] it's stored in, so it can be replaced with owned text (which works
+/// whatever the lifetime of the document is).
+pub trait VisitMut {
+    fn visit_document_mut(&mut self, doc: &mut Document<'_>) {
+        walk_document_mut(self, doc);
+    }
+
+    /// Called before visiting the line and the children of `node` (so changes to them are
+    /// visited too). Top-level nodes have a depth of 0.
+    fn enter_node_mut(&mut self, _node: &mut Node<'_>, _depth: usize) {}
+
+    /// Called after visiting the line and the children of `node`.
+    fn exit_node_mut(&mut self, _node: &mut Node<'_>, _depth: usize) {}
+
+    fn visit_node_mut(&mut self, node: &mut Node<'_>, depth: usize) {
+        walk_node_mut(self, node, depth);
+    }
+
+    fn visit_line_mut(&mut self, line: &mut Line<'_>) {
+        walk_line_mut(self, line);
+    }
+
+    fn visit_text_line_mut(&mut self, line: &mut TextLine<'_>) {
+        self.visit_terms_mut(&mut line.content);
+    }
+
+    fn visit_heading_mut(&mut self, heading: &mut HeadingLine<'_>) {
+        self.visit_terms_mut(&mut heading.content);
+    }
+
+    fn visit_quote_mut(&mut self, quote: &mut QuoteLine<'_>) {
+        self.visit_terms_mut(&mut quote.content);
+    }
+
+    fn visit_table_mut(&mut self, table: &mut TableLine<'_>) {
+        walk_table_mut(self, table);
+    }
+
+    fn visit_image_mut(&mut self, _image: &mut ImageLine) {}
+    fn visit_dot_graph_mut(&mut self, _graph: &mut DotGraphLine<'_>) {}
+    fn visit_code_block_mut(&mut self, _code: &mut CodeBlockLine) {}
+    fn visit_display_math_mut(&mut self, _math: &mut Cow<'_, str>) {}
+    fn visit_rule_mut(&mut self) {}
+    fn visit_error_mut(&mut self, _error: &mut ParseError) {}
+
+    fn visit_terms_mut(&mut self, terms: &mut Vec<Spanned<Term<'_>>>) {
+        for t in terms {
+            self.visit_term_mut(t);
+        }
+    }
+
+    fn visit_term_mut(&mut self, term: &mut Spanned<Term<'_>>) {
+        walk_term_mut(self, term);
+    }
+
+    fn visit_space_mut(&mut self) {}
+    fn visit_word_mut(&mut self, _word: &mut Cow<'_, str>) {}
+    fn visit_tag_mut(&mut self, _tag: &mut Cow<'_, str>) {}
+    fn visit_url_mut(&mut self, _url: &mut Cow<'_, str>) {}
+    fn visit_math_mut(&mut self, _math: &mut Cow<'_, str>) {}
+    fn visit_code_mut(&mut self, _code: &mut Cow<'_, str>) {}
+    fn visit_kbd_mut(&mut self, _keys: &mut Cow<'_, str>) {}
+
+    fn visit_anchor_mut(&mut self, _id: &mut Cow<'_, str>) {}
+
+    /// Called for the marker of a footnote (its content is visited after the nodes).
+    fn visit_footnote_ref_mut(&mut self, _number: &mut usize) {}
+
+    fn visit_footnote_mut(&mut self, _number: usize, content: &mut Vec<Spanned<Term<'_>>>) {
+        self.visit_terms_mut(content);
+    }
+
+    fn visit_ref_mut(&mut self, content: &mut Vec<Spanned<Term<'_>>>, _target: &mut String) {
+        self.visit_terms_mut(content);
+    }
+
+    fn visit_link_mut(&mut self, content: &mut Vec<Spanned<Term<'_>>>, _target: &mut String) {
+        self.visit_terms_mut(content);
+    }
+
+    fn visit_bold_mut(&mut self, terms: &mut Vec<Spanned<Term<'_>>>) {
+        self.visit_terms_mut(terms);
+    }
+
+    fn visit_italics_mut(&mut self, terms: &mut Vec<Spanned<Term<'_>>>) {
+        self.visit_terms_mut(terms);
+    }
+
+    fn visit_strikethrough_mut(&mut self, terms: &mut Vec<Spanned<Term<'_>>>) {
+        self.visit_terms_mut(terms);
+    }
+
+    fn visit_underline_mut(&mut self, terms: &mut Vec<Spanned<Term<'_>>>) {
+        self.visit_terms_mut(terms);
+    }
+
+    fn visit_highlight_mut(&mut self, terms: &mut Vec<Spanned<Term<'_>>>) {
+        self.visit_terms_mut(terms);
+    }
+
+    fn visit_superscript_mut(&mut self, terms: &mut Vec<Spanned<Term<'_>>>) {
+        self.visit_terms_mut(terms);
+    }
+
+    fn visit_subscript_mut(&mut self, terms: &mut Vec<Spanned<Term<'_>>>) {
+        self.visit_terms_mut(terms);
+    }
+}
+
+/// Visit the nodes, and then the content of each footnote.
+pub fn walk_document_mut<V: VisitMut + ?Sized>(v: &mut V, doc: &mut Document<'_>) {
+    for node in &mut doc.nodes {
+        v.visit_node_mut(node, 0);
+    }
+    for (i, content) in doc.footnotes.iter_mut().enumerate() {
+        v.visit_footnote_mut(i + 1, content);
+    }
+}
+
+pub fn walk_node_mut<V: VisitMut + ?Sized>(v: &mut V, node: &mut Node<'_>, depth: usize) {
+    v.enter_node_mut(node, depth);
+    v.visit_line_mut(&mut node.line);
+    for child in &mut node.children {
+        v.visit_node_mut(child, depth + 1);
+    }
+    v.exit_node_mut(node, depth);
+}
+
+pub fn walk_line_mut<V: VisitMut + ?Sized>(v: &mut V, line: &mut Line<'_>) {
+    match line {
+        Line::Text(l) => v.visit_text_line_mut(l),
+        Line::Heading(l) => v.visit_heading_mut(l),
+        Line::Quote(l) => v.visit_quote_mut(l),
+        Line::Table(l) => v.visit_table_mut(l),
+        Line::Image(l) => v.visit_image_mut(l),
+        Line::DotGraph(l) => v.visit_dot_graph_mut(l),
+        Line::CodeBlock(l) => v.visit_code_block_mut(l),
+        Line::DisplayMath(x) => v.visit_display_math_mut(x),
+        Line::Rule => v.visit_rule_mut(),
+        Line::Error(e) => v.visit_error_mut(e),
+    }
+}
+
+pub fn walk_table_mut<V: VisitMut + ?Sized>(v: &mut V, table: &mut TableLine<'_>) {
+    for item in &mut table.items {
+        if let TableItem::Row(cells) = item {
+            for cell in cells {
+                v.visit_terms_mut(cell);
+            }
+        }
+    }
+}
+
+pub fn walk_term_mut<V: VisitMut + ?Sized>(v: &mut V, term: &mut Spanned<Term<'_>>) {
+    match &mut term.value {
+        Term::Space => v.visit_space_mut(),
+        Term::Word(x) => v.visit_word_mut(x),
+        Term::Tag(x) => v.visit_tag_mut(x),
+        Term::Url(x) => v.visit_url_mut(x),
+        Term::Math(x) => v.visit_math_mut(x),
+        Term::Code(x) => v.visit_code_mut(x),
+        Term::Kbd(x) => v.visit_kbd_mut(x),
+        Term::Anchor(x) => v.visit_anchor_mut(x),
+        Term::Footnote(n) => v.visit_footnote_ref_mut(n),
+        Term::Ref { content, target } => v.visit_ref_mut(content, target),
+        Term::Link { content, target } => v.visit_link_mut(content, target),
+        Term::Bold(x) => v.visit_bold_mut(x),
+        Term::Italics(x) => v.visit_italics_mut(x),
+        Term::Strikethrough(x) => v.visit_strikethrough_mut(x),
+        Term::Underline(x) => v.visit_underline_mut(x),
+        Term::Highlight(x) => v.visit_highlight_mut(x),
+        Term::Superscript(x) => v.visit_superscript_mut(x),
+        Term::Subscript(x) => v.visit_subscript_mut(x),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::parser::stage3::{TaskPrefix, TaskState};
+
+    #[test]
+    fn tasks_with_depth() {
+        #[derive(Default)]
+        struct Tasks(Vec<(usize, TaskState, String)>);
+
+        impl<'a> Visit<'a> for Tasks {
+            fn enter_node(&mut self, node: &'a Node<'_>, depth: usize) {
+                if let Line::Text(TextLine {
+                    task: Some(TaskPrefix { state, .. }),
+                    content,
+                    ..
+                }) = &node.line
+                {
+                    let text = crate::parser::stage3::plain_text(content);
+                    self.0.push((depth, *state, text.trim().to_owned()));
+                }
+            }
+        }
+
+        let doc = parse("(x) a\n  ( ) b\n  not a task\n    (-) c\nd\n").unwrap();
+        let mut tasks = Tasks::default();
+        tasks.visit_document(&doc);
+        assert_eq!(
+            tasks.0,
+            vec![
+                (0, TaskState::Done, "a".to_owned()),
+                (1, TaskState::Todo, "b".to_owned()),
+                (2, TaskState::Cancelled, "c".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn terms_in_tables_and_emphasis() {
+        #[derive(Default)]
+        struct Words<'a>(Vec<&'a str>);
+
+        impl<'a> Visit<'a> for Words<'a> {
+            fn visit_word(&mut self, word: &'a str) {
+                self.0.push(word);
+            }
+        }
+
+        let doc = parse("*a _b_* @s{c}\n@table{\n  @{d}{@sup{e}}\n}\n").unwrap();
+        let mut words = Words::default();
+        words.visit_document(&doc);
+        assert_eq!(words.0, ["a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn rewrite_urls() {
+        struct Https;
+
+        impl VisitMut for Https {
+            fn visit_url_mut(&mut self, url: &mut Cow<'_, str>) {
+                if let Some(rest) = url.strip_prefix("http://") {
+                    *url = Cow::Owned(format!("https://{rest}"));
+                }
+            }
+        }
+
+        let mut doc = parse("see http://a.org\n  *and http://b.org*\n").unwrap();
+        Https.visit_document_mut(&mut doc);
+        assert_eq!(
+            doc,
+            parse("see https://a.org\n  *and https://b.org*\n").unwrap()
+        );
+    }
+}